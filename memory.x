@@ -1,6 +1,7 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* the last sector (128K at 0x08060000) is reserved for the persistent config, see `src/config.rs` */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
   RAM : ORIGIN = 0x20000000, LENGTH = 96K
 }
@@ -0,0 +1,207 @@
+//! Monitors the battery voltage to detect when the batteries are running empty.
+//!
+//! Running the car on nearly empty batteries leads to brown-outs which look like random hangs.
+//! This module is hardware-agnostic: it gets the measured voltage and decides on the state of the
+//! battery, the actual measurement (ADC) is done by the consumer.
+
+/// Below this voltage (ca. 1.1V per cell for 8x AA) the battery is considered to be low.
+pub const LOW_BATTERY_VOLTAGE_IN_MV: u16 = 8_800;
+/// Below this voltage (ca. 1.0V per cell for 8x AA) the battery is considered to be critical.
+pub const CRITICAL_BATTERY_VOLTAGE_IN_MV: u16 = 8_000;
+/// The voltage needs to rise by this much above a threshold before the state improves again.
+/// This avoids flickering between two states when the voltage is close to a threshold.
+pub const BATTERY_VOLTAGE_HYSTERESIS_IN_MV: u16 = 200;
+/// Smoothing factor of the exponential moving average, expressed as a power of two:
+/// each new measurement is weighted with `1 / 2^FILTER_SHIFT`.
+const FILTER_SHIFT: u32 = 3;
+
+const _: () = assert!(CRITICAL_BATTERY_VOLTAGE_IN_MV < LOW_BATTERY_VOLTAGE_IN_MV);
+
+/// The state of the battery, based on its (filtered) voltage.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BatteryState {
+    /// The battery is sufficiently charged.
+    Ok,
+    /// The battery is running low, the speed of the car should be limited.
+    Low,
+    /// The battery is (nearly) empty, the car should not drive anymore.
+    Critical,
+}
+
+/// The latest known status of the battery.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatteryStatus {
+    /// The filtered battery voltage.
+    pub voltage_in_mv: u16,
+    pub state: BatteryState,
+}
+
+/// Filters the measured battery voltage and decides on the [`BatteryState`].
+pub struct BatteryMonitor {
+    /// The filtered voltage, scaled by `2^FILTER_SHIFT` to avoid losing precision. `None` until the first measurement.
+    filtered_voltage: Option<u32>,
+    state: BatteryState,
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        BatteryMonitor {
+            filtered_voltage: None,
+            state: BatteryState::Ok,
+        }
+    }
+}
+
+impl BatteryMonitor {
+    /// Process a new voltage measurement and return the resulting status of the battery.
+    pub fn update(&mut self, voltage_in_mv: u16) -> BatteryStatus {
+        let scaled_voltage = (voltage_in_mv as u32) << FILTER_SHIFT;
+        let filtered_voltage = match self.filtered_voltage {
+            // the first measurement is taken as-is, otherwise it'd take a while to reach the actual voltage.
+            None => scaled_voltage,
+            Some(filtered_voltage) => {
+                filtered_voltage - (filtered_voltage >> FILTER_SHIFT) + voltage_in_mv as u32
+            }
+        };
+        self.filtered_voltage = Some(filtered_voltage);
+        let voltage_in_mv = (filtered_voltage >> FILTER_SHIFT) as u16;
+
+        let new_state = match self.state {
+            BatteryState::Ok if voltage_in_mv < CRITICAL_BATTERY_VOLTAGE_IN_MV => {
+                BatteryState::Critical
+            }
+            BatteryState::Ok if voltage_in_mv < LOW_BATTERY_VOLTAGE_IN_MV => BatteryState::Low,
+            BatteryState::Low if voltage_in_mv < CRITICAL_BATTERY_VOLTAGE_IN_MV => {
+                BatteryState::Critical
+            }
+            BatteryState::Low
+                if voltage_in_mv
+                    >= LOW_BATTERY_VOLTAGE_IN_MV + BATTERY_VOLTAGE_HYSTERESIS_IN_MV =>
+            {
+                BatteryState::Ok
+            }
+            BatteryState::Critical
+                if voltage_in_mv
+                    >= CRITICAL_BATTERY_VOLTAGE_IN_MV + BATTERY_VOLTAGE_HYSTERESIS_IN_MV =>
+            {
+                BatteryState::Low
+            }
+            state => state,
+        };
+        self.state = new_state;
+
+        BatteryStatus {
+            voltage_in_mv,
+            state: self.state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the voltages (in mV) to a new monitor and return the resulting statuses.
+    fn monitor(voltages_in_mv: impl IntoIterator<Item = u16>) -> Vec<BatteryStatus> {
+        let mut monitor = BatteryMonitor::default();
+        voltages_in_mv
+            .into_iter()
+            .map(|voltage_in_mv| monitor.update(voltage_in_mv))
+            .collect()
+    }
+
+    fn states(statuses: &[BatteryStatus]) -> Vec<BatteryState> {
+        let mut states: Vec<BatteryState> = statuses.iter().map(|status| status.state).collect();
+        states.dedup();
+        states
+    }
+
+    #[test]
+    fn first_measurement_is_taken_as_is() {
+        assert_eq!(
+            monitor([7_500]),
+            [BatteryStatus {
+                voltage_in_mv: 7_500,
+                state: BatteryState::Critical
+            }]
+        );
+        assert_eq!(
+            monitor([9_600]),
+            [BatteryStatus {
+                voltage_in_mv: 9_600,
+                state: BatteryState::Ok
+            }]
+        );
+    }
+
+    #[test]
+    fn filtered_voltage_converges_to_the_measured_voltage() {
+        let statuses = monitor([9_600].into_iter().chain([9_000; 60]));
+        let voltages: Vec<u16> = statuses.iter().map(|status| status.voltage_in_mv).collect();
+        // each measurement is weighted with 1/8
+        assert_eq!(voltages[1], 9_525);
+        assert!(voltages.windows(2).all(|v| v[1] <= v[0]));
+        // 63% of the step after ca. 8 measurements
+        assert!(voltages[8] < 9_600 - 600 * 63 / 100 + 30);
+        assert_eq!(*voltages.last().unwrap(), 9_000);
+    }
+
+    #[test]
+    fn single_dip_is_filtered() {
+        let statuses = monitor([9_200, 9_200, 7_000, 9_200, 9_200]);
+        assert_eq!(states(&statuses), [BatteryState::Ok]);
+    }
+
+    #[test]
+    fn discharging_battery_gets_low_and_then_critical() {
+        // the voltage drops by 10mV per measurement from 9.4V to 7.6V
+        let statuses = monitor((0..=180).map(|i| 9_400 - i * 10));
+        assert_eq!(
+            states(&statuses),
+            [BatteryState::Ok, BatteryState::Low, BatteryState::Critical]
+        );
+        let first_low = statuses
+            .iter()
+            .find(|status| status.state == BatteryState::Low)
+            .unwrap();
+        assert!(first_low.voltage_in_mv < LOW_BATTERY_VOLTAGE_IN_MV);
+        let first_critical = statuses
+            .iter()
+            .find(|status| status.state == BatteryState::Critical)
+            .unwrap();
+        assert!(first_critical.voltage_in_mv < CRITICAL_BATTERY_VOLTAGE_IN_MV);
+    }
+
+    #[test]
+    fn recovery_requires_the_hysteresis() {
+        let mut monitor = BatteryMonitor::default();
+        assert_eq!(monitor.update(7_900).state, BatteryState::Critical);
+
+        // above the critical threshold, but not by the hysteresis
+        for _ in 0..50 {
+            assert_eq!(monitor.update(8_150).state, BatteryState::Critical);
+        }
+        let status = (0..50).map(|_| monitor.update(8_200)).last().unwrap();
+        assert_eq!(status.voltage_in_mv, 8_200);
+        assert_eq!(status.state, BatteryState::Low);
+
+        // above the low threshold, but not by the hysteresis
+        for _ in 0..50 {
+            assert_eq!(monitor.update(8_950).state, BatteryState::Low);
+        }
+        let status = (0..50).map(|_| monitor.update(9_000)).last().unwrap();
+        assert_eq!(status.voltage_in_mv, 9_000);
+        assert_eq!(status.state, BatteryState::Ok);
+    }
+
+    #[test]
+    fn no_flickering_around_the_low_threshold() {
+        // noisy measurements (+/- 150mV) around 8.8V, e.g. due to the load of the motor
+        let noise = [0, 150, -100, 50, -150, 100, -50, 150, 0, -150];
+        let trace = (0..200).map(|i| (8_800 + noise[i % noise.len()]) as u16);
+        let statuses = monitor([9_200].into_iter().chain(trace));
+        assert_eq!(states(&statuses), [BatteryState::Ok, BatteryState::Low]);
+    }
+}
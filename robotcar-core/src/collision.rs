@@ -0,0 +1,158 @@
+//! Decides whether the obstacle in front is too close, based on the (filtered) front distance and the speed at
+//! which the obstacle is getting closer (see [`crate::distance_filter::ClosingSpeedEstimator`]).
+//! The actual measurement and the reaction (emergency brake) are done by the consumer.
+
+/// The minimum time to collision with the obstacle in front. If the obstacle is approaching so fast that it
+/// would be hit earlier than this the car has to brake, even if the distance is still larger than the minimum
+/// front distance.
+pub const MIN_TIME_TO_COLLISION_IN_MS: u32 = 1_000;
+
+/// Below this closing speed the time to collision isn't considered (the estimate of the closing speed is noisy).
+pub const MIN_CLOSING_SPEED_IN_MM_PER_S: i32 = 200;
+
+/// The assessment of the obstacle in front, see [`assess_collision_risk`].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CollisionRisk {
+    /// The obstacle is far enough away (or not approaching fast enough), the car may drive forward.
+    Clear,
+    /// The obstacle is closer than the minimum front distance.
+    TooClose,
+    /// The obstacle is approaching so fast that it would be hit in less than [`MIN_TIME_TO_COLLISION_IN_MS`].
+    TimeToCollision { time_to_collision_in_ms: u32 },
+}
+
+/// The estimated time (in milliseconds) until the obstacle in front is hit. `None` if it isn't approaching
+/// (at least with [`MIN_CLOSING_SPEED_IN_MM_PER_S`]).
+pub fn time_to_collision_in_ms(distance_in_mm: u16, closing_speed_in_mm_per_s: i32) -> Option<u32> {
+    if closing_speed_in_mm_per_s < MIN_CLOSING_SPEED_IN_MM_PER_S {
+        return None;
+    }
+    Some(distance_in_mm as u32 * 1_000 / closing_speed_in_mm_per_s as u32)
+}
+
+/// Assess the obstacle in front. The closing speed is optional as it is only known after a few measurements.
+pub fn assess_collision_risk(
+    distance_in_mm: u16,
+    closing_speed_in_mm_per_s: Option<i32>,
+    min_front_distance_in_mm: u16,
+) -> CollisionRisk {
+    if distance_in_mm < min_front_distance_in_mm {
+        return CollisionRisk::TooClose;
+    }
+    match closing_speed_in_mm_per_s
+        .and_then(|closing_speed| time_to_collision_in_ms(distance_in_mm, closing_speed))
+    {
+        Some(time_to_collision_in_ms) if time_to_collision_in_ms < MIN_TIME_TO_COLLISION_IN_MS => {
+            CollisionRisk::TimeToCollision {
+                time_to_collision_in_ms,
+            }
+        }
+        _ => CollisionRisk::Clear,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN_FRONT_DISTANCE_IN_MM: u16 = 300;
+
+    #[test]
+    fn approaching_obstacle_triggers_the_brake_before_the_minimum_distance() {
+        // 1.5m/s towards an obstacle 1m away => hit in 667ms
+        assert_eq!(time_to_collision_in_ms(1_000, 1_500), Some(666));
+        assert_eq!(
+            assess_collision_risk(1_000, Some(1_500), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::TimeToCollision {
+                time_to_collision_in_ms: 666
+            }
+        );
+    }
+
+    #[test]
+    fn slowly_approaching_obstacle_is_clear() {
+        // 0.5m/s towards an obstacle 1m away => hit in 2s
+        assert_eq!(time_to_collision_in_ms(1_000, 500), Some(2_000));
+        assert_eq!(
+            assess_collision_risk(1_000, Some(500), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::Clear
+        );
+    }
+
+    #[test]
+    fn time_to_collision_exactly_at_the_minimum_is_clear() {
+        assert_eq!(
+            assess_collision_risk(1_000, Some(1_000), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::Clear
+        );
+        assert_eq!(
+            assess_collision_risk(999, Some(1_000), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::TimeToCollision {
+                time_to_collision_in_ms: 999
+            }
+        );
+    }
+
+    #[test]
+    fn static_obstacle_has_no_time_to_collision() {
+        assert_eq!(time_to_collision_in_ms(1_000, 0), None);
+        assert_eq!(
+            assess_collision_risk(1_000, Some(0), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::Clear
+        );
+    }
+
+    #[test]
+    fn receding_obstacle_has_no_time_to_collision() {
+        assert_eq!(time_to_collision_in_ms(400, -1_000), None);
+        assert_eq!(
+            assess_collision_risk(400, Some(-1_000), MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::Clear
+        );
+    }
+
+    #[test]
+    fn closing_speed_below_the_cutoff_is_ignored() {
+        // would be hit in 500ms, but the closing speed is too small to be trusted
+        assert_eq!(
+            time_to_collision_in_ms(99, MIN_CLOSING_SPEED_IN_MM_PER_S - 1),
+            None
+        );
+        assert_eq!(
+            assess_collision_risk(
+                MIN_FRONT_DISTANCE_IN_MM,
+                Some(MIN_CLOSING_SPEED_IN_MM_PER_S - 1),
+                MIN_FRONT_DISTANCE_IN_MM
+            ),
+            CollisionRisk::Clear
+        );
+        // 300mm at 200mm/s => hit in 1.5s
+        assert_eq!(
+            time_to_collision_in_ms(MIN_FRONT_DISTANCE_IN_MM, MIN_CLOSING_SPEED_IN_MM_PER_S),
+            Some(1_500)
+        );
+        assert_eq!(
+            time_to_collision_in_ms(100, MIN_CLOSING_SPEED_IN_MM_PER_S),
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn obstacle_closer_than_the_minimum_is_too_close_regardless_of_its_speed() {
+        for closing_speed in [None, Some(-1_000), Some(0), Some(5_000)] {
+            assert_eq!(
+                assess_collision_risk(299, closing_speed, MIN_FRONT_DISTANCE_IN_MM),
+                CollisionRisk::TooClose
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_closing_speed_is_clear() {
+        assert_eq!(
+            assess_collision_risk(MIN_FRONT_DISTANCE_IN_MM, None, MIN_FRONT_DISTANCE_IN_MM),
+            CollisionRisk::Clear
+        );
+    }
+}
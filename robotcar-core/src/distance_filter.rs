@@ -0,0 +1,504 @@
+//! Filters for the measurements of a distance sensor.
+//!
+//! The raw measurements of the TOF sensor occasionally contain outliers, which would otherwise directly trigger
+//! an emergency brake. The filters can be chained to a pipeline (a tuple of two filters is again a filter),
+//! see [`FrontDistanceFilter`] for the one used for the front distance sensor.
+//!
+//! Additionally, the [`ClosingSpeedEstimator`] estimates how fast an obstacle is approaching based on the
+//! filtered distances.
+
+/// The timestamps of the measurements (from the monotonic timer).
+pub type Instant = fugit::TimerInstantU32<1_000_000>;
+
+/// A single stage in the filter pipeline of a distance sensor.
+pub trait DistanceFilter {
+    /// Process a new measurement taken at `now`. Returns the filtered distance or `None` if the measurement
+    /// has been rejected.
+    fn filter(&mut self, distance_in_mm: u16, now: Instant) -> Option<u16>;
+
+    /// Forget all previous measurements, e.g. because the sensor has been reset.
+    fn reset(&mut self);
+}
+
+/// Chains two filters: the output of the first filter is fed into the second one.
+impl<A: DistanceFilter, B: DistanceFilter> DistanceFilter for (A, B) {
+    fn filter(&mut self, distance_in_mm: u16, now: Instant) -> Option<u16> {
+        let distance_in_mm = self.0.filter(distance_in_mm, now)?;
+        self.1.filter(distance_in_mm, now)
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+        self.1.reset();
+    }
+}
+
+/// Returns the median of the last `N` measurements (or of all measurements as long as there are fewer than `N`).
+/// This removes single outliers without adding much lag.
+pub struct MedianFilter<const N: usize> {
+    values: [u16; N],
+    len: usize,
+    next: usize,
+}
+
+impl<const N: usize> Default for MedianFilter<N> {
+    fn default() -> Self {
+        MedianFilter {
+            values: [0; N],
+            len: 0,
+            next: 0,
+        }
+    }
+}
+
+impl<const N: usize> DistanceFilter for MedianFilter<N> {
+    fn filter(&mut self, distance_in_mm: u16, _now: Instant) -> Option<u16> {
+        self.values[self.next] = distance_in_mm;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        let mut sorted = self.values;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        Some(sorted[self.len / 2])
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+}
+
+/// Exponential moving average: each new measurement is weighted with `1 / 2^shift`.
+pub struct ExponentialMovingAverage {
+    shift: u32,
+    /// The filtered distance, scaled by `2^shift` to avoid losing precision. `None` until the first measurement.
+    filtered_distance: Option<u32>,
+}
+
+impl ExponentialMovingAverage {
+    pub const fn new(shift: u32) -> ExponentialMovingAverage {
+        ExponentialMovingAverage {
+            shift,
+            filtered_distance: None,
+        }
+    }
+}
+
+impl DistanceFilter for ExponentialMovingAverage {
+    fn filter(&mut self, distance_in_mm: u16, _now: Instant) -> Option<u16> {
+        let filtered_distance = match self.filtered_distance {
+            None => (distance_in_mm as u32) << self.shift,
+            Some(filtered_distance) => {
+                filtered_distance - (filtered_distance >> self.shift) + distance_in_mm as u32
+            }
+        };
+        self.filtered_distance = Some(filtered_distance);
+        Some((filtered_distance >> self.shift) as u16)
+    }
+
+    fn reset(&mut self) {
+        self.filtered_distance = None;
+    }
+}
+
+/// Rejects measurements which differ more from the previous measurement than physically plausible.
+///
+/// A real change (e.g. an obstacle suddenly appearing in front of the car) persists, thus the measurement is
+/// accepted after `max_consecutive_rejections` rejections in a row. This limits the added lag.
+pub struct RateOfChangeFilter {
+    max_rate_in_mm_per_s: u32,
+    max_consecutive_rejections: u8,
+    consecutive_rejections: u8,
+    /// The last accepted measurement.
+    last_measurement: Option<(u16, Instant)>,
+}
+
+impl RateOfChangeFilter {
+    pub const fn new(
+        max_rate_in_mm_per_s: u32,
+        max_consecutive_rejections: u8,
+    ) -> RateOfChangeFilter {
+        RateOfChangeFilter {
+            max_rate_in_mm_per_s,
+            max_consecutive_rejections,
+            consecutive_rejections: 0,
+            last_measurement: None,
+        }
+    }
+}
+
+impl DistanceFilter for RateOfChangeFilter {
+    fn filter(&mut self, distance_in_mm: u16, now: Instant) -> Option<u16> {
+        if let Some((last_distance_in_mm, last_update)) = self.last_measurement {
+            let elapsed_in_us = now
+                .checked_duration_since(last_update)
+                .map_or(0, |elapsed| elapsed.to_micros());
+            let max_change_in_mm =
+                self.max_rate_in_mm_per_s as u64 * elapsed_in_us as u64 / 1_000_000;
+            let change_in_mm = distance_in_mm.abs_diff(last_distance_in_mm) as u64;
+            if change_in_mm > max_change_in_mm
+                && self.consecutive_rejections < self.max_consecutive_rejections
+            {
+                self.consecutive_rejections += 1;
+                #[cfg(feature = "defmt")]
+                defmt::debug!(
+                    "rejecting distance of {}mm, it changed by {}mm since the last one",
+                    distance_in_mm,
+                    change_in_mm
+                );
+                return None;
+            }
+        }
+
+        self.consecutive_rejections = 0;
+        self.last_measurement = Some((distance_in_mm, now));
+        Some(distance_in_mm)
+    }
+
+    fn reset(&mut self) {
+        self.consecutive_rejections = 0;
+        self.last_measurement = None;
+    }
+}
+
+/// Max. plausible change of the front distance: the car driving at full speed towards someone walking towards it.
+const FRONT_DISTANCE_MAX_RATE_IN_MM_PER_S: u32 = 5_000;
+
+/// The filter pipeline used for the front distance sensor: outliers are rejected and the remaining
+/// measurements are smoothed (median of 3 followed by a light moving average).
+///
+/// The smoothing delays a sudden drop of the distance considerably (someone stepping in front of the car at 30cm
+/// would only be below 50cm after 600ms). Thus, measurements closer than the minimum front distance bypass the
+/// smoothing once they passed the outlier rejection, so that the car brakes after the 2nd measurement.
+pub struct FrontDistanceFilter {
+    outlier_rejection: RateOfChangeFilter,
+    smoothing: (MedianFilter<3>, ExponentialMovingAverage),
+}
+
+impl Default for FrontDistanceFilter {
+    fn default() -> Self {
+        FrontDistanceFilter {
+            outlier_rejection: RateOfChangeFilter::new(FRONT_DISTANCE_MAX_RATE_IN_MM_PER_S, 1),
+            smoothing: (MedianFilter::default(), ExponentialMovingAverage::new(1)),
+        }
+    }
+}
+
+impl FrontDistanceFilter {
+    /// Process a new measurement taken at `now`. Returns the filtered distance or `None` if the measurement
+    /// has been rejected. Measurements closer than `min_front_distance_in_mm` aren't delayed by the smoothing.
+    pub fn filter(
+        &mut self,
+        distance_in_mm: u16,
+        now: Instant,
+        min_front_distance_in_mm: u16,
+    ) -> Option<u16> {
+        let distance_in_mm = self.outlier_rejection.filter(distance_in_mm, now)?;
+        if distance_in_mm < min_front_distance_in_mm {
+            // restart the smoothing from here, it must not report the old (larger) distance once the
+            // measurements are above the minimum again
+            self.smoothing.reset();
+            self.smoothing.filter(distance_in_mm, now);
+            Some(distance_in_mm)
+        } else {
+            self.smoothing.filter(distance_in_mm, now)
+        }
+    }
+
+    /// Forget all previous measurements, e.g. because the sensor has been reset.
+    pub fn reset(&mut self) {
+        self.outlier_rejection.reset();
+        self.smoothing.reset();
+    }
+}
+
+/// Smoothing factor of the closing speed, expressed as a power of two:
+/// each new estimate is weighted with `1 / 2^CLOSING_SPEED_FILTER_SHIFT`.
+const CLOSING_SPEED_FILTER_SHIFT: u32 = 2;
+
+/// Estimates how fast the distance to an obstacle decreases, based on consecutive (filtered) measurements.
+#[derive(Default)]
+pub struct ClosingSpeedEstimator {
+    last_measurement: Option<(u16, Instant)>,
+    closing_speed_in_mm_per_s: Option<i32>,
+}
+
+impl ClosingSpeedEstimator {
+    /// Process a new distance measured at `now` and return the current estimate of the closing speed
+    /// (positive if the obstacle is getting closer). `None` until at least two measurements are available.
+    pub fn update(&mut self, distance_in_mm: u16, now: Instant) -> Option<i32> {
+        if let Some((last_distance_in_mm, last_update)) = self.last_measurement {
+            let elapsed_in_us = now
+                .checked_duration_since(last_update)
+                .map_or(0, |elapsed| elapsed.to_micros());
+            // two measurements at the same time don't tell anything about the speed
+            if elapsed_in_us == 0 {
+                return self.closing_speed_in_mm_per_s;
+            }
+            let speed = ((last_distance_in_mm as i64 - distance_in_mm as i64) * 1_000_000
+                / elapsed_in_us as i64) as i32;
+            self.closing_speed_in_mm_per_s = Some(match self.closing_speed_in_mm_per_s {
+                None => speed,
+                Some(closing_speed) => {
+                    closing_speed + ((speed - closing_speed) >> CLOSING_SPEED_FILTER_SHIFT)
+                }
+            });
+        }
+        self.last_measurement = Some((distance_in_mm, now));
+        self.closing_speed_in_mm_per_s
+    }
+
+    /// The latest estimate of the closing speed, see [`ClosingSpeedEstimator::update`].
+    pub fn closing_speed_in_mm_per_s(&self) -> Option<i32> {
+        self.closing_speed_in_mm_per_s
+    }
+
+    /// Forget all previous measurements, e.g. because the sensor has been reset.
+    pub fn reset(&mut self) {
+        self.last_measurement = None;
+        self.closing_speed_in_mm_per_s = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The TOF delivers a measurement every 100ms (`LongPrecise`).
+    const MEASUREMENT_INTERVAL_IN_MS: u32 = 100;
+
+    fn at(measurement: usize) -> Instant {
+        Instant::from_ticks(measurement as u32 * MEASUREMENT_INTERVAL_IN_MS * 1_000)
+    }
+
+    /// Feed the trace (one measurement every 100ms) to the filter and return its outputs.
+    fn run(filter: &mut impl DistanceFilter, trace: &[u16]) -> Vec<Option<u16>> {
+        trace
+            .iter()
+            .enumerate()
+            .map(|(i, &distance_in_mm)| filter.filter(distance_in_mm, at(i)))
+            .collect()
+    }
+
+    fn run_front_distance_filter(trace: &[u16], min_front_distance_in_mm: u16) -> Vec<Option<u16>> {
+        let mut filter = FrontDistanceFilter::default();
+        trace
+            .iter()
+            .enumerate()
+            .map(|(i, &distance_in_mm)| {
+                filter.filter(distance_in_mm, at(i), min_front_distance_in_mm)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn median_filter_removes_single_outliers() {
+        let mut filter = MedianFilter::<3>::default();
+        assert_eq!(
+            run(&mut filter, &[1_000, 1_010, 3_000, 1_020, 30, 1_000]),
+            [1_000, 1_010, 1_010, 1_020, 1_020, 1_000].map(Some)
+        );
+    }
+
+    #[test]
+    fn median_filter_follows_a_step_after_half_of_its_window() {
+        let mut filter = MedianFilter::<3>::default();
+        assert_eq!(
+            run(&mut filter, &[1_000, 1_000, 1_000, 500, 500, 500]),
+            [1_000, 1_000, 1_000, 1_000, 500, 500].map(Some)
+        );
+    }
+
+    #[test]
+    fn median_filter_uses_all_measurements_until_its_window_is_full() {
+        let mut filter = MedianFilter::<5>::default();
+        assert_eq!(run(&mut filter, &[100, 300]), [100, 300].map(Some));
+        filter.reset();
+        assert_eq!(run(&mut filter, &[700]), [Some(700)]);
+    }
+
+    #[test]
+    fn moving_average_starts_with_the_first_measurement_and_converges() {
+        let mut filter = ExponentialMovingAverage::new(1);
+        assert_eq!(
+            run(&mut filter, &[1_000, 2_000, 2_000, 2_000, 2_000]),
+            [1_000, 1_500, 1_750, 1_875, 1_937].map(Some)
+        );
+        let converged = run(&mut filter, &[2_000; 20]);
+        assert_eq!(converged.last(), Some(&Some(2_000)));
+
+        filter.reset();
+        assert_eq!(run(&mut filter, &[300]), [Some(300)]);
+    }
+
+    #[test]
+    fn rate_of_change_filter_rejects_implausible_jumps_once() {
+        // 5m/s => max. 500mm between two measurements
+        let mut filter = RateOfChangeFilter::new(5_000, 1);
+        assert_eq!(
+            run(&mut filter, &[2_000, 1_600, 300, 1_500, 300, 300, 310]),
+            [
+                Some(2_000),
+                Some(1_600),
+                None, // outlier
+                Some(1_500),
+                None, // real change, but implausible at first
+                Some(300),
+                Some(310),
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_of_change_filter_allows_larger_changes_after_a_longer_time() {
+        let mut filter = RateOfChangeFilter::new(5_000, 1);
+        assert_eq!(filter.filter(2_000, at(0)), Some(2_000));
+        assert_eq!(filter.filter(1_000, at(2)), Some(1_000));
+
+        filter.reset();
+        assert_eq!(filter.filter(100, at(3)), Some(100));
+    }
+
+    #[test]
+    fn chained_filters_feed_the_output_of_the_first_into_the_second() {
+        let mut filter = (
+            RateOfChangeFilter::new(5_000, 1),
+            MedianFilter::<3>::default(),
+        );
+        assert_eq!(
+            run(&mut filter, &[1_000, 1_000, 3_000, 1_000]),
+            // the outlier doesn't even reach the median filter
+            [Some(1_000), Some(1_000), None, Some(1_000)]
+        );
+
+        filter.reset();
+        assert_eq!(run(&mut filter, &[3_000]), [Some(3_000)]);
+    }
+
+    #[test]
+    fn front_distance_filter_smooths_noise() {
+        let trace = [1_500, 1_520, 1_480, 1_900, 1_510, 1_490, 1_500];
+        let filtered = run_front_distance_filter(&trace, 500);
+        assert!(filtered
+            .iter()
+            .all(|distance| matches!(distance, Some(1_480..=1_520))));
+    }
+
+    #[test]
+    fn smoothing_alone_delays_a_sudden_obstacle() {
+        // someone steps in front of the car: 2m => 30cm
+        let trace = [2_000, 2_000, 2_000, 300, 300, 300, 300, 300, 300];
+        // without the bypass
+        let filtered = run_front_distance_filter(&trace, 0);
+        assert_eq!(
+            filtered,
+            [
+                Some(2_000),
+                Some(2_000),
+                Some(2_000),
+                None,
+                Some(2_000),
+                Some(1_150),
+                Some(725),
+                Some(512),
+                Some(406),
+            ]
+        );
+        // the 6th measurement of the obstacle (ca. 600ms) is the first one below 50cm
+        let first_below_minimum = filtered
+            .iter()
+            .position(|distance| matches!(distance, Some(distance) if *distance < 500));
+        assert_eq!(first_below_minimum, Some(8));
+    }
+
+    #[test]
+    fn close_measurements_bypass_the_smoothing_once_confirmed() {
+        let trace = [2_000, 2_000, 2_000, 300, 300, 300];
+        assert_eq!(
+            run_front_distance_filter(&trace, 500),
+            [
+                Some(2_000),
+                Some(2_000),
+                Some(2_000),
+                // a single measurement might still be an outlier
+                None,
+                Some(300),
+                Some(300),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_close_outlier_is_still_rejected() {
+        let trace = [2_000, 2_000, 300, 2_000, 2_000];
+        assert_eq!(
+            run_front_distance_filter(&trace, 500),
+            [Some(2_000), Some(2_000), None, Some(2_000), Some(2_000)]
+        );
+    }
+
+    #[test]
+    fn smoothing_catches_up_once_the_obstacle_is_gone() {
+        let trace = [2_000, 2_000, 300, 300, 300, 700, 700, 700, 700];
+        let filtered = run_front_distance_filter(&trace, 500);
+        assert_eq!(filtered[4], Some(300));
+        // above the minimum the smoothed distance is used again, starting from the close measurement
+        assert_eq!(filtered[5], Some(500));
+        assert_eq!(filtered[6], Some(600));
+        assert_eq!(filtered[8], Some(675));
+    }
+
+    fn estimate_closing_speed(trace: &[u16]) -> Vec<Option<i32>> {
+        let mut estimator = ClosingSpeedEstimator::default();
+        trace
+            .iter()
+            .enumerate()
+            .map(|(i, &distance_in_mm)| estimator.update(distance_in_mm, at(i)))
+            .collect()
+    }
+
+    #[test]
+    fn closing_speed_of_an_approaching_obstacle() {
+        // approaching with 1m/s
+        let trace: Vec<u16> = (0..20).map(|i| 2_000 - i * 100).collect();
+        let estimates = estimate_closing_speed(&trace);
+        assert_eq!(estimates[0], None);
+        assert!(estimates[1..]
+            .iter()
+            .all(|estimate| *estimate == Some(1_000)));
+    }
+
+    #[test]
+    fn closing_speed_of_a_static_obstacle() {
+        let estimates = estimate_closing_speed(&[1_000; 10]);
+        assert!(estimates[1..].iter().all(|estimate| *estimate == Some(0)));
+    }
+
+    #[test]
+    fn closing_speed_of_a_receding_obstacle_is_negative() {
+        // getting further away with 0.5m/s
+        let trace: Vec<u16> = (0..10).map(|i| 1_000 + i * 50).collect();
+        let estimates = estimate_closing_speed(&trace);
+        assert_eq!(estimates.last(), Some(&Some(-500)));
+    }
+
+    #[test]
+    fn closing_speed_is_smoothed() {
+        let estimates = estimate_closing_speed(&[1_000, 1_000, 1_000, 900, 900]);
+        // a step of 1m/s is weighted with 1/4
+        assert_eq!(estimates[3], Some(250));
+        assert_eq!(estimates[4], Some(187));
+    }
+
+    #[test]
+    fn closing_speed_ignores_measurements_at_the_same_time() {
+        let mut estimator = ClosingSpeedEstimator::default();
+        estimator.update(1_000, at(0));
+        assert_eq!(estimator.update(900, at(1)), Some(1_000));
+        assert_eq!(estimator.update(500, at(1)), Some(1_000));
+
+        estimator.reset();
+        assert_eq!(estimator.closing_speed_in_mm_per_s(), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! Drive profiles restrict what the car is allowed to do, e.g. the "kid mode" used when the car is lent to children
+//! at outreach events.
+//!
+//! The limits are enforced by the car itself (see `Car::set_drive_profile`), independent of the source controlling it
+//! (see `arbiter`). The remote control additionally uses the [`SpeedCurve`] of the profile. The selected profile is
+//! persisted in the configuration, switching it is locked behind a key sequence (see `RemoteControl`).
+
+use crate::speed_control::MOTOR_DEAD_BAND_IN_PERCENT;
+
+/// A set of [`DriveLimits`] which can be selected.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DriveProfile {
+    /// The car can use its full speed & steering range.
+    #[default]
+    Normal,
+    /// Slow, with a reduced steering range, a larger safety distance and a geofence.
+    Kid,
+}
+
+const _: () = assert!(
+    DriveProfile::Normal.limits().speed_curve.min_duty > MOTOR_DEAD_BAND_IN_PERCENT
+        && DriveProfile::Kid.limits().speed_curve.min_duty > MOTOR_DEAD_BAND_IN_PERCENT
+);
+
+/// The limits of a [`DriveProfile`].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DriveLimits {
+    /// The maximum speed (in percentage) while driving forward. Higher speeds are reduced to this.
+    pub max_forward_speed: u8,
+    /// The maximum speed (in percentage) while driving backwards. Higher speeds are reduced to this.
+    pub max_backwards_speed: u8,
+    /// The maximum steering angle (in percentage of the calibrated maximum) in either direction.
+    pub max_steering: u8,
+    /// How the remote control changes the speed with every button press.
+    pub speed_curve: SpeedCurve,
+    /// The minimum front distance. If the distance is less than this the car will do an emergency brake.
+    pub min_front_distance_in_mm: u16,
+    /// How far the car may get from where it has been switched on (based on `Car::pose`), `None` if it may drive
+    /// anywhere. Outside the geofence the car only drives back towards its start.
+    pub geofence_radius_in_mm: Option<u16>,
+    /// The front distance down to which the car may still creep forward while the collision avoidance is overridden
+    /// (see `Car::set_collision_override`), `None` if it can't be overridden.
+    pub collision_override_min_front_distance_in_mm: Option<u16>,
+}
+
+impl DriveProfile {
+    pub const fn limits(&self) -> DriveLimits {
+        match self {
+            DriveProfile::Normal => DriveLimits {
+                max_forward_speed: 100,
+                max_backwards_speed: 100,
+                max_steering: 100,
+                speed_curve: SpeedCurve {
+                    steps: 4,
+                    expo: 0,
+                    min_duty: 25,
+                },
+                min_front_distance_in_mm: 500,
+                geofence_radius_in_mm: None,
+                collision_override_min_front_distance_in_mm: Some(100),
+            },
+            DriveProfile::Kid => DriveLimits {
+                max_forward_speed: 40,
+                max_backwards_speed: 30,
+                max_steering: 70,
+                speed_curve: SpeedCurve {
+                    steps: 5,
+                    expo: 50,
+                    min_duty: 25,
+                },
+                min_front_distance_in_mm: 800,
+                geofence_radius_in_mm: Some(5_000),
+                // the safety distance must not be undone
+                collision_override_min_front_distance_in_mm: None,
+            },
+        }
+    }
+
+    /// The profile which is selected after this one when switching the profile.
+    pub fn next(self) -> DriveProfile {
+        match self {
+            DriveProfile::Normal => DriveProfile::Kid,
+            DriveProfile::Kid => DriveProfile::Normal,
+        }
+    }
+}
+
+impl DriveLimits {
+    /// The speed (in percentage, negative to drive backwards) of the step of the speed curve (negative to drive
+    /// backwards, `0` to stop).
+    pub fn speed_of_step(&self, step: i8) -> i8 {
+        if step >= 0 {
+            self.speed_curve
+                .speed(step.unsigned_abs(), self.max_forward_speed) as i8
+        } else {
+            -(self
+                .speed_curve
+                .speed(step.unsigned_abs(), self.max_backwards_speed) as i8)
+        }
+    }
+
+    /// The highest step of the speed curve which isn't faster than the speed (in percentage, negative to drive
+    /// backwards), e.g. to find out where the remote control continues if the speed has been reduced by the car.
+    pub fn step_of_speed(&self, speed: i8) -> i8 {
+        let step = (1..=self.speed_curve.steps as i8)
+            .rev()
+            .find(|&step| {
+                self.speed_of_step(step * speed.signum()).unsigned_abs() <= speed.unsigned_abs()
+            })
+            .unwrap_or(0);
+        step * speed.signum()
+    }
+
+    /// Whether the car may creep forward towards the obstacle in front (`None` if there is no valid front distance)
+    /// while the collision avoidance is overridden.
+    pub fn may_creep_forward(&self, front_distance_in_mm: Option<u16>) -> bool {
+        match (
+            self.collision_override_min_front_distance_in_mm,
+            front_distance_in_mm,
+        ) {
+            (Some(min_distance_in_mm), Some(distance_in_mm)) => {
+                distance_in_mm >= min_distance_in_mm
+            }
+            _ => false,
+        }
+    }
+
+    /// The number of steps from standing still to the maximum speed in either direction.
+    pub fn max_step(&self) -> i8 {
+        self.speed_curve.steps as i8
+    }
+}
+
+/// How the speed steps of the remote control (one per button press) are mapped to the speed of the motor.
+///
+/// The first step starts at [`SpeedCurve::min_duty`] (which needs to be above [`MOTOR_DEAD_BAND_IN_PERCENT`], slower
+/// speeds only make the motor hum without moving the car), the last one reaches the maximum speed. In between the curve
+/// is `(1 - expo) * x + expo * x^3` as with the steering (see `SteeringCalibration::expo`), i.e. the steps are finer at
+/// low speeds and coarser at high speeds.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SpeedCurve {
+    /// The number of steps from standing still to the maximum speed, this defines the size of the steps.
+    pub steps: u8,
+    /// How non-linear the curve is (in percentage: 0% = linear, 100% = fully cubic).
+    pub expo: u8,
+    /// The speed (in percentage) of the first step, i.e. the minimum speed at which the car actually moves.
+    pub min_duty: u8,
+}
+
+impl SpeedCurve {
+    /// The speed (in percentage) of the step in one direction with the given maximum speed.
+    fn speed(&self, step: u8, max_speed: u8) -> u8 {
+        if step == 0 || self.steps == 0 {
+            return 0;
+        }
+        let min_duty = self.min_duty.min(max_speed) as u64;
+        let range = max_speed as u64 - min_duty;
+        if self.steps == 1 {
+            return max_speed;
+        }
+        // the position on the curve is x = n / d, the first step is at 0 and the last one at 1.
+        let n = step.min(self.steps) as u64 - 1;
+        let d = self.steps as u64 - 1;
+        let expo = self.expo.min(100) as u64;
+        // the curve, scaled by 100 * d^3.
+        let curve = (100 - expo) * n * d * d + expo * n.pow(3);
+        let divisor = 100 * d.pow(3);
+        (min_duty + (range * curve + divisor / 2) / divisor) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kid_profile_uses_fine_speed_steps() {
+        let limits = DriveProfile::Kid.limits();
+        let forward: Vec<i8> = (0..=limits.max_step())
+            .map(|step| limits.speed_of_step(step))
+            .collect();
+        assert_eq!(forward, [0, 25, 27, 30, 34, 40]);
+        assert_eq!(limits.speed_of_step(-limits.max_step()), -30);
+    }
+
+    #[test]
+    fn speed_steps_continue_from_a_reduced_speed() {
+        let limits = DriveProfile::Normal.limits();
+        assert_eq!(limits.step_of_speed(60), 2);
+        assert_eq!(limits.step_of_speed(-50), -2);
+        assert_eq!(limits.step_of_speed(0), 0);
+    }
+
+    #[test]
+    fn collision_override_allows_creeping_forward_in_normal_profile() {
+        let limits = DriveProfile::Normal.limits();
+        assert!(limits.may_creep_forward(Some(300)));
+        assert!(limits.may_creep_forward(Some(100)));
+        assert!(!limits.may_creep_forward(Some(99)));
+        assert!(!limits.may_creep_forward(None));
+    }
+
+    #[test]
+    fn collision_override_has_no_effect_in_kid_profile() {
+        let limits = DriveProfile::Kid.limits();
+        assert!(!limits.may_creep_forward(Some(300)));
+        assert!(!limits.may_creep_forward(Some(limits.min_front_distance_in_mm - 1)));
+    }
+}
@@ -0,0 +1,18 @@
+//! The hardware-independent logic of the robotcar (calculations, filters & controllers).
+//!
+//! It doesn't depend on the HAL (and only optionally on `defmt`), thus it can be tested on the host:
+//! `cargo test-host` (see `.cargo/config`).
+
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+#![deny(warnings)]
+
+pub mod battery;
+pub mod collision;
+pub mod distance_filter;
+pub mod drive_profile;
+pub mod manoeuvre;
+pub mod odometry;
+pub mod pid;
+pub mod speed_control;
+pub mod steering;
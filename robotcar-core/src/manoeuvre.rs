@@ -0,0 +1,191 @@
+//! Sequences of manoeuvres (e.g. "forward 50% for 2s, steer left 60%, reverse 1s, stop") and their encoding in the
+//! configuration. The sequences are played by the `ManoeuvrePlayer` of the firmware.
+
+use crate::steering::Direction;
+
+/// The maximum number of manoeuvres in a sequence.
+pub const MAX_MANOEUVRES: usize = 16;
+
+/// A single step of a [`ManoeuvreSequence`].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Manoeuvre {
+    /// Drive with the speed (in percentage, negative to drive backwards) for the given time.
+    /// The car keeps driving afterwards until the speed is changed by a later manoeuvre.
+    Drive { speed: i8, duration_in_ms: u16 },
+    /// Steer in the given direction, this takes effect immediately (i.e. while driving).
+    Steer(Direction),
+    /// Brake and wait for the given time.
+    Stop { duration_in_ms: u16 },
+}
+
+impl Manoeuvre {
+    /// The size of an encoded manoeuvre, see [`Manoeuvre::to_bytes`].
+    const SIZE: usize = 4;
+
+    /// How long the manoeuvre takes before the next one starts.
+    pub fn duration_in_ms(&self) -> u32 {
+        match self {
+            Manoeuvre::Drive { duration_in_ms, .. } | Manoeuvre::Stop { duration_in_ms } => {
+                *duration_in_ms as u32
+            }
+            Manoeuvre::Steer(_) => 0,
+        }
+    }
+
+    /// Encode the manoeuvre: the variant in the first byte, followed by its data.
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        match self {
+            Manoeuvre::Drive {
+                speed,
+                duration_in_ms,
+            } => {
+                let [duration_low, duration_high] = duration_in_ms.to_le_bytes();
+                [0, speed as u8, duration_low, duration_high]
+            }
+            Manoeuvre::Steer(Direction::Centre) => [1, 0, 0, 0],
+            Manoeuvre::Steer(Direction::Left(percentage)) => [1, 1, percentage, 0],
+            Manoeuvre::Steer(Direction::Right(percentage)) => [1, 2, percentage, 0],
+            Manoeuvre::Stop { duration_in_ms } => {
+                let [duration_low, duration_high] = duration_in_ms.to_le_bytes();
+                [2, 0, duration_low, duration_high]
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Manoeuvre> {
+        let duration_in_ms = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let manoeuvre = match (bytes[0], bytes[1]) {
+            (0, speed) => Manoeuvre::Drive {
+                speed: speed as i8,
+                duration_in_ms,
+            },
+            (1, 0) => Manoeuvre::Steer(Direction::Centre),
+            (1, 1) => Manoeuvre::Steer(Direction::Left(bytes[2])),
+            (1, 2) => Manoeuvre::Steer(Direction::Right(bytes[2])),
+            (2, 0) => Manoeuvre::Stop { duration_in_ms },
+            _ => return None,
+        };
+        Some(manoeuvre)
+    }
+}
+
+/// A sequence of up to [`MAX_MANOEUVRES`] manoeuvres which are executed one after the other.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ManoeuvreSequence {
+    manoeuvres: [Manoeuvre; MAX_MANOEUVRES],
+    len: usize,
+}
+
+impl ManoeuvreSequence {
+    /// The sequence used unless another one has been stored in the configuration.
+    pub const DEMO: ManoeuvreSequence = ManoeuvreSequence::new(&[
+        Manoeuvre::Drive {
+            speed: 50,
+            duration_in_ms: 2_000,
+        },
+        Manoeuvre::Steer(Direction::Left(60)),
+        Manoeuvre::Drive {
+            speed: -50,
+            duration_in_ms: 1_000,
+        },
+        Manoeuvre::Steer(Direction::Centre),
+        Manoeuvre::Stop { duration_in_ms: 0 },
+    ]);
+
+    /// The size of an encoded sequence: the number of manoeuvres followed by all manoeuvres.
+    pub const SIZE: usize = 1 + MAX_MANOEUVRES * Manoeuvre::SIZE;
+
+    /// Create a sequence from the manoeuvres. Manoeuvres beyond [`MAX_MANOEUVRES`] are ignored.
+    pub const fn new(manoeuvres: &[Manoeuvre]) -> ManoeuvreSequence {
+        let mut sequence = ManoeuvreSequence {
+            manoeuvres: [Manoeuvre::Stop { duration_in_ms: 0 }; MAX_MANOEUVRES],
+            len: 0,
+        };
+        while sequence.len < manoeuvres.len() && sequence.len < MAX_MANOEUVRES {
+            sequence.manoeuvres[sequence.len] = manoeuvres[sequence.len];
+            sequence.len += 1;
+        }
+        sequence
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn manoeuvres(&self) -> &[Manoeuvre] {
+        &self.manoeuvres[..self.len]
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.len as u8;
+        for (manoeuvre, chunk) in self
+            .manoeuvres()
+            .iter()
+            .zip(bytes[1..].chunks_exact_mut(Manoeuvre::SIZE))
+        {
+            chunk.copy_from_slice(&manoeuvre.to_bytes());
+        }
+        bytes
+    }
+
+    /// Decode the sequence, see [`ManoeuvreSequence::to_bytes`]. An empty sequence is rejected as there would be
+    /// nothing to play.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ManoeuvreSequence> {
+        if bytes.len() != Self::SIZE || bytes[0] == 0 || bytes[0] as usize > MAX_MANOEUVRES {
+            return None;
+        }
+        let mut sequence = ManoeuvreSequence::new(&[]);
+        for chunk in bytes[1..]
+            .chunks_exact(Manoeuvre::SIZE)
+            .take(bytes[0] as usize)
+        {
+            sequence.manoeuvres[sequence.len] = Manoeuvre::from_bytes(chunk)?;
+            sequence.len += 1;
+        }
+        Some(sequence)
+    }
+}
+
+impl Default for ManoeuvreSequence {
+    fn default() -> Self {
+        ManoeuvreSequence::DEMO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_sequence_can_be_decoded() {
+        let bytes = ManoeuvreSequence::DEMO.to_bytes();
+        assert_eq!(
+            ManoeuvreSequence::from_bytes(&bytes),
+            Some(ManoeuvreSequence::DEMO)
+        );
+    }
+
+    #[test]
+    fn empty_sequence_is_rejected() {
+        let bytes = ManoeuvreSequence::new(&[]).to_bytes();
+        assert_eq!(bytes[0], 0);
+        assert_eq!(ManoeuvreSequence::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn too_long_sequence_is_rejected() {
+        let mut bytes = ManoeuvreSequence::DEMO.to_bytes();
+        bytes[0] = MAX_MANOEUVRES as u8 + 1;
+        assert_eq!(ManoeuvreSequence::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn unknown_manoeuvre_is_rejected() {
+        let mut bytes = ManoeuvreSequence::DEMO.to_bytes();
+        bytes[1] = 3;
+        assert_eq!(ManoeuvreSequence::from_bytes(&bytes), None);
+    }
+}
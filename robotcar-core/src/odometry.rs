@@ -0,0 +1,80 @@
+//! Calculates the distance travelled and the speed of the car based on the pulses of the wheel encoder.
+//!
+//! This module is hardware-agnostic: it gets the value of the pulse counter, the actual counting is done by the
+//! consumer (e.g. a timer, see `wheel_encoder`). The encoder only has a single channel, thus it can't tell the
+//! direction, which is instead taken from the motor.
+
+/// The circumference of the wheels (65mm diameter) in µm.
+pub const WHEEL_CIRCUMFERENCE_IN_UM: i64 = 204_204;
+/// The number of pulses per revolution of the wheel: the encoder on the motor shaft delivers 13 pulses per revolution
+/// of the motor, which is followed by a 1:48 gearbox.
+pub const PULSES_PER_WHEEL_REVOLUTION: i64 = 13 * 48;
+/// Smoothing factor of the exponential moving average of the speed, expressed as a power of two:
+/// each new measurement is weighted with `1 / 2^SPEED_FILTER_SHIFT`.
+const SPEED_FILTER_SHIFT: u32 = 1;
+
+/// Keeps track of the distance travelled and the speed of the car.
+#[derive(Default)]
+pub struct Odometry {
+    /// The value of the pulse counter at the last update, `None` before the first update.
+    last_count: Option<u16>,
+    last_update: Option<fugit::TimerInstantU32<1_000_000>>,
+    /// The sum of all pulses since the start, negative ones for driving backwards.
+    pulses: i32,
+    /// The filtered speed.
+    speed_in_mm_per_s: i32,
+    /// Whether the motor has last been driving backwards.
+    backwards: bool,
+}
+
+impl Odometry {
+    /// Process the current value of the pulse counter (which may overflow) and return the distance travelled
+    /// (in µm, negative while driving backwards) since the last update (0 on the first update).
+    ///
+    /// `motor_speed` is the speed currently set on the motor, only its sign is used to decide on the direction.
+    /// If it's 0 the last direction is kept, as the car might still be rolling.
+    pub fn update(
+        &mut self,
+        count: u16,
+        motor_speed: i8,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) -> i32 {
+        if motor_speed != 0 {
+            self.backwards = motor_speed < 0;
+        }
+        let last_count = self.last_count.replace(count);
+        let last_update = self.last_update.replace(now);
+        let (last_count, last_update) = match last_count.zip(last_update) {
+            Some(last) => last,
+            None => return 0,
+        };
+
+        let mut pulses = count.wrapping_sub(last_count) as i32;
+        if self.backwards {
+            pulses = -pulses;
+        }
+        self.pulses += pulses;
+
+        let dt_in_us = now
+            .checked_duration_since(last_update)
+            .map_or(1, |dt| dt.to_micros().max(1));
+        let speed_in_mm_per_s = (pulses as i64 * WHEEL_CIRCUMFERENCE_IN_UM * 1_000
+            / (PULSES_PER_WHEEL_REVOLUTION * dt_in_us as i64))
+            as i32;
+        self.speed_in_mm_per_s +=
+            (speed_in_mm_per_s - self.speed_in_mm_per_s) >> SPEED_FILTER_SHIFT;
+
+        (pulses as i64 * WHEEL_CIRCUMFERENCE_IN_UM / PULSES_PER_WHEEL_REVOLUTION) as i32
+    }
+
+    /// The distance travelled since the start (driving backwards reduces it).
+    pub fn distance_travelled_in_mm(&self) -> i32 {
+        (self.pulses as i64 * WHEEL_CIRCUMFERENCE_IN_UM / (PULSES_PER_WHEEL_REVOLUTION * 1_000))
+            as i32
+    }
+
+    /// The current (filtered) speed, negative while driving backwards.
+    pub fn speed_in_mm_per_s(&self) -> i32 {
+        self.speed_in_mm_per_s
+    }
+}
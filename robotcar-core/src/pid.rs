@@ -0,0 +1,180 @@
+//! A generic PID controller.
+//!
+//! It only uses integer arithmetic and has no dependencies on the hardware (nor on `defmt`), thus it can be run in a
+//! simulation on the host (see [`crate::speed_control`]). The gains are given in thousandths to allow for
+//! fine-grained gains without floating point arithmetic.
+
+/// The gains are given in thousandths of the output unit, e.g. `kp = 200` results in an output of 0.2 per unit of error.
+pub const GAIN_SCALE: i64 = 1_000;
+
+/// The gains of a [`PidController`], scaled by [`GAIN_SCALE`].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct PidGains {
+    /// Proportional gain: output per unit of error.
+    pub kp: i32,
+    /// Integral gain: output per unit of error and second.
+    pub ki: i32,
+    /// Derivative gain: output per unit of change of the measurement per second.
+    pub kd: i32,
+}
+
+/// A PID controller with anti-windup.
+///
+/// * The integral term is clamped to the output limit and isn't integrated further while the output is saturated
+///   (conditional integration), so that the controller doesn't overshoot after having been saturated for a while.
+/// * The derivative term is calculated on the measurement instead of the error, so that a change of the
+///   setpoint doesn't cause a spike in the output ("derivative kick"). It can additionally be smoothed, as it
+///   amplifies the noise of the measurement (see [`PidController::with_derivative_filter`]).
+pub struct PidController {
+    gains: PidGains,
+    /// The output is limited to `-output_limit..=output_limit`.
+    output_limit: i32,
+    /// The integral term, scaled by [`GAIN_SCALE`].
+    integral: i64,
+    /// The (filtered) derivative term, scaled by [`GAIN_SCALE`].
+    derivative: i64,
+    /// Smoothing factor of the derivative term, expressed as a power of two: each new value is weighted with
+    /// `1 / 2^derivative_filter_shift` (0: not filtered).
+    derivative_filter_shift: u32,
+    previous_measurement: Option<i32>,
+}
+
+impl PidController {
+    pub const fn new(gains: PidGains, output_limit: i32) -> PidController {
+        PidController {
+            gains,
+            output_limit,
+            integral: 0,
+            derivative: 0,
+            derivative_filter_shift: 0,
+            previous_measurement: None,
+        }
+    }
+
+    /// Smooth the derivative term with an exponential moving average, each new value is weighted with `1 / 2^shift`.
+    pub const fn with_derivative_filter(mut self, shift: u32) -> PidController {
+        self.derivative_filter_shift = shift;
+        self
+    }
+
+    /// Change the limit of the output (e.g. because the maximum speed has been reduced).
+    /// The integral term is clamped to the new limit.
+    pub fn set_output_limit(&mut self, output_limit: i32) {
+        self.output_limit = output_limit;
+        let limit = self.scaled_output_limit();
+        self.integral = self.integral.clamp(-limit, limit);
+    }
+
+    /// Forget the history (integral & previous measurement), e.g. because the setpoint has changed direction.
+    pub fn reset(&mut self) {
+        self.integral = 0;
+        self.derivative = 0;
+        self.previous_measurement = None;
+    }
+
+    /// Calculate the new output based on the current measurement. `dt_in_ms` is the time since the last update.
+    pub fn update(&mut self, setpoint: i32, measurement: i32, dt_in_ms: u32) -> i32 {
+        let dt_in_ms = dt_in_ms.max(1) as i64;
+        let limit = self.scaled_output_limit();
+        let error = setpoint as i64 - measurement as i64;
+
+        let proportional = self.gains.kp as i64 * error;
+        let integral =
+            (self.integral + self.gains.ki as i64 * error * dt_in_ms / 1_000).clamp(-limit, limit);
+        let derivative = match self.previous_measurement {
+            Some(previous_measurement) => {
+                let derivative = -(self.gains.kd as i64
+                    * (measurement as i64 - previous_measurement as i64)
+                    * 1_000
+                    / dt_in_ms);
+                self.derivative += (derivative - self.derivative) >> self.derivative_filter_shift;
+                self.derivative
+            }
+            None => 0,
+        };
+        self.previous_measurement = Some(measurement);
+
+        let output = proportional + integral + derivative;
+        let saturated_output = output.clamp(-limit, limit);
+        // only integrate if this doesn't drive the output further into saturation
+        if output == saturated_output || (output > saturated_output) != (error > 0) {
+            self.integral = integral;
+        }
+
+        (saturated_output / GAIN_SCALE) as i32
+    }
+
+    fn scaled_output_limit(&self) -> i64 {
+        self.output_limit as i64 * GAIN_SCALE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: PidGains = PidGains {
+        kp: 1_000,
+        ki: 0,
+        kd: 0,
+    };
+    const D: PidGains = PidGains {
+        kp: 0,
+        ki: 0,
+        kd: 100,
+    };
+
+    #[test]
+    fn proportional_output_is_limited() {
+        let mut controller = PidController::new(P, 100);
+        assert_eq!(controller.update(50, 20, 20), 30);
+        assert_eq!(controller.update(50, 80, 20), -30);
+        assert_eq!(controller.update(500, 0, 20), 100);
+        controller.set_output_limit(20);
+        assert_eq!(controller.update(500, 0, 20), 20);
+    }
+
+    #[test]
+    fn integral_doesnt_wind_up_while_saturated() {
+        let gains = PidGains {
+            kp: 1_000,
+            ki: 1_000,
+            kd: 0,
+        };
+        let mut controller = PidController::new(gains, 100);
+        for _ in 0..100 {
+            assert_eq!(controller.update(1_000, 0, 100), 100);
+        }
+        // the setpoint is reached: without the anti-windup the integral would keep the output saturated
+        assert_eq!(controller.update(1_000, 1_000, 100), 0);
+    }
+
+    #[test]
+    fn derivative_acts_on_the_change_of_the_measurement() {
+        let mut controller = PidController::new(D, 100);
+        assert_eq!(controller.update(0, 100, 20), 0);
+        // 2mm in 20ms => 100mm/s
+        assert_eq!(controller.update(0, 102, 20), -10);
+        assert_eq!(controller.update(0, 102, 20), 0);
+        // a change of the setpoint doesn't cause a spike
+        assert_eq!(controller.update(500, 102, 20), 0);
+    }
+
+    #[test]
+    fn filtered_derivative_smooths_jitter() {
+        let mut controller = PidController::new(D, 100).with_derivative_filter(2);
+        assert_eq!(controller.update(0, 100, 20), 0);
+        // a single jitter of the measurement only has a quarter of the effect
+        assert_eq!(controller.update(0, 104, 20), -5);
+        assert_eq!(controller.update(0, 100, 20), 1);
+        // a steady change is still fully taken into account
+        let outputs: Vec<i32> = (1..=50)
+            .map(|i| controller.update(0, 100 + 2 * i, 20))
+            .collect();
+        assert_eq!(outputs.last(), Some(&-10));
+
+        controller.reset();
+        assert_eq!(controller.update(0, 100, 20), 0);
+        assert_eq!(controller.update(0, 104, 20), -5);
+    }
+}
@@ -0,0 +1,217 @@
+//! The tuning of the speed controller (see [`crate::pid::PidController`]) which drives the motor at a target speed,
+//! measured by the [`crate::odometry::Odometry`].
+
+use crate::pid::PidGains;
+
+/// Below this speed (in percentage) the motor only hums without moving the car (this is the worst case, depending on
+/// the motor, the battery and the load it starts moving from 10% on). Every minimum speed at which the car is meant to
+/// move (e.g. the first step of the remote control) must be above it.
+pub const MOTOR_DEAD_BAND_IN_PERCENT: u8 = 20;
+
+/// The gains of the speed controller (output: speed in percentage, input: speed in mm/s).
+///
+/// They are checked in a simulation (see the tests) with a first order model of the motor (time constant 120-200ms,
+/// 7-14mm/s per percent above a dead band of 10% to [`MOTOR_DEAD_BAND_IN_PERCENT`], the speed measured by the [`crate::odometry::Odometry`] every
+/// 20ms): the target speed is reached (within 5%) in less than 450ms, with an overshoot of up to 15% for a fast
+/// motor. After an additional 30% load the speed recovers within 450ms. The D-term isn't needed as the motor
+/// doesn't oscillate.
+pub const SPEED_CONTROLLER_GAINS: PidGains = PidGains {
+    kp: 200,
+    ki: 1_500,
+    kd: 0,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::odometry::{Odometry, PULSES_PER_WHEEL_REVOLUTION, WHEEL_CIRCUMFERENCE_IN_UM};
+    use crate::pid::PidController;
+
+    /// The speed controller is updated together with the odometry.
+    const CONTROL_INTERVAL_IN_MS: u32 = 20;
+
+    /// A first order model of the motor: the speed approaches the steady state speed of the current duty with the
+    /// time constant. Below the dead band the motor doesn't move at all.
+    struct Motor {
+        time_constant_in_ms: f32,
+        speed_per_percent_in_mm_per_s: f32,
+        dead_band_in_percent: f32,
+        /// The fraction of the speed lost due to an additional load (e.g. driving uphill).
+        load: f32,
+        speed_in_mm_per_s: f32,
+        position_in_um: f32,
+    }
+
+    impl Motor {
+        fn new(
+            time_constant_in_ms: f32,
+            speed_per_percent_in_mm_per_s: f32,
+            dead_band_in_percent: f32,
+        ) -> Self {
+            Motor {
+                time_constant_in_ms,
+                speed_per_percent_in_mm_per_s,
+                dead_band_in_percent,
+                load: 0.0,
+                speed_in_mm_per_s: 0.0,
+                position_in_um: 0.0,
+            }
+        }
+
+        /// Simulate one millisecond with the given duty.
+        fn step(&mut self, duty_in_percent: f32) {
+            let steady_state_speed = (duty_in_percent - self.dead_band_in_percent).max(0.0)
+                * self.speed_per_percent_in_mm_per_s
+                * (1.0 - self.load);
+            self.speed_in_mm_per_s +=
+                (steady_state_speed - self.speed_in_mm_per_s) / self.time_constant_in_ms;
+            self.position_in_um += self.speed_in_mm_per_s;
+        }
+
+        /// The value of the (16 bit) pulse counter of the wheel encoder.
+        fn encoder_count(&self) -> u16 {
+            (self.position_in_um as i64 * PULSES_PER_WHEEL_REVOLUTION / WHEEL_CIRCUMFERENCE_IN_UM)
+                as u16
+        }
+    }
+
+    struct Response {
+        /// The time until the (actual) speed stays within 5% of the target speed.
+        settling_time_in_ms: Option<u32>,
+        /// The max. speed above the target speed, in percent of the target speed.
+        overshoot_in_percent: f32,
+    }
+
+    /// Drive the motor at the target speed for `duration_in_ms`, starting at `start_in_ms`, and measure the
+    /// response of the speed from that point on.
+    fn run(
+        motor: &mut Motor,
+        controller: &mut PidController,
+        odometry: &mut Odometry,
+        target_speed_in_mm_per_s: i32,
+        start_in_ms: u32,
+        duration_in_ms: u32,
+    ) -> Response {
+        let mut duty = 0;
+        let mut settling_time_in_ms = None;
+        let mut max_speed_in_mm_per_s = 0.0f32;
+        for t in start_in_ms..start_in_ms + duration_in_ms {
+            if t % CONTROL_INTERVAL_IN_MS == 0 {
+                odometry.update(
+                    motor.encoder_count(),
+                    duty as i8,
+                    fugit::TimerInstantU32::from_ticks(t * 1_000),
+                );
+                // as done by the car: the controller only drives forward
+                duty = controller
+                    .update(
+                        target_speed_in_mm_per_s,
+                        odometry.speed_in_mm_per_s(),
+                        CONTROL_INTERVAL_IN_MS,
+                    )
+                    .clamp(0, 100);
+            }
+            motor.step(duty as f32);
+
+            let speed = motor.speed_in_mm_per_s;
+            max_speed_in_mm_per_s = max_speed_in_mm_per_s.max(speed);
+            let within_band = (speed - target_speed_in_mm_per_s as f32).abs()
+                <= 0.05 * target_speed_in_mm_per_s as f32;
+            match (within_band, settling_time_in_ms) {
+                (true, None) => settling_time_in_ms = Some(t - start_in_ms),
+                (false, Some(_)) => settling_time_in_ms = None,
+                _ => {}
+            }
+        }
+        Response {
+            settling_time_in_ms,
+            overshoot_in_percent: ((max_speed_in_mm_per_s / target_speed_in_mm_per_s as f32 - 1.0)
+                * 100.0)
+                .max(0.0),
+        }
+    }
+
+    /// The motors the gains have to work with.
+    fn motors() -> impl Iterator<Item = Motor> {
+        [120.0, 200.0].into_iter().flat_map(|time_constant_in_ms| {
+            [7.0, 14.0]
+                .into_iter()
+                .flat_map(move |speed_per_percent_in_mm_per_s| {
+                    [10.0, MOTOR_DEAD_BAND_IN_PERCENT as f32].into_iter().map(
+                        move |dead_band_in_percent| {
+                            Motor::new(
+                                time_constant_in_ms,
+                                speed_per_percent_in_mm_per_s,
+                                dead_band_in_percent,
+                            )
+                        },
+                    )
+                })
+        })
+    }
+
+    const TARGET_SPEEDS_IN_MM_PER_S: [i32; 2] = [150, 300];
+
+    #[test]
+    fn speed_controller_reaches_the_target_speed() {
+        for target_speed_in_mm_per_s in TARGET_SPEEDS_IN_MM_PER_S {
+            for mut motor in motors() {
+                let mut controller = PidController::new(SPEED_CONTROLLER_GAINS, 100);
+                let mut odometry = Odometry::default();
+                let response = run(
+                    &mut motor,
+                    &mut controller,
+                    &mut odometry,
+                    target_speed_in_mm_per_s,
+                    0,
+                    2_000,
+                );
+                assert!(
+                    response.settling_time_in_ms.is_some_and(|t| t < 450),
+                    "settling time {:?}ms at {}mm/s",
+                    response.settling_time_in_ms,
+                    target_speed_in_mm_per_s
+                );
+                assert!(
+                    response.overshoot_in_percent < 15.0,
+                    "overshoot {}% at {}mm/s",
+                    response.overshoot_in_percent,
+                    target_speed_in_mm_per_s
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn speed_controller_recovers_from_a_load_step() {
+        for target_speed_in_mm_per_s in TARGET_SPEEDS_IN_MM_PER_S {
+            for mut motor in motors() {
+                let mut controller = PidController::new(SPEED_CONTROLLER_GAINS, 100);
+                let mut odometry = Odometry::default();
+                run(
+                    &mut motor,
+                    &mut controller,
+                    &mut odometry,
+                    target_speed_in_mm_per_s,
+                    0,
+                    2_000,
+                );
+                motor.load = 0.3;
+                let response = run(
+                    &mut motor,
+                    &mut controller,
+                    &mut odometry,
+                    target_speed_in_mm_per_s,
+                    2_000,
+                    2_000,
+                );
+                assert!(
+                    response.settling_time_in_ms.is_some_and(|t| t < 450),
+                    "recovery time {:?}ms at {}mm/s",
+                    response.settling_time_in_ms,
+                    target_speed_in_mm_per_s
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,801 @@
+//! This represents a steering unit on the robotcar, powered by a servo motor: the calibration of the servo, the
+//! conversion of steering angles to pulse widths & PWM duties and the driver which slews the servo.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+use embedded_hal::PwmPin;
+use fugit::{HertzU32, TimerInstantU32};
+use Direction::{Centre, Left, Right};
+use Error::InvalidPercentage;
+
+/// The shortest pulse width (in µs) which is safe for the servo, the servo might get damaged below this.
+pub const SERVO_MIN_SAFE_PULSE_WIDTH_IN_US: u16 = 1000;
+/// The longest pulse width (in µs) which is safe for the servo, the servo might get damaged above this.
+pub const SERVO_MAX_SAFE_PULSE_WIDTH_IN_US: u16 = 2000;
+
+/// The calibration of the steering servo. This differs slightly for each servo and thus for each car.
+///
+/// All values are pulse widths in µs and thus independent of the PWM frequency and timer configuration.
+/// The end points are relative to the trimmed centre (`centre + trim`). They can differ as the steering
+/// linkage of the chassis isn't necessarily symmetric.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SteeringCalibration {
+    /// The pulse width at which the servo is in its (mechanical) centre position.
+    pub centre: u16,
+    /// Offset added to the centre so that the car drives straight ahead.
+    pub trim: i16,
+    /// The pulse width which needs to be subtracted from the trimmed centre to achieve the maximum steering angle to the left.
+    pub max_left: u16,
+    /// The pulse width which needs to be added to the trimmed centre to achieve the maximum steering angle to the right.
+    pub max_right: u16,
+    /// How non-linear the steering curve is (in percentage: 0% = linear, 100% = fully cubic).
+    /// A higher value allows finer steering around the centre while still reaching the end points.
+    pub expo: u8,
+}
+
+impl SteeringCalibration {
+    /// Pulse widths empirically determined for the servo in our car.
+    pub const DEFAULT: SteeringCalibration = SteeringCalibration {
+        centre: 1504,
+        trim: 0,
+        max_left: 244,
+        max_right: 244,
+        expo: 0,
+    };
+
+    /// The pulse width at which the car drives straight ahead.
+    pub const fn trimmed_centre(&self) -> Option<u16> {
+        self.centre.checked_add_signed(self.trim)
+    }
+
+    /// Check whether all pulse widths resulting from this calibration are within the safe range of the servo.
+    pub const fn is_within_safe_range(&self) -> bool {
+        let centre = match self.trimmed_centre() {
+            Some(centre) => centre,
+            None => return false,
+        };
+        let min_pulse_width = match centre.checked_sub(self.max_left) {
+            Some(min_pulse_width) => min_pulse_width,
+            None => return false,
+        };
+        let max_pulse_width = match centre.checked_add(self.max_right) {
+            Some(max_pulse_width) => max_pulse_width,
+            None => return false,
+        };
+        self.expo <= 100
+            && min_pulse_width >= SERVO_MIN_SAFE_PULSE_WIDTH_IN_US
+            && max_pulse_width <= SERVO_MAX_SAFE_PULSE_WIDTH_IN_US
+    }
+
+    /// Check whether the calibration is safe for the servo and can be realised with a PWM of the given frequency.
+    pub fn is_valid_for(&self, pwm_frequency: HertzU32) -> bool {
+        // the longest pulse must fit into a single PWM period.
+        let period_in_us = 1_000_000 / pwm_frequency.raw().max(1);
+        self.is_within_safe_range() && (SERVO_MAX_SAFE_PULSE_WIDTH_IN_US as u32) < period_in_us
+    }
+
+    /// Returns a copy of this calibration where the `parameter` has been changed by `delta`.
+    /// Note that the result is not validated, see [`Self::is_within_safe_range`].
+    pub fn adjusted(self, parameter: CalibrationParameter, delta: i16) -> SteeringCalibration {
+        let mut calibration = self;
+        match parameter {
+            CalibrationParameter::Centre => {
+                calibration.centre = self.centre.saturating_add_signed(delta)
+            }
+            CalibrationParameter::Trim => calibration.trim = self.trim.saturating_add(delta),
+            CalibrationParameter::MaxLeft => {
+                calibration.max_left = self.max_left.saturating_add_signed(delta)
+            }
+            CalibrationParameter::MaxRight => {
+                calibration.max_right = self.max_right.saturating_add_signed(delta)
+            }
+            CalibrationParameter::Expo => {
+                calibration.expo = (self.expo as i16).saturating_add(delta).clamp(0, 100) as u8
+            }
+        }
+        calibration
+    }
+
+    /// The pulse width for steering to the left (by the given percentage of the maximum steering angle).
+    pub fn left_pulse_width(&self, percentage: u8) -> u16 {
+        self.centre_pulse_width()
+            .saturating_sub(self.side_offset(self.max_left, percentage))
+            .max(SERVO_MIN_SAFE_PULSE_WIDTH_IN_US)
+    }
+
+    /// The pulse width for steering to the right (by the given percentage of the maximum steering angle).
+    pub fn right_pulse_width(&self, percentage: u8) -> u16 {
+        self.centre_pulse_width()
+            .saturating_add(self.side_offset(self.max_right, percentage))
+            .min(SERVO_MAX_SAFE_PULSE_WIDTH_IN_US)
+    }
+
+    /// The pulse width for driving straight ahead. Never risks damaging the servo, even if the calibration
+    /// hasn't been validated.
+    pub fn centre_pulse_width(&self) -> u16 {
+        self.trimmed_centre().unwrap_or(self.centre).clamp(
+            SERVO_MIN_SAFE_PULSE_WIDTH_IN_US,
+            SERVO_MAX_SAFE_PULSE_WIDTH_IN_US,
+        )
+    }
+
+    /// Calculate the pulse width offset from the trimmed centre for the given percentage (0 - 100) of the `max_side`.
+    ///
+    /// The steering curve is `(1 - expo) * x + expo * x^3`, calculated with integers and rounded to
+    /// the nearest µs so that 100% exactly reaches the end point.
+    fn side_offset(&self, max_side: u16, percentage: u8) -> u16 {
+        let expo = self.expo.min(100) as u64;
+        let percentage = percentage.min(100) as u64;
+        // the curve, scaled by 100^3 (the percentages are scaled by 100 each).
+        let curve = (100 - expo) * percentage * 100 * 100 + expo * percentage.pow(3);
+        let divisor = 100 * 100 * 100 * 100;
+        ((max_side as u64 * curve + divisor / 2) / divisor) as u16
+    }
+}
+
+// ensure at compile time that the default calibration can't damage the servo.
+const _: () = assert!(SteeringCalibration::DEFAULT.is_within_safe_range());
+const _: () = assert!(SERVO_MIN_SAFE_PULSE_WIDTH_IN_US < SERVO_MAX_SAFE_PULSE_WIDTH_IN_US);
+
+impl Default for SteeringCalibration {
+    fn default() -> Self {
+        SteeringCalibration::DEFAULT
+    }
+}
+
+/// The individual parameters of a [`SteeringCalibration`].
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalibrationParameter {
+    Centre,
+    Trim,
+    MaxLeft,
+    MaxRight,
+    Expo,
+}
+
+/// Convert the pulse width to the duty of a PWM with the given maximum duty and frequency.
+/// Pulses longer than the period result in the maximum duty.
+pub fn duty_for_pulse_width(pulse_width_in_us: u16, max_duty: u16, pwm_frequency: HertzU32) -> u16 {
+    let max_duty = max_duty as u64;
+    let duty =
+        (pulse_width_in_us as u64 * max_duty * pwm_frequency.raw() as u64 + 500_000) / 1_000_000;
+    duty.min(max_duty) as u16
+}
+
+/// The change in pulse width (in µs) needed to turn the servo by one degree (standard servos turn by ca. 90° for 1000µs).
+pub const SERVO_PULSE_WIDTH_PER_DEGREE_IN_US: u32 = 11;
+/// The default maximum speed at which the steering turns if slewing is enabled.
+pub const DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND: u16 = 300;
+
+/// The PWM duty for the (trimmed) centre position with the current calibration, `0` until the steering has been
+/// set up. This allows the panic handler to centre the steering without access to the [`Steering`].
+static CENTRE_DUTY: AtomicU16 = AtomicU16::new(0);
+
+/// The PWM duty with which the steering is in its centre position, see `panic_handler`.
+pub fn centre_duty() -> Option<u16> {
+    match CENTRE_DUTY.load(Ordering::Relaxed) {
+        0 => None,
+        duty => Some(duty),
+    }
+}
+
+/// The steering unit of the robotcar.
+pub struct Steering<PWM>
+where
+    PWM: PwmPin,
+{
+    pwm: PWM,
+    /// The frequency of the PWM signal, needed to convert pulse widths to duties.
+    pwm_frequency: HertzU32,
+    calibration: SteeringCalibration,
+    current_direction: Direction,
+    /// The maximum speed at which the steering turns. `None` if the steering should turn as fast as the servo can.
+    slew_rate_in_degrees_per_second: Option<u16>,
+    /// The pulse width which corresponds to `current_direction`.
+    target_pulse_width: u16,
+    /// The pulse width currently sent to the servo. Differs from `target_pulse_width` while slewing.
+    current_pulse_width: u16,
+    /// The last time the slewing was advanced.
+    last_update: Option<TimerInstantU32<1_000_000>>,
+}
+
+/// Defines errors which can happen while trying to steer.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// An invalid steering angle has been defined. The steering angle must be given as a percentage value between 0 and 100 to be valid.
+    InvalidPercentage,
+    /// The calibration would result in a pulse width outside of the range which is safe for the servo or supported by the PWM.
+    InvalidCalibration,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Steer centre (straight ahead)
+    Centre,
+    /// Steer left with the defined angle (in percentage: 0% = centre, 100% = max. left)
+    Left(u8),
+    /// Steer right with the defined angle (in percentage: 0% = centre, 100% = max. right)
+    Right(u8),
+}
+
+impl<PWM> Steering<PWM>
+where
+    PWM: PwmPin<Duty = u16>,
+{
+    /// Set up the steering. The `pwm_frequency` must match the frequency with which the `pwm` has been configured.
+    ///
+    /// If a `slew_rate_in_degrees_per_second` is defined, [`Self::update`] must be called periodically
+    /// for the steering to follow the requested direction.
+    pub fn new(
+        mut pwm: PWM,
+        pwm_frequency: HertzU32,
+        calibration: SteeringCalibration,
+        slew_rate_in_degrees_per_second: Option<u16>,
+    ) -> Steering<PWM> {
+        pwm.enable();
+        let calibration = if calibration.is_valid_for(pwm_frequency) {
+            calibration
+        } else {
+            #[cfg(feature = "defmt")]
+            defmt::error!(
+                "invalid steering calibration {}, using the default instead",
+                calibration
+            );
+            SteeringCalibration::default()
+        };
+        let mut servo = Steering {
+            pwm,
+            pwm_frequency,
+            calibration,
+            current_direction: Centre,
+            slew_rate_in_degrees_per_second,
+            target_pulse_width: calibration.centre,
+            current_pulse_width: calibration.centre,
+            last_update: None,
+        };
+
+        servo.publish_centre_duty();
+        servo.steer_immediately(Centre).ok(); // centre will never fail as we don't specify a percentage
+
+        servo
+    }
+
+    /// Set the new steering direction. The direction will be kept until the next call which sets a new direction.
+    ///
+    /// If slewing is enabled the steering will turn towards the new direction with every call to [`Self::update`].
+    pub fn steer(&mut self, direction: Direction) -> Result<(), Error> {
+        self.target_pulse_width = self.pulse_width_for_direction(direction)?;
+        self.current_direction = direction;
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "steering {}, resulting in a pulse width of {}us",
+            direction,
+            self.target_pulse_width
+        );
+
+        if self.slew_rate_in_degrees_per_second.is_none() {
+            self.set_pulse_width(self.target_pulse_width);
+        }
+
+        Ok(())
+    }
+
+    /// Set the new steering direction and turn to it as fast as possible, ignoring the slew rate.
+    /// Use this for emergencies.
+    pub fn steer_immediately(&mut self, direction: Direction) -> Result<(), Error> {
+        self.target_pulse_width = self.pulse_width_for_direction(direction)?;
+        self.current_direction = direction;
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "steering {} immediately, resulting in a pulse width of {}us",
+            direction,
+            self.target_pulse_width
+        );
+        self.set_pulse_width(self.target_pulse_width);
+
+        Ok(())
+    }
+
+    /// Advance the slewing towards the requested direction. Needs to be called periodically if slewing is enabled.
+    pub fn update(&mut self, now: TimerInstantU32<1_000_000>) {
+        let slew_rate = match self.slew_rate_in_degrees_per_second {
+            Some(slew_rate) if self.current_pulse_width != self.target_pulse_width => slew_rate,
+            _ => {
+                self.last_update = Some(now);
+                return;
+            }
+        };
+        let last_update = match self.last_update {
+            Some(last_update) => last_update,
+            None => {
+                self.last_update = Some(now);
+                return;
+            }
+        };
+
+        let elapsed_in_us = now
+            .checked_duration_since(last_update)
+            .map_or(0, |elapsed| elapsed.to_micros());
+        // after a long pause this would overflow, the step is anyway limited by the target
+        let max_step =
+            (slew_rate as u64 * SERVO_PULSE_WIDTH_PER_DEGREE_IN_US as u64 * elapsed_in_us as u64
+                / 1_000_000)
+                .min(u16::MAX as u64) as u16;
+        if max_step == 0 {
+            // keep the last update time so that the elapsed time accumulates until a step can be done.
+            return;
+        }
+        self.last_update = Some(now);
+
+        let pulse_width = if self.current_pulse_width < self.target_pulse_width {
+            self.current_pulse_width
+                .saturating_add(max_step)
+                .min(self.target_pulse_width)
+        } else {
+            self.current_pulse_width
+                .saturating_sub(max_step)
+                .max(self.target_pulse_width)
+        };
+        self.set_pulse_width(pulse_width);
+    }
+
+    /// Change the maximum speed at which the steering turns. `None` disables the slewing.
+    pub fn set_slew_rate(&mut self, slew_rate_in_degrees_per_second: Option<u16>) {
+        self.slew_rate_in_degrees_per_second = slew_rate_in_degrees_per_second;
+        if slew_rate_in_degrees_per_second.is_none() {
+            self.set_pulse_width(self.target_pulse_width);
+        }
+    }
+
+    pub fn slew_rate(&self) -> Option<u16> {
+        self.slew_rate_in_degrees_per_second
+    }
+
+    /// The current angle of the servo (positive to the left), based on the pulse width currently sent to it,
+    /// i.e. this follows the slewing.
+    pub fn current_angle_in_degrees(&self) -> f32 {
+        let calibration = &self.calibration;
+        let centre = calibration.trimmed_centre().unwrap_or(calibration.centre);
+        (centre as f32 - self.current_pulse_width as f32)
+            / SERVO_PULSE_WIDTH_PER_DEGREE_IN_US as f32
+    }
+
+    /// The direction the steering has been set to (note that it might still be turning towards it).
+    pub fn current_direction(&self) -> Direction {
+        self.current_direction
+    }
+
+    fn pulse_width_for_direction(&self, direction: Direction) -> Result<u16, Error> {
+        match direction {
+            Centre => Ok(self.calibration.centre_pulse_width()),
+            Left(percentage) | Right(percentage) if percentage > 100 => Err(InvalidPercentage),
+            Left(percentage) => Ok(self.calibration.left_pulse_width(percentage)),
+            Right(percentage) => Ok(self.calibration.right_pulse_width(percentage)),
+        }
+    }
+
+    fn set_pulse_width(&mut self, pulse_width_in_us: u16) {
+        let duty = self.duty_for_pulse_width(pulse_width_in_us);
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "setting servo pulse width to {}us (duty {})",
+            pulse_width_in_us,
+            duty
+        );
+        self.pwm.set_duty(duty);
+        self.current_pulse_width = pulse_width_in_us;
+    }
+
+    /// The calibration currently in use.
+    pub fn calibration(&self) -> SteeringCalibration {
+        self.calibration
+    }
+
+    /// Replace the calibration. The current direction is re-applied (without slewing) with the new calibration
+    /// so that the effect is immediately visible.
+    pub fn set_calibration(&mut self, calibration: SteeringCalibration) -> Result<(), Error> {
+        if !calibration.is_valid_for(self.pwm_frequency) {
+            return Err(Error::InvalidCalibration);
+        }
+        #[cfg(feature = "defmt")]
+        defmt::debug!("new steering calibration: {}", calibration);
+        self.calibration = calibration;
+        self.publish_centre_duty();
+        self.steer_immediately(self.current_direction)
+    }
+
+    /// Update [`CENTRE_DUTY`] to match the current calibration.
+    fn publish_centre_duty(&self) {
+        if let Ok(centre) = self.pulse_width_for_direction(Centre) {
+            CENTRE_DUTY.store(self.duty_for_pulse_width(centre), Ordering::Relaxed);
+        }
+    }
+
+    /// Convert the pulse width to the PWM duty, based on the maximum duty and the frequency of the PWM.
+    fn duty_for_pulse_width(&self, pulse_width_in_us: u16) -> u16 {
+        duty_for_pulse_width(
+            pulse_width_in_us,
+            self.pwm.get_max_duty(),
+            self.pwm_frequency,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::RateExtU32;
+
+    #[test]
+    fn duty_for_pulse_width_scales_with_max_duty_and_frequency() {
+        // (max. duty, frequency, pulse width, expected duty)
+        let cases = [
+            // 50Hz = 20ms period
+            (20_000, 50, 1_500, 1_500),
+            (20_000, 50, 1_000, 1_000),
+            (65_535, 50, 1_500, 4_915),
+            (65_535, 50, 2_000, 6_554),
+            (1_000, 50, 1_504, 75),
+            // 100Hz = 10ms period
+            (10_000, 100, 1_500, 1_500),
+            (65_535, 100, 1_260, 8_257),
+            (4_095, 100, 1_748, 716),
+            // 300Hz = 3.33ms period
+            (53_333, 300, 1_500, 24_000),
+            (u16::MAX, 300, 2_000, 39_321),
+        ];
+        for (max_duty, frequency, pulse_width, expected_duty) in cases {
+            assert_eq!(
+                duty_for_pulse_width(pulse_width, max_duty, frequency.Hz()),
+                expected_duty,
+                "max. duty {max_duty}, {frequency}Hz, {pulse_width}µs"
+            );
+        }
+    }
+
+    #[test]
+    fn duty_for_pulse_width_is_limited_to_the_max_duty() {
+        assert_eq!(duty_for_pulse_width(2_000, 1_000, 1_000.Hz()), 1_000);
+        assert_eq!(
+            duty_for_pulse_width(u16::MAX, u16::MAX, 1_000.Hz()),
+            u16::MAX
+        );
+    }
+
+    #[test]
+    fn calibration_must_fit_into_the_pwm_period() {
+        let calibration = SteeringCalibration::DEFAULT;
+        assert!(calibration.is_valid_for(50.Hz()));
+        assert!(calibration.is_valid_for(300.Hz()));
+        // 2ms period, the longest safe pulse doesn't fit anymore
+        assert!(!calibration.is_valid_for(500.Hz()));
+    }
+
+    #[test]
+    fn calibration_outside_of_the_safe_range_is_invalid() {
+        let too_far_left = SteeringCalibration {
+            max_left: 505,
+            ..SteeringCalibration::DEFAULT
+        };
+        assert!(!too_far_left.is_within_safe_range());
+        let trimmed_too_far_right = SteeringCalibration {
+            trim: 300,
+            ..SteeringCalibration::DEFAULT
+        };
+        assert!(!trimmed_too_far_right.is_within_safe_range());
+        let invalid_expo = SteeringCalibration {
+            expo: 101,
+            ..SteeringCalibration::DEFAULT
+        };
+        assert!(!invalid_expo.is_within_safe_range());
+    }
+
+    #[test]
+    fn linear_steering_reaches_the_asymmetric_end_points() {
+        let calibration = SteeringCalibration {
+            centre: 1_500,
+            trim: 0,
+            max_left: 200,
+            max_right: 300,
+            expo: 0,
+        };
+        assert_eq!(calibration.centre_pulse_width(), 1_500);
+        assert_eq!(calibration.left_pulse_width(0), 1_500);
+        assert_eq!(calibration.left_pulse_width(50), 1_400);
+        assert_eq!(calibration.left_pulse_width(100), 1_300);
+        assert_eq!(calibration.right_pulse_width(50), 1_650);
+        assert_eq!(calibration.right_pulse_width(100), 1_800);
+    }
+
+    #[test]
+    fn end_points_are_relative_to_the_trimmed_centre() {
+        let calibration = SteeringCalibration {
+            centre: 1_500,
+            trim: -20,
+            max_left: 200,
+            max_right: 200,
+            expo: 0,
+        };
+        assert_eq!(calibration.centre_pulse_width(), 1_480);
+        assert_eq!(calibration.left_pulse_width(100), 1_280);
+        assert_eq!(calibration.right_pulse_width(100), 1_680);
+    }
+
+    #[test]
+    fn expo_gives_finer_steering_around_the_centre() {
+        let calibration = SteeringCalibration {
+            centre: 1_500,
+            trim: 0,
+            max_left: 200,
+            max_right: 200,
+            expo: 100,
+        };
+        // fully cubic: 50% => 12.5% of the end point
+        assert_eq!(calibration.right_pulse_width(50), 1_525);
+        assert_eq!(calibration.left_pulse_width(10), 1_500);
+        // the end points are still reached exactly
+        assert_eq!(calibration.right_pulse_width(100), 1_700);
+        assert_eq!(calibration.left_pulse_width(100), 1_300);
+
+        let half_expo = SteeringCalibration {
+            expo: 50,
+            ..calibration
+        };
+        // 0.5 * 0.5 + 0.5 * 0.125 = 31.25%
+        assert_eq!(half_expo.right_pulse_width(50), 1_563);
+        assert_eq!(half_expo.right_pulse_width(100), 1_700);
+    }
+
+    #[test]
+    fn pulse_widths_never_leave_the_safe_range() {
+        let unsafe_calibration = SteeringCalibration {
+            centre: 1_900,
+            trim: 0,
+            max_left: 1_000,
+            max_right: 400,
+            expo: 0,
+        };
+        assert_eq!(
+            unsafe_calibration.right_pulse_width(100),
+            SERVO_MAX_SAFE_PULSE_WIDTH_IN_US
+        );
+        assert_eq!(
+            unsafe_calibration.left_pulse_width(100),
+            SERVO_MIN_SAFE_PULSE_WIDTH_IN_US
+        );
+    }
+
+    #[test]
+    fn adjusting_the_expo_is_limited_to_100_percent() {
+        let calibration = SteeringCalibration::DEFAULT.adjusted(CalibrationParameter::Expo, 150);
+        assert_eq!(calibration.expo, 100);
+        let calibration = calibration.adjusted(CalibrationParameter::Expo, -150);
+        assert_eq!(calibration.expo, 0);
+    }
+
+    /// A PWM pin which only records the duty.
+    struct FakePwm {
+        max_duty: u16,
+        duty: u16,
+        enabled: bool,
+    }
+
+    impl FakePwm {
+        fn new(max_duty: u16) -> Self {
+            FakePwm {
+                max_duty,
+                duty: 0,
+                enabled: false,
+            }
+        }
+    }
+
+    impl PwmPin for FakePwm {
+        type Duty = u16;
+
+        fn disable(&mut self) {
+            self.enabled = false;
+        }
+
+        fn enable(&mut self) {
+            self.enabled = true;
+        }
+
+        fn get_duty(&self) -> u16 {
+            self.duty
+        }
+
+        fn get_max_duty(&self) -> u16 {
+            self.max_duty
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            self.duty = duty;
+        }
+    }
+
+    /// The PWM configurations (max. duty, frequency) the driver is tested with: the one used on the car
+    /// (TIM3 at 50Hz) and a faster timer with a larger resolution.
+    fn pwm_configurations() -> [(u16, HertzU32); 2] {
+        [(20_000, 50.Hz()), (53_333, 300.Hz())]
+    }
+
+    /// [`CENTRE_DUTY`] is global, thus the tests creating a [`Steering`] must not run in parallel.
+    static STEERING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn lock() -> std::sync::MutexGuard<'static, ()> {
+        STEERING.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn at(ms: u32) -> TimerInstantU32<1_000_000> {
+        TimerInstantU32::from_ticks(ms * 1_000)
+    }
+
+    #[test]
+    fn new_steering_is_centred_and_publishes_the_centre_duty() {
+        let _lock = lock();
+        for ((max_duty, frequency), expected_duty) in
+            pwm_configurations().into_iter().zip([1_504, 24_064])
+        {
+            let steering = Steering::new(
+                FakePwm::new(max_duty),
+                frequency,
+                SteeringCalibration::DEFAULT,
+                None,
+            );
+            assert!(steering.pwm.enabled);
+            assert_eq!(steering.pwm.get_duty(), expected_duty);
+            assert_eq!(centre_duty(), Some(expected_duty));
+            assert_eq!(steering.current_direction(), Centre);
+        }
+    }
+
+    #[test]
+    fn steering_without_slewing_sets_the_duty_immediately() {
+        let _lock = lock();
+        for ((max_duty, frequency), expected_duty) in
+            pwm_configurations().into_iter().zip([1_260, 20_160])
+        {
+            let mut steering = Steering::new(
+                FakePwm::new(max_duty),
+                frequency,
+                SteeringCalibration::DEFAULT,
+                None,
+            );
+            steering.steer(Left(100)).unwrap();
+            assert_eq!(steering.pwm.get_duty(), expected_duty);
+            assert_eq!(steering.current_angle_in_degrees(), 244.0 / 11.0);
+            assert_eq!(steering.steer(Right(101)), Err(InvalidPercentage));
+            assert_eq!(steering.current_direction(), Left(100));
+            assert_eq!(steering.pwm.get_duty(), expected_duty);
+        }
+    }
+
+    #[test]
+    fn slewing_turns_with_the_slew_rate() {
+        let _lock = lock();
+        for (max_duty, frequency) in pwm_configurations() {
+            let mut steering = Steering::new(
+                FakePwm::new(max_duty),
+                frequency,
+                SteeringCalibration::DEFAULT,
+                Some(DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND),
+            );
+            let centre_duty = steering.pwm.get_duty();
+            steering.update(at(0));
+            steering.steer(Left(100)).unwrap();
+            assert_eq!(steering.pwm.get_duty(), centre_duty);
+
+            // 300°/s * 11µs/° => 66µs per 20ms
+            let pulse_widths: Vec<u16> = (1..=5)
+                .map(|i| {
+                    steering.update(at(i * 20));
+                    steering.current_pulse_width
+                })
+                .collect();
+            assert_eq!(pulse_widths, [1_438, 1_372, 1_306, 1_260, 1_260]);
+            assert_eq!(
+                steering.pwm.get_duty(),
+                duty_for_pulse_width(1_260, max_duty, frequency)
+            );
+
+            // emergencies don't wait for the slewing
+            steering.steer_immediately(Centre).unwrap();
+            assert_eq!(steering.pwm.get_duty(), centre_duty);
+        }
+    }
+
+    #[test]
+    fn slewing_accumulates_short_intervals() {
+        let _lock = lock();
+        let mut steering = Steering::new(
+            FakePwm::new(20_000),
+            50.Hz(),
+            SteeringCalibration::DEFAULT,
+            Some(10),
+        );
+        steering.update(at(0));
+        steering.steer(Right(100)).unwrap();
+        // 10°/s * 11µs/° => 1µs per ~9ms
+        steering.update(at(5));
+        assert_eq!(steering.current_pulse_width, 1_504);
+        steering.update(at(10));
+        assert_eq!(steering.current_pulse_width, 1_505);
+    }
+
+    #[test]
+    fn slewing_after_a_long_pause_does_a_full_step() {
+        let _lock = lock();
+        let mut steering = Steering::new(
+            FakePwm::new(20_000),
+            50.Hz(),
+            SteeringCalibration::DEFAULT,
+            Some(DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND),
+        );
+        steering.update(at(0));
+        steering.steer(Left(100)).unwrap();
+        // 3300µs/s * 19.86s = 65_538µs, which would wrap to a step of 2µs
+        steering.update(at(19_860));
+        assert_eq!(steering.current_pulse_width, 1_260);
+    }
+
+    #[test]
+    fn new_calibration_is_applied_immediately() {
+        let _lock = lock();
+        for (max_duty, frequency) in pwm_configurations() {
+            let mut steering = Steering::new(
+                FakePwm::new(max_duty),
+                frequency,
+                SteeringCalibration::DEFAULT,
+                Some(DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND),
+            );
+            steering.steer(Right(50)).unwrap();
+            let calibration = SteeringCalibration {
+                trim: 20,
+                ..SteeringCalibration::DEFAULT
+            };
+            steering.set_calibration(calibration).unwrap();
+            assert_eq!(steering.calibration(), calibration);
+            assert_eq!(
+                steering.current_pulse_width,
+                calibration.right_pulse_width(50)
+            );
+            assert_eq!(
+                steering.pwm.get_duty(),
+                duty_for_pulse_width(calibration.right_pulse_width(50), max_duty, frequency)
+            );
+            assert_eq!(
+                centre_duty(),
+                Some(duty_for_pulse_width(1_524, max_duty, frequency))
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_calibration_is_rejected() {
+        let _lock = lock();
+        let mut steering = Steering::new(
+            FakePwm::new(20_000),
+            50.Hz(),
+            SteeringCalibration::DEFAULT,
+            None,
+        );
+        let unsafe_calibration = SteeringCalibration {
+            max_right: 600,
+            ..SteeringCalibration::DEFAULT
+        };
+        assert_eq!(
+            steering.set_calibration(unsafe_calibration),
+            Err(Error::InvalidCalibration)
+        );
+        assert_eq!(steering.calibration(), SteeringCalibration::DEFAULT);
+        assert_eq!(centre_duty(), Some(1_504));
+
+        // an invalid calibration (e.g. from the flash) is replaced by the default
+        let steering = Steering::new(FakePwm::new(20_000), 50.Hz(), unsafe_calibration, None);
+        assert_eq!(steering.calibration(), SteeringCalibration::DEFAULT);
+    }
+}
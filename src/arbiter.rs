@@ -0,0 +1,153 @@
+//! Decides which of the sources wanting to control the car actually gets to control it.
+//!
+//! The safety layer, the remote control (manual) and the autonomous modes submit the [`Command`] they want to be
+//! executed to the car (see `Car::submit_command`) instead of driving the motor & the steering directly. The command
+//! of the source with the highest priority wins: safety > manual > autonomous. If there is no command the car is
+//! idle, i.e. it stops.
+//!
+//! A command can have a timeout after which it is dropped, e.g. so that the car stops if an autonomous mode doesn't
+//! refresh its command anymore. The command of a source which has been overridden is dropped as well: the source
+//! has to submit a new command once the source with the higher priority has released the control again. This way
+//! the car never starts driving again on its own after e.g. the user took over.
+//!
+//! Note that the collision avoidance isn't a source: it restricts every command (the car can't drive forward while
+//! `Car::current_state` isn't `Normal`), independent of which source is in control.
+
+use crate::steering::Direction;
+use defmt::Format;
+
+/// How long a command of an autonomous mode is valid. The autonomous modes refresh their command with every update
+/// (see `AUTONOMOUS_MODE_INTERVAL_IN_MS`), thus the car stops if a few updates are missed.
+pub const AUTONOMOUS_COMMAND_TIMEOUT_IN_MS: u32 = 100;
+
+/// How long a command of the remote control is valid without receiving anything from the app. The app only sends
+/// changes, thus every message refreshes the command (see [`Arbiter::refresh`]). This stops the car if the connection
+/// is lost or the receiving side (DMA / UART) stopped working, as the latter can't be supervised otherwise.
+pub const MANUAL_COMMAND_TIMEOUT_IN_MS: u32 = 10_000;
+
+/// A source of commands, in the order of its priority (the last one has the highest priority).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone, Format)]
+pub enum CommandSource {
+    /// An autonomous mode (manoeuvre sequence, wall following, follow me, line following).
+    Autonomous,
+    /// The user with the remote control.
+    Manual,
+    /// The car itself, e.g. to stop while the battery is critical.
+    Safety,
+}
+
+impl CommandSource {
+    /// All sources, starting with the highest priority.
+    const BY_PRIORITY: [CommandSource; 3] = [
+        CommandSource::Safety,
+        CommandSource::Manual,
+        CommandSource::Autonomous,
+    ];
+}
+
+/// How the car should move.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum Motion {
+    /// Brake.
+    Stop,
+    /// Drive with the given speed of the motor (in percentage, negative to drive backwards).
+    Duty(i8),
+    /// Drive at the given speed (in mm/s, negative to drive backwards), see `Car::has_speed_control`.
+    Speed(i16),
+}
+
+/// What a source wants the car to do.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct Command {
+    pub motion: Motion,
+    pub steering: Direction,
+}
+
+impl Command {
+    /// Stop with the steering centred, this is also what the car does while it is idle.
+    pub const STOP: Command = Command {
+        motion: Motion::Stop,
+        steering: Direction::Centre,
+    };
+}
+
+#[derive(Copy, Clone)]
+struct Submission {
+    command: Command,
+    /// When the command is dropped, `None` if it is valid until it is replaced or released.
+    expiry: Option<fugit::TimerInstantU32<1_000_000>>,
+}
+
+/// Keeps the latest command of each source and decides which one is executed.
+#[derive(Default)]
+pub struct Arbiter {
+    /// The latest command of each source, indexed by the [`CommandSource`].
+    submissions: [Option<Submission>; 3],
+}
+
+impl Arbiter {
+    /// Replace the command of the source.
+    pub fn submit(
+        &mut self,
+        source: CommandSource,
+        command: Command,
+        expiry: Option<fugit::TimerInstantU32<1_000_000>>,
+    ) {
+        self.submissions[source as usize] = Some(Submission { command, expiry });
+    }
+
+    /// Extend the validity of the command of the source (if it has a timeout), e.g. because the source is still alive.
+    pub fn refresh(&mut self, source: CommandSource, expiry: fugit::TimerInstantU32<1_000_000>) {
+        if let Some(Submission {
+            expiry: Some(previous_expiry),
+            ..
+        }) = &mut self.submissions[source as usize]
+        {
+            *previous_expiry = expiry;
+        }
+    }
+
+    /// Drop the command of the source, e.g. because an autonomous mode has been stopped.
+    pub fn release(&mut self, source: CommandSource) {
+        self.submissions[source as usize] = None;
+    }
+
+    /// Drop all commands, the car is idle afterwards.
+    pub fn release_all(&mut self) {
+        self.submissions = Default::default();
+    }
+
+    /// Drop the commands which have timed out.
+    pub fn expire(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        for source in CommandSource::BY_PRIORITY {
+            let submission = &mut self.submissions[source as usize];
+            if matches!(submission, Some(Submission { expiry: Some(expiry), .. }) if *expiry <= now)
+            {
+                defmt::debug!("the command of {} timed out", source);
+                *submission = None;
+            }
+        }
+    }
+
+    /// The source which is in control and its command, `None` if the car is idle.
+    /// The commands of the sources with a lower priority are dropped.
+    pub fn arbitrate(&mut self) -> Option<(CommandSource, Command)> {
+        let mut winner = None;
+        for source in CommandSource::BY_PRIORITY {
+            let submission = &mut self.submissions[source as usize];
+            match (submission.as_ref(), winner) {
+                (None, _) => {}
+                (Some(_), Some((winning_source, _))) => {
+                    defmt::debug!(
+                        "the command of {} is overridden by {}",
+                        source,
+                        winning_source
+                    );
+                    *submission = None;
+                }
+                (Some(submission), None) => winner = Some((source, submission.command)),
+            }
+        }
+        winner
+    }
+}
@@ -1,12 +1,13 @@
 //! Represents the bluetooth module and abstracts away some of the technical details for other consumers.
 
+use crate::error::{Error, TransportError};
 use stm32f4xx_hal::{
     dma::{config::DmaConfig, PeripheralToMemory, Stream2, StreamsTuple, Transfer},
     gpio::{PA10, PB6},
     pac::{DMA2, USART1},
     prelude::*,
     rcc::Clocks,
-    serial::{self, Rx, Serial},
+    serial::{self, Rx, Serial, Tx},
 };
 
 pub type USART1RxBufferInt =
@@ -15,11 +16,16 @@ pub type USART1RxBuffer = Option<USART1RxBufferInt>;
 pub type USART1RxTransfer =
     Transfer<Stream2<DMA2>, 4_u8, Rx<USART1>, PeripheralToMemory, USART1RxBufferInt>;
 
+/// The baud rate of the UART connection to the bluetooth module (its default). Each character takes 10 bits (8N1).
+pub const BAUD_RATE: u32 = 9600;
+
 /// Represents the [Adafruit Bluefruit LE UART Friend](https://learn.adafruit.com/introducing-the-adafruit-bluefruit-le-uart-friend)
 /// connected via USART and with DMA enabled for USART.
 pub struct BluefruitLEUARTFriend {
     pub rx_transfer: USART1RxTransfer,
     pub rx_buffer: USART1RxBuffer,
+    /// Used to send data to the connected device (e.g. the app). Can be taken out to be used independently of the receiving side.
+    pub tx: Option<Tx<USART1>>,
 }
 
 impl BluefruitLEUARTFriend {
@@ -32,26 +38,26 @@ impl BluefruitLEUARTFriend {
         tx_pin: PB6,
         rx_pin: PA10,
         clocks: &Clocks,
-    ) -> BluefruitLEUARTFriend {
+    ) -> Result<BluefruitLEUARTFriend, Error> {
         // set up USART1
         let usart1 = Serial::new(
             pac_usart1,
             (tx_pin.into_alternate(), rx_pin.into_alternate()),
             serial::Config::default()
-                .baudrate(9600.bps())
+                .baudrate(BAUD_RATE.bps())
                 .dma(serial::config::DmaConfig::Rx),
             clocks,
         )
-        .expect("USART1 can be set up");
+        .map_err(|_| Error::Transport(TransportError::Setup))?;
 
-        let (_usart1_tx, mut usart1_rx) = usart1.split();
+        let (usart1_tx, mut usart1_rx) = usart1.split();
         usart1_rx.listen_idle();
 
         // set up DMA for USART1 RX
         let streams = StreamsTuple::new(pac_dma2);
         let rx_stream = streams.2;
         let rx_buffer = cortex_m::singleton!(: [u8; adafruit_bluefruit_protocol::MAX_CONTROLLER_MESSAGE_LENGTH] = [0; adafruit_bluefruit_protocol::MAX_CONTROLLER_MESSAGE_LENGTH])
-            .expect("RX buffer singleton created");
+            .ok_or(Error::Transport(TransportError::Setup))?;
         let mut rx_transfer = Transfer::init_peripheral_to_memory(
             rx_stream,
             usart1_rx,
@@ -65,11 +71,12 @@ impl BluefruitLEUARTFriend {
         );
         rx_transfer.start(|_rx| {});
         let rx_buffer = cortex_m::singleton!(: [u8; adafruit_bluefruit_protocol::MAX_CONTROLLER_MESSAGE_LENGTH] = [0; adafruit_bluefruit_protocol::MAX_CONTROLLER_MESSAGE_LENGTH])
-            .expect("RX buffer singleton created");
+            .ok_or(Error::Transport(TransportError::Setup))?;
 
-        BluefruitLEUARTFriend {
+        Ok(BluefruitLEUARTFriend {
             rx_transfer,
             rx_buffer: Some(rx_buffer),
-        }
+            tx: Some(usart1_tx),
+        })
     }
 }
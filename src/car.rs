@@ -1,16 +1,30 @@
 //! The functionality within this module represents the robotcar. It abstracts away the technical
 //! details from its consumers.
 
-use crate::app::Display;
+use crate::app::{Display, Imu, LineSensorT};
+use crate::arbiter::{Arbiter, Command, CommandSource, Motion};
+use crate::battery::{BatteryState, BatteryStatus};
 use crate::car::CarState::{ForwardDistanceInvalid, Normal};
+use crate::collision::{self, assess_collision_risk, CollisionRisk, MIN_TIME_TO_COLLISION_IN_MS};
+use crate::distance_filter::{ClosingSpeedEstimator, FrontDistanceFilter};
+use crate::drive_profile::{DriveLimits, DriveProfile};
+use crate::error::{Error, Reaction};
+use crate::event_log::{self, EmergencyStopReason, Event};
+use crate::line_sensor::LineSensor;
+use crate::odometry::Odometry;
+use crate::pid::PidController;
+use crate::pose::{estimated_speed_in_mm_per_s, Pose, PoseEstimator};
+use crate::reset_cause::{ResetCause, ResetInfo};
+use crate::speed_control::{MOTOR_DEAD_BAND_IN_PERCENT, SPEED_CONTROLLER_GAINS};
 use crate::steering::Direction::{Centre, Left, Right};
-use crate::steering::Steering;
-use crate::tof_sensor::DistanceSensor;
+use crate::steering::{CalibrationParameter, Direction, Steering, SteeringCalibration};
+use crate::tof_sensor::{DistanceSensor, RangingConfig, RangingPreset, RangingProfile};
 use core::fmt::Debug;
 use core::marker::PhantomData;
 use defmt::Format;
+use display_interface::DisplayError;
 use embedded_graphics::mono_font::ascii::FONT_6X12;
-use embedded_graphics::mono_font::MonoTextStyleBuilder;
+use embedded_graphics::mono_font::{MonoTextStyle, MonoTextStyleBuilder};
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics::prelude::Point;
 use embedded_graphics::text::Text;
@@ -18,7 +32,8 @@ use embedded_graphics::Drawable;
 use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::PwmPin;
 use fugit::ExtU32;
-use tb6612fng::{DriveError, Motor};
+use ssd1306::mode::DisplayConfig;
+use tb6612fng::Motor;
 
 /// The current state of the car, based on its knowledge of its surroundings.
 #[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
@@ -29,20 +44,79 @@ pub enum CarState {
     ForwardDistanceInvalid,
 }
 
-/// Errors which can potentially happen while interacting with the car.
+/// The health of the front distance sensor.
 #[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
-pub enum Error {
-    /// An attempt was made to drive forward but this is currently prohibited (collision avoidance).
-    NotAllowedToDriveForward,
-    /// Something went wrong in the underlying motor control library. See the attached error for further details.
-    DriveError(DriveError),
+pub struct DistanceSensorStatus {
+    /// The number of failed measurements in a row.
+    pub consecutive_errors: u8,
+    /// The number of times the sensor had to be reset since the boot.
+    pub resets: u32,
+}
+
+/// A snapshot of the current status of the car, e.g. to be sent as telemetry.
+#[derive(PartialEq, Debug, Copy, Clone, Format)]
+pub struct CarStatus {
+    pub state: CarState,
+    /// The current speed in percentage, see [`Car::current_speed`].
+    pub speed: i8,
+    /// See [`Car::drive_profile`].
+    pub drive_profile: DriveProfile,
+    /// The source which is in control of the car, `None` while the car is idle (see `arbiter`).
+    pub control: Option<CommandSource>,
+    pub front_distance_in_mm: Option<u16>,
+    /// How fast the obstacle in front is getting closer (negative if it's getting further away).
+    pub closing_speed_in_mm_per_s: Option<i32>,
+    /// See [`Car::time_to_collision_in_ms`].
+    pub time_to_collision_in_ms: Option<u32>,
+    /// The health of the front distance sensor, `None` if there is none.
+    pub front_distance_sensor: Option<DistanceSensorStatus>,
+    /// The status of the battery, `None` if it's not being monitored.
+    pub battery: Option<BatteryStatus>,
+    /// The number of times the I2C bus had to be recovered since the boot, see [`Car::handle_i2c_bus_recoveries`].
+    pub i2c_bus_recoveries: u32,
+    /// Why the microcontroller has last been reset.
+    pub reset: ResetInfo,
+    /// Whether the car only runs in a degraded mode, see [`Car::is_degraded_mode`].
+    pub degraded_mode: bool,
+    /// The distance travelled since the boot, `None` if the car has no wheel encoder.
+    pub distance_travelled_in_mm: Option<i32>,
+    /// The measured speed, `None` if the car has no wheel encoder.
+    pub measured_speed_in_mm_per_s: Option<i32>,
+    /// See [`Car::target_speed_in_mm_per_s`].
+    pub target_speed_in_mm_per_s: Option<i16>,
+    /// See [`Car::pose`].
+    pub pose: Pose,
 }
 
 /// The maximum amount of time for which it's acceptable to not get a TOF signal. If this timeout is exceeded the car will do an emergency brake.
 pub const MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS: u32 = 200;
 
-/// The minimum front distance. If the distance is less than this the car will do an emergency brake.
-const MIN_FRONT_DISTANCE_IN_MM: u16 = 500;
+/// After this many failed measurements in a row the distance sensor will be reset.
+const MAX_CONSECUTIVE_DISTANCE_SENSOR_ERRORS: u8 = 3;
+
+/// If the distance sensor doesn't deliver any measurement for this long it will be reset.
+/// This is longer than [`MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS`] as the reset itself takes a while.
+const DISTANCE_SENSOR_RESET_TIMEOUT_IN_MS: u32 = 1_000;
+
+/// Above this speed (in percentage, in either direction) the high speed ranging profile of the distance sensor is used.
+const HIGH_SPEED_THRESHOLD: u8 = 50;
+
+/// The ranging profile of the side distance sensor: the wall is close, thus fast measurements are more important
+/// than the range.
+pub const SIDE_DISTANCE_RANGING_PROFILE: RangingProfile = RangingProfile {
+    preset: RangingPreset::ShortFast,
+    narrow_field_of_view: false,
+};
+
+/// The maximum speed (in percentage) while the collision avoidance is overridden, see [`Car::set_collision_override`].
+pub const COLLISION_OVERRIDE_MAX_SPEED: u8 = 25;
+const _: () = assert!(COLLISION_OVERRIDE_MAX_SPEED > MOTOR_DEAD_BAND_IN_PERCENT);
+
+/// The maximum speed (in percentage) while the battery is low. Higher speeds will be reduced to this.
+const LOW_BATTERY_MAX_SPEED: u8 = 50;
+
+/// The maximum speed (in percentage) in the degraded mode. Higher speeds will be reduced to this.
+const DEGRADED_MODE_MAX_SPEED: u8 = 30;
 
 /// Represents the robot car.
 pub struct Car<ServoPwm, MAIN1, MAIN2, MAPWM, DS, DE, OLED>
@@ -54,13 +128,57 @@ where
     steering: Steering<ServoPwm>,
     motor: Motor<MAIN1, MAIN2, MAPWM>,
     front_distance_sensor: Option<DS>,
+    /// The distance sensor looking to the right, `None` if there is none (see [`Car::enable_side_distance_sensor`]).
+    side_distance_sensor: Option<DS>,
     display: Option<Display>,
+    /// The IMU, `None` if there is none (see [`Car::enable_imu`]).
+    imu: Option<Imu>,
+    /// The reflectance sensors, `None` if there are none (see [`Car::enable_line_sensor`]).
+    line_sensor: Option<LineSensorT>,
     led_status_obstacle: OLED,
 
+    /// The display has been disabled due to an error, see [`Car::refresh_display`].
+    display_faulted: bool,
+    /// The IMU has been disabled due to an error, it is re-initialised after the next recovery of the I2C bus.
+    imu_faulted: bool,
+    // config
+    ranging_config: RangingConfig,
+    drive_profile: DriveProfile,
+    /// Why the microcontroller has last been reset.
+    reset_info: ResetInfo,
+
     // data
     current_state: CarState,
+    /// The parameter selected for adjustment if the steering calibration is active.
+    steering_calibration: Option<CalibrationParameter>,
+    arbiter: Arbiter,
+    /// The source which is in control and its latest command, `None` while the car is idle.
+    active_command: Option<(CommandSource, Command)>,
+    /// The ranging profile currently used by the distance sensor.
+    ranging_profile: RangingProfile,
+    front_distance_filter: FrontDistanceFilter,
+    closing_speed_estimator: ClosingSpeedEstimator,
+    /// Whether the car is outside of the geofence of the drive profile.
+    outside_geofence: bool,
+    /// See [`Car::set_collision_override`].
+    collision_override: bool,
+    /// The latest filtered front distance.
     latest_front_distance_in_mm: Option<u16>,
     last_front_distance_update: Option<fugit::TimerInstantU32<1_000_000>>,
+    consecutive_distance_sensor_errors: u8,
+    distance_sensor_resets: u32,
+    /// When the distance sensor has last been reset (or first been validated if it has never been reset).
+    last_distance_sensor_reset: Option<fugit::TimerInstantU32<1_000_000>>,
+    battery: Option<BatteryStatus>,
+    /// The number of I2C bus recoveries after which the devices on the bus have last been re-initialised.
+    i2c_bus_recoveries: u32,
+    /// The odometry based on the wheel encoder, `None` if the car has no wheel encoder.
+    odometry: Option<Odometry>,
+    speed_controller: PidController,
+    /// See [`Car::target_speed_in_mm_per_s`].
+    target_speed_in_mm_per_s: Option<i16>,
+    pose_estimator: PoseEstimator,
+    last_odometry_update: Option<fugit::TimerInstantU32<1_000_000>>,
     /// Needed to be able to specify the `DE` type parameter
     _distance_sensor_error: PhantomData<DE>,
 }
@@ -79,46 +197,235 @@ where
         steering: Steering<ServoPwm>,
         motor: Motor<MAIN1, MAIN2, MAPWM>,
         front_distance_sensor: Option<DS>,
+        ranging_config: RangingConfig,
         display: Option<Display>,
         led_status_obstacle: OLED,
+        reset_info: ResetInfo,
     ) -> Self {
-        Car {
+        let mut car = Car {
             steering,
             motor,
             display,
+            imu: None,
+            line_sensor: None,
             led_status_obstacle,
+            display_faulted: false,
+            imu_faulted: false,
+            ranging_config,
+            drive_profile: DriveProfile::default(),
+            outside_geofence: false,
+            collision_override: false,
+            ranging_profile: ranging_config.profile(false),
+            reset_info,
             current_state: Normal,
+            steering_calibration: None,
+            arbiter: Arbiter::default(),
+            active_command: None,
             front_distance_sensor,
+            side_distance_sensor: None,
+            front_distance_filter: FrontDistanceFilter::default(),
+            closing_speed_estimator: ClosingSpeedEstimator::default(),
             latest_front_distance_in_mm: None,
             last_front_distance_update: None,
+            consecutive_distance_sensor_errors: 0,
+            distance_sensor_resets: 0,
+            last_distance_sensor_reset: None,
+            battery: None,
+            i2c_bus_recoveries: 0,
+            odometry: None,
+            speed_controller: PidController::new(SPEED_CONTROLLER_GAINS, 100),
+            target_speed_in_mm_per_s: None,
+            pose_estimator: PoseEstimator::default(),
+            last_odometry_update: None,
             _distance_sensor_error: PhantomData,
+        };
+        // show the reset cause right away
+        car.update_display();
+        car
+    }
+
+    /// Submit the command of the source, it is executed right away if the source is in control (see `arbiter`).
+    /// The command is dropped after `timeout_in_ms` unless it is replaced before, `None` keeps it until it is replaced
+    /// or released. Fails if a source with a higher priority is in control.
+    pub fn submit_command(
+        &mut self,
+        source: CommandSource,
+        command: Command,
+        timeout_in_ms: Option<u32>,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) -> Result<(), Error> {
+        if self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationActive);
+        }
+        self.arbiter.submit(
+            source,
+            command,
+            timeout_in_ms.map(|timeout| now + timeout.millis()),
+        );
+        let winner = self.arbiter.arbitrate();
+        if let Some((winning_source, _)) =
+            winner.filter(|(winning_source, _)| *winning_source != source)
+        {
+            return Err(Error::CommandOverridden(winning_source));
+        }
+        self.set_active_command(winner);
+        // the command is executed even if it didn't change, e.g. to drive forward again after an obstacle is gone.
+        self.execute(command)
+    }
+
+    /// Release the control of the source, e.g. because an autonomous mode has been stopped. The car stops if the
+    /// source has been in control.
+    pub fn release_command(&mut self, source: CommandSource) {
+        self.arbiter.release(source);
+        self.apply_arbitration();
+    }
+
+    /// Restart the timeout of the command of the source (if it has one, see [`Car::submit_command`]).
+    pub fn refresh_command(
+        &mut self,
+        source: CommandSource,
+        timeout_in_ms: u32,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        self.arbiter.refresh(source, now + timeout_in_ms.millis());
+    }
+
+    /// Needs to be called periodically to drop the commands which have timed out (see [`Car::submit_command`]).
+    pub fn expire_commands(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        self.arbiter.expire(now);
+        self.apply_arbitration();
+    }
+
+    /// Execute the command of the source which is in control (or stop if the car is idle) if it has changed.
+    fn apply_arbitration(&mut self) {
+        let winner = self.arbiter.arbitrate();
+        if winner == self.active_command {
+            return;
+        }
+        self.set_active_command(winner);
+        let command = winner.map_or(Command::STOP, |(_, command)| command);
+        if let Err(e) = self.execute(command) {
+            self.handle_error(e);
+        }
+    }
+
+    fn set_active_command(&mut self, active_command: Option<(CommandSource, Command)>) {
+        let source = active_command.map(|(source, _)| source);
+        if source != self.active_command.map(|(source, _)| source) {
+            defmt::info!("control changed to {}", source);
+            event_log::record(Event::ControlChanged(source));
+        }
+        self.active_command = active_command;
+    }
+
+    /// Let the safety layer stop the car, the commands of all other sources are dropped. If `hold` is set the car is
+    /// kept stopped until the control of [`CommandSource::Safety`] is released again, otherwise the car is idle
+    /// afterwards (i.e. it stays stopped until it gets a new command) unless it is already being kept stopped.
+    fn safety_stop(&mut self, hold: bool) {
+        let already_held = matches!(self.active_command, Some((CommandSource::Safety, _)));
+        self.arbiter
+            .submit(CommandSource::Safety, Command::STOP, None);
+        self.arbiter.arbitrate(); // drops the commands of all other sources
+        if !hold && !already_held {
+            self.arbiter.release(CommandSource::Safety);
+        }
+        let winner = self.arbiter.arbitrate();
+        self.set_active_command(winner);
+        self.execute(Command::STOP).ok(); // stopping can't fail
+    }
+
+    /// Execute the command in control again, so that changed limits (e.g. of the collision avoidance, the battery or
+    /// the geofence, see [`Car::execute`]) apply right away. If the command isn't allowed anymore the car stops, but
+    /// the command stays in control (thus e.g. driving backwards is still possible).
+    fn enforce_limits(&mut self) {
+        let command = self
+            .active_command
+            .map_or(Command::STOP, |(_, command)| command);
+        if let Err(e) = self.execute(command) {
+            defmt::warn!("{} isn't allowed anymore ({}), stopping", command, e);
+            self.halt();
+        }
+    }
+
+    /// Drive & steer as requested by the command. The steering isn't changed while it is being calibrated.
+    fn execute(&mut self, command: Command) -> Result<(), Error> {
+        if !self.is_steering_calibration_active() {
+            self.steering
+                .steer(self.limit_steering(command.steering))
+                .map_err(Error::Steering)?;
+        }
+        match command.motion {
+            Motion::Duty(speed) if speed > 0 => self.drive_forward(speed as u8),
+            Motion::Duty(speed) if speed < 0 => self.drive_backwards(speed.unsigned_abs()),
+            Motion::Speed(speed_in_mm_per_s) => self.drive_at_speed(speed_in_mm_per_s),
+            Motion::Stop | Motion::Duty(_) => {
+                self.halt();
+                Ok(())
+            }
         }
     }
 
-    pub fn steer_left(&mut self) {
+    fn steer_left(&mut self) {
         self.steering.steer(Left(100)).ok(); // we know that 100% is an acceptable value
     }
 
-    pub fn steer_center(&mut self) {
+    fn steer_center(&mut self) {
         self.steering.steer(Centre).ok(); // we know that 100% is an acceptable value
     }
 
-    pub fn steer_right(&mut self) {
+    fn steer_right(&mut self) {
         self.steering.steer(Right(100)).ok(); // we know that 100% is an acceptable value
     }
 
-    pub fn drive_forward(&mut self, speed: u8) -> Result<(), Error> {
-        if self.current_state != Normal {
+    /// Advance the steering towards the requested direction (the steering turns with a limited speed).
+    /// Needs to be called periodically.
+    pub fn update_steering(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        self.steering.update(now);
+    }
+
+    fn drive_forward(&mut self, speed: u8) -> Result<(), Error> {
+        self.stop_speed_control();
+        self.drive_forward_with_duty(speed)
+    }
+
+    fn drive_backwards(&mut self, speed: u8) -> Result<(), Error> {
+        self.stop_speed_control();
+        self.drive_backwards_with_duty(speed)
+    }
+
+    fn drive_forward_with_duty(&mut self, speed: u8) -> Result<(), Error> {
+        if self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationActive);
+        }
+        if self.current_state != Normal && !self.may_creep_forward() {
             return Err(Error::NotAllowedToDriveForward);
         }
+        if self.is_leaving_geofence(true) {
+            return Err(Error::OutsideGeofence);
+        }
+        let mut speed = self.limit_speed(speed, true)?;
+        if self.collision_override {
+            speed = speed.min(COLLISION_OVERRIDE_MAX_SPEED);
+        }
 
-        self.motor.drive_forward(speed).map_err(Error::DriveError)
+        self.motor.drive_forward(speed).map_err(Error::Drive)?;
+        self.update_ranging_profile();
+        Ok(())
     }
 
-    pub fn drive_backwards(&mut self, speed: u8) -> Result<(), Error> {
+    fn drive_backwards_with_duty(&mut self, speed: u8) -> Result<(), Error> {
         // no need to validate `self.current_state` here as we're still allowed to drive back even if
         // it's `ForwardDistanceInvalid` (we don't have a back sensor, so we presume that driving back is safe)
-        self.motor.drive_backwards(speed).map_err(Error::DriveError)
+        if self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationActive);
+        }
+        if self.is_leaving_geofence(false) {
+            return Err(Error::OutsideGeofence);
+        }
+        let speed = self.limit_speed(speed, false)?;
+        self.motor.drive_backwards(speed).map_err(Error::Drive)?;
+        self.update_ranging_profile();
+        Ok(())
     }
 
     /// Return the current speed of the motor (in percentage). Note that driving forward returns a positive number
@@ -127,20 +434,619 @@ where
         self.motor.current_speed()
     }
 
-    pub fn halt(&mut self) {
+    fn halt(&mut self) {
+        self.stop_speed_control();
         self.motor.brake();
+        self.update_ranging_profile();
+    }
+
+    /// Enable the speed control, this requires a wheel encoder which needs to be passed to [`Car::update_odometry`].
+    pub fn enable_speed_control(&mut self) {
+        self.odometry = Some(Odometry::default());
+    }
+
+    /// Use the IMU to improve the estimate of the heading, see [`Car::pose`]. The IMU must already be initialised.
+    pub fn enable_imu(&mut self, imu: Imu) {
+        self.imu = Some(imu);
+    }
+
+    /// Use the distance sensor looking to the right, e.g. to follow a wall. The sensor must already be initialised
+    /// with [`SIDE_DISTANCE_RANGING_PROFILE`].
+    pub fn enable_side_distance_sensor(&mut self, side_distance_sensor: DS) {
+        self.side_distance_sensor = Some(side_distance_sensor);
+    }
+
+    pub fn has_side_distance_sensor(&self) -> bool {
+        self.side_distance_sensor.is_some()
+    }
+
+    /// The new distance measured by the side distance sensor, `None` if there is none or no new measurement is
+    /// available since the last call. The sensor measures continuously, thus this can be called at any time, but a
+    /// measurement is only returned once (so that e.g. a controller doesn't see the same value twice).
+    pub fn measure_side_distance(&mut self) -> Result<Option<u16>, Error> {
+        let side_distance_sensor = match self.side_distance_sensor.as_mut() {
+            Some(side_distance_sensor) => side_distance_sensor,
+            None => return Ok(None),
+        };
+        match side_distance_sensor.is_data_ready() {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(e) => {
+                defmt::error!(
+                    "Failed to check the side TOF for new data: {}",
+                    defmt::Debug2Format(&e)
+                );
+                return Err(Error::DistanceSensor);
+            }
+        }
+        match side_distance_sensor.get_distance_in_mm() {
+            Ok(distance) => Ok(Some(distance)),
+            Err(e) => {
+                defmt::error!(
+                    "Failed to get distance from the side TOF: {}",
+                    defmt::Debug2Format(&e)
+                );
+                Err(Error::DistanceSensor)
+            }
+        }
+    }
+
+    /// Use the reflectance sensors, e.g. to follow a line. The sensors must already be initialised.
+    pub fn enable_line_sensor(&mut self, line_sensor: LineSensorT) {
+        self.line_sensor = Some(line_sensor);
+    }
+
+    /// The position of the line below the car (see `Reflectance::line_position`), `None` if the line isn't seen or
+    /// there is no line sensor.
+    pub fn read_line_position(&mut self) -> Result<Option<i8>, Error> {
+        let line_sensor = match self.line_sensor.as_mut() {
+            Some(line_sensor) => line_sensor,
+            None => return Ok(None),
+        };
+        match line_sensor.read() {
+            Ok(reflectance) => Ok(reflectance.line_position()),
+            Err(e) => {
+                defmt::error!(
+                    "Failed to read the line sensor: {}",
+                    defmt::Debug2Format(&e)
+                );
+                Err(Error::LineSensor)
+            }
+        }
+    }
+
+    /// Whether the car has a wheel encoder and can thus drive with a target speed, see [`Car::drive_at_speed`].
+    pub fn has_speed_control(&self) -> bool {
+        self.odometry.is_some()
+    }
+
+    /// The speed the speed controller is driving the car at (negative while driving backwards), `None` if the speed
+    /// is set directly (in percentage, see `Motion::Duty`).
+    pub fn target_speed_in_mm_per_s(&self) -> Option<i16> {
+        self.target_speed_in_mm_per_s
+    }
+
+    /// Drive at the given speed (negative to drive backwards), the duty of the motor is controlled so that the
+    /// speed is kept independent of the battery & load. Requires a wheel encoder (see [`Car::enable_speed_control`]).
+    fn drive_at_speed(&mut self, speed_in_mm_per_s: i16) -> Result<(), Error> {
+        if !self.has_speed_control() {
+            return Err(Error::SpeedControlNotAvailable);
+        }
+        if self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationActive);
+        }
+        if speed_in_mm_per_s > 0 && self.current_state != Normal {
+            return Err(Error::NotAllowedToDriveForward);
+        }
+        self.limit_speed(0, true)?; // fails if the battery is critical
+        if speed_in_mm_per_s == 0 {
+            self.halt();
+            return Ok(());
+        }
+
+        let previous_target = self.target_speed_in_mm_per_s.unwrap_or(0);
+        if previous_target.signum() != speed_in_mm_per_s.signum() {
+            self.speed_controller.reset();
+        }
+        self.target_speed_in_mm_per_s = Some(speed_in_mm_per_s);
+        Ok(())
+    }
+
+    /// Stop controlling the speed, the duty of the motor is kept as it is.
+    fn stop_speed_control(&mut self) {
+        self.target_speed_in_mm_per_s = None;
+        self.speed_controller.reset();
+    }
+
+    /// Needs to be called periodically with the current value of the pulse counter of the wheel encoder (`None` if
+    /// there is none). Updates the odometry & the pose and, if a target speed is set, adjusts the duty of the motor
+    /// to reach it.
+    pub fn update_odometry(
+        &mut self,
+        encoder_count: Option<u16>,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        let motor_speed = self.current_speed();
+        let distance_in_um = match (self.odometry.as_mut(), encoder_count) {
+            (Some(odometry), Some(encoder_count)) => {
+                Some(odometry.update(encoder_count, motor_speed, now))
+            }
+            _ => None,
+        };
+        let dt_in_ms = match self
+            .last_odometry_update
+            .replace(now)
+            .and_then(|last_update| now.checked_duration_since(last_update))
+        {
+            Some(dt) => dt.to_millis(),
+            None => return,
+        };
+
+        // without a wheel encoder the distance can only be estimated based on the speed of the motor.
+        let distance_in_mm = match distance_in_um {
+            Some(distance_in_um) => distance_in_um as f32 / 1_000.0,
+            None => estimated_speed_in_mm_per_s(motor_speed) as f32 * dt_in_ms as f32 / 1_000.0,
+        };
+        let yaw_rate = self.read_yaw_rate();
+        self.pose_estimator.update(
+            distance_in_mm,
+            self.steering.current_angle_in_degrees(),
+            yaw_rate,
+            dt_in_ms,
+        );
+
+        self.enforce_geofence();
+        self.control_speed(dt_in_ms);
+    }
+
+    /// The estimated pose of the car relative to where it has been started.
+    pub fn pose(&self) -> Pose {
+        self.pose_estimator.pose()
+    }
+
+    /// The yaw rate measured by the IMU, `None` if there is no IMU or it failed.
+    fn read_yaw_rate(&mut self) -> Option<f32> {
+        if self.imu_faulted {
+            return None;
+        }
+        match self.imu.as_mut()?.get_yaw_rate_in_degrees_per_second() {
+            Ok(yaw_rate) => Some(yaw_rate),
+            Err(e) => {
+                defmt::error!("Failed to read the IMU: {}", defmt::Debug2Format(&e));
+                self.handle_error(Error::Imu);
+                None
+            }
+        }
+    }
+
+    /// Adjust the duty of the motor to reach the target speed (if one is set).
+    fn control_speed(&mut self, dt_in_ms: u32) {
+        let (target_speed_in_mm_per_s, measured_speed_in_mm_per_s) =
+            match (self.target_speed_in_mm_per_s, self.odometry.as_ref()) {
+                (Some(target_speed_in_mm_per_s), Some(odometry)) => {
+                    (target_speed_in_mm_per_s, odometry.speed_in_mm_per_s())
+                }
+                _ => return,
+            };
+
+        let max_speed = match self.limit_speed(100, target_speed_in_mm_per_s > 0) {
+            Ok(max_speed) => max_speed,
+            Err(e) => {
+                self.halt();
+                self.handle_error(e);
+                return;
+            }
+        };
+        self.speed_controller.set_output_limit(max_speed as i32);
+        let duty = self.speed_controller.update(
+            target_speed_in_mm_per_s as i32,
+            measured_speed_in_mm_per_s,
+            dt_in_ms,
+        );
+        // the controller only drives in the direction of the target speed, reversing the motor to slow down would be too harsh.
+        let result = if target_speed_in_mm_per_s > 0 {
+            self.drive_forward_with_duty(duty.clamp(0, 100) as u8)
+        } else {
+            self.drive_backwards_with_duty((-duty).clamp(0, 100) as u8)
+        };
+        if let Err(e) = result {
+            // e.g. an obstacle appeared in front: stop instead of trying again with the next update.
+            self.halt();
+            self.handle_error(e);
+        }
+    }
+
+    pub fn ranging_config(&self) -> RangingConfig {
+        self.ranging_config
+    }
+
+    /// Change which ranging profiles are used by the distance sensor. Takes effect immediately.
+    pub fn set_ranging_config(&mut self, ranging_config: RangingConfig) {
+        self.ranging_config = ranging_config;
+        self.update_ranging_profile();
+    }
+
+    /// Switch the ranging profile of the distance sensor if needed for the current speed.
+    fn update_ranging_profile(&mut self) {
+        let high_speed = self.current_speed().unsigned_abs() > HIGH_SPEED_THRESHOLD;
+        let profile = self.ranging_config.profile(high_speed);
+        if profile == self.ranging_profile {
+            return;
+        }
+        self.ranging_profile = profile;
+
+        if let Some(front_distance_sensor) = self.front_distance_sensor.as_mut() {
+            if let Err(e) = front_distance_sensor.set_ranging_profile(profile) {
+                // the sensor will be reset (with the new profile) if this happens repeatedly.
+                defmt::error!(
+                    "Failed to change the TOF ranging profile: {}",
+                    defmt::Debug2Format(&e)
+                );
+                self.consecutive_distance_sensor_errors =
+                    self.consecutive_distance_sensor_errors.saturating_add(1);
+            }
+        }
+    }
+
+    pub fn current_state(&self) -> CarState {
+        self.current_state
+    }
+
+    /// A snapshot of the current status of the car.
+    pub fn status(&mut self) -> CarStatus {
+        CarStatus {
+            state: self.current_state,
+            speed: self.current_speed(),
+            drive_profile: self.drive_profile,
+            control: self.active_command.map(|(source, _)| source),
+            front_distance_in_mm: self.latest_front_distance_in_mm,
+            closing_speed_in_mm_per_s: self.closing_speed_estimator.closing_speed_in_mm_per_s(),
+            time_to_collision_in_ms: self.time_to_collision_in_ms(),
+            front_distance_sensor: self.front_distance_sensor.as_ref().map(|_| {
+                DistanceSensorStatus {
+                    consecutive_errors: self.consecutive_distance_sensor_errors,
+                    resets: self.distance_sensor_resets,
+                }
+            }),
+            battery: self.battery,
+            i2c_bus_recoveries: self.i2c_bus_recoveries,
+            reset: self.reset_info,
+            degraded_mode: self.is_degraded_mode(),
+            distance_travelled_in_mm: self
+                .odometry
+                .as_ref()
+                .map(|odometry| odometry.distance_travelled_in_mm()),
+            measured_speed_in_mm_per_s: self
+                .odometry
+                .as_ref()
+                .map(|odometry| odometry.speed_in_mm_per_s()),
+            target_speed_in_mm_per_s: self.target_speed_in_mm_per_s,
+            pose: self.pose(),
+        }
+    }
+
+    /// The car keeps crashing (see [`ResetInfo::requires_degraded_mode`]), thus it only runs in a degraded mode
+    /// with a reduced max. speed until the next reset.
+    pub fn is_degraded_mode(&self) -> bool {
+        self.reset_info.requires_degraded_mode()
+    }
+
+    /// Needs to be called with the number of I2C bus recoveries so far (see `i2c_bus::recovery_count`) before the
+    /// devices on the bus are used. If the bus has been recovered since the last call, the devices on it might have
+    /// lost their state and are re-initialised. Until the distance sensor delivers new data the car can't drive forward.
+    /// If the re-initialisation fails it is retried on the next call.
+    pub fn handle_i2c_bus_recoveries(&mut self, i2c_bus_recoveries: u32) {
+        if i2c_bus_recoveries == self.i2c_bus_recoveries {
+            return;
+        }
+        defmt::warn!("I2C bus has been recovered, re-initialising the devices on it");
+
+        self.emergency_stop(EmergencyStopReason::NoDistanceData);
+        self.forget_front_distance();
+        // give the sensor the full timeout to deliver new data before it's reset
+        self.last_distance_sensor_reset = None;
+
+        let mut success = true;
+        if let Some(front_distance_sensor) = self.front_distance_sensor.as_mut() {
+            if let Err(e) = front_distance_sensor.initialise(self.ranging_profile) {
+                defmt::error!(
+                    "Failed to re-initialise the TOF: {}",
+                    defmt::Debug2Format(&e)
+                );
+                success = false;
+            }
+        }
+        // the car can drive without the side distance sensor, the line sensor, the IMU and the display, thus a failure doesn't need
+        // to block the re-initialisation.
+        if let Some(side_distance_sensor) = self.side_distance_sensor.as_mut() {
+            if let Err(e) = side_distance_sensor.initialise(SIDE_DISTANCE_RANGING_PROFILE) {
+                defmt::error!(
+                    "Failed to re-initialise the side TOF: {}",
+                    defmt::Debug2Format(&e)
+                );
+            }
+        }
+        if let Some(line_sensor) = self.line_sensor.as_mut() {
+            if let Err(e) = line_sensor.initialise() {
+                defmt::error!(
+                    "Failed to re-initialise the line sensor: {}",
+                    defmt::Debug2Format(&e)
+                );
+            }
+        }
+        if let Some(imu) = self.imu.as_mut() {
+            match imu.initialise() {
+                Ok(()) => self.imu_faulted = false,
+                Err(e) => {
+                    defmt::error!(
+                        "Failed to re-initialise the IMU: {}",
+                        defmt::Debug2Format(&e)
+                    );
+                    self.handle_error(Error::Imu);
+                }
+            }
+        }
+        if let Some(display) = self.display.as_mut() {
+            match display.init() {
+                Ok(()) => self.display_faulted = false,
+                Err(e) => {
+                    defmt::error!(
+                        "Failed to re-initialise the display: {}",
+                        defmt::Debug2Format(&e)
+                    );
+                    self.handle_error(Error::Display);
+                }
+            }
+        }
+
+        if success {
+            self.i2c_bus_recoveries = i2c_bus_recoveries;
+            self.update_display();
+        }
+    }
+
+    /// Needs to be called for every new battery measurement. Limits the speed if the battery is low
+    /// and stops the car if the battery is critical.
+    pub fn handle_battery_status(&mut self, battery: BatteryStatus) {
+        let previous_state = self.battery.map(|battery| battery.state);
+        self.battery = Some(battery);
+        if previous_state.unwrap_or(BatteryState::Ok) != battery.state {
+            defmt::info!(
+                "battery state changed to {} at {}mV",
+                battery.state,
+                battery.voltage_in_mv
+            );
+            event_log::record(Event::BatteryStateChanged(battery.state));
+        }
+        if previous_state == Some(BatteryState::Critical) && battery.state != BatteryState::Critical
+        {
+            defmt::info!("battery not critical anymore, the car can drive again");
+            self.release_command(CommandSource::Safety);
+        }
+
+        match battery.state {
+            BatteryState::Ok => {}
+            BatteryState::Low => {
+                if self.current_speed().unsigned_abs() > LOW_BATTERY_MAX_SPEED {
+                    defmt::warn!(
+                        "battery low ({}mV), reducing the speed",
+                        battery.voltage_in_mv
+                    );
+                    self.enforce_limits();
+                }
+            }
+            BatteryState::Critical if previous_state != Some(BatteryState::Critical) => {
+                if self.current_speed() != 0 {
+                    defmt::error!(
+                        "battery critical ({}mV), stopping the car!",
+                        battery.voltage_in_mv
+                    );
+                    event_log::record(Event::EmergencyStop(EmergencyStopReason::BatteryCritical));
+                }
+                // keep the car stopped until the battery has been recharged
+                self.safety_stop(true);
+            }
+            BatteryState::Critical => {}
+        }
+
+        if previous_state != Some(battery.state) {
+            self.update_display();
+        }
+    }
+
+    /// Reduce the speed (percentage) to what is currently allowed in the direction based on the battery, the degraded
+    /// mode and the drive profile.
+    fn limit_speed(&self, speed: u8, forward: bool) -> Result<u8, Error> {
+        let battery_state = self.battery.map(|battery| battery.state);
+        if battery_state == Some(BatteryState::Critical) {
+            return Err(Error::BatteryCritical);
+        }
+        // only reduce valid speeds, invalid ones will be rejected by the motor.
+        if speed > 100 {
+            return Ok(speed);
+        }
+        let mut speed = speed;
+        if battery_state == Some(BatteryState::Low) {
+            speed = speed.min(LOW_BATTERY_MAX_SPEED);
+        }
+        if self.is_degraded_mode() {
+            speed = speed.min(DEGRADED_MODE_MAX_SPEED);
+        }
+        let limits = self.drive_limits();
+        if forward {
+            speed = speed.min(limits.max_forward_speed);
+        } else {
+            speed = speed.min(limits.max_backwards_speed);
+        }
+        Ok(speed)
+    }
+
+    /// Reduce the steering angle to the range allowed by the drive profile.
+    fn limit_steering(&self, direction: Direction) -> Direction {
+        let max_steering = self.drive_limits().max_steering;
+        match direction {
+            Centre => Centre,
+            Left(percentage) => Left(percentage.min(max_steering)),
+            Right(percentage) => Right(percentage.min(max_steering)),
+        }
+    }
+
+    /// The drive profile which restricts what the car is allowed to do, see `drive_profile`.
+    pub fn drive_profile(&self) -> DriveProfile {
+        self.drive_profile
+    }
+
+    pub fn drive_limits(&self) -> DriveLimits {
+        self.drive_profile.limits()
+    }
+
+    /// Switch to another drive profile. The car stops, so that it doesn't continue with a speed which isn't allowed
+    /// anymore. The new minimum front distance applies from the next validation of the distance on.
+    pub fn set_drive_profile(&mut self, drive_profile: DriveProfile) {
+        if drive_profile == self.drive_profile {
+            return;
+        }
+        defmt::info!("switching to the drive profile {}", drive_profile);
+        event_log::record(Event::DriveProfileChanged(drive_profile));
+        self.drive_profile = drive_profile;
+        // the new profile might not allow overriding the collision avoidance
+        self.set_collision_override(false);
+        self.safety_stop(false);
+    }
+
+    /// Stop the car if it is leaving the geofence of the drive profile (see [`DriveLimits::geofence_radius_in_mm`]).
+    fn enforce_geofence(&mut self) {
+        let radius_in_mm = match self.drive_limits().geofence_radius_in_mm {
+            Some(radius_in_mm) => radius_in_mm as f32,
+            None => return,
+        };
+        let pose = self.pose();
+        let outside =
+            pose.x_in_mm * pose.x_in_mm + pose.y_in_mm * pose.y_in_mm > radius_in_mm * radius_in_mm;
+        if outside != self.outside_geofence {
+            self.outside_geofence = outside;
+            if outside {
+                defmt::warn!("the car has reached the geofence, it can only drive back");
+                event_log::record(Event::GeofenceReached);
+            }
+        }
+        let speed = self.current_speed();
+        if speed != 0 && self.is_leaving_geofence(speed > 0) {
+            self.enforce_limits();
+        }
+    }
+
+    /// Whether driving in the direction (forward or backwards) takes the car further away from where it has been
+    /// switched on while it is outside of the geofence.
+    fn is_leaving_geofence(&self, forward: bool) -> bool {
+        if !self.outside_geofence {
+            return false;
+        }
+        let pose = self.pose();
+        // the movement along the heading which gets the car closer to the start
+        let towards_start =
+            -(pose.x_in_mm * libm::cosf(pose.heading) + pose.y_in_mm * libm::sinf(pose.heading));
+        (towards_start > 0.0) != forward
+    }
+
+    /// Start calibrating the steering. The car stops and can't drive until the calibration is finished.
+    pub fn start_steering_calibration(&mut self) {
+        defmt::info!("starting steering calibration");
+        event_log::record(Event::SteeringCalibrationStarted);
+        self.arbiter.release_all();
+        self.set_active_command(None);
+        self.halt();
+        self.steering_calibration = Some(CalibrationParameter::MaxLeft);
+        self.select_steering_calibration_parameter(CalibrationParameter::MaxLeft)
+            .ok(); // we've just activated the calibration
+    }
+
+    /// Finish calibrating the steering. The calibration stays in use but is not persisted by the car itself.
+    pub fn finish_steering_calibration(&mut self) {
+        defmt::info!(
+            "finished steering calibration: {}",
+            self.steering.calibration()
+        );
+        event_log::record(Event::SteeringCalibrationFinished);
+        self.steering_calibration = None;
+        self.steer_center();
+        self.update_display();
+    }
+
+    pub fn is_steering_calibration_active(&self) -> bool {
+        self.steering_calibration.is_some()
+    }
+
+    pub fn steering_calibration(&self) -> SteeringCalibration {
+        self.steering.calibration()
+    }
+
+    /// The calibration parameter which is currently selected to be adjusted (if the calibration is active).
+    pub fn selected_steering_calibration_parameter(&self) -> Option<CalibrationParameter> {
+        self.steering_calibration
+    }
+
+    /// Select the calibration parameter to be adjusted. The steering moves to the position affected by it
+    /// so that the effect of the changes is visible. Only possible while the calibration is active.
+    pub fn select_steering_calibration_parameter(
+        &mut self,
+        parameter: CalibrationParameter,
+    ) -> Result<(), Error> {
+        if !self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationNotActive);
+        }
+
+        self.steering_calibration = Some(parameter);
+        match parameter {
+            CalibrationParameter::MaxLeft => self.steer_left(),
+            CalibrationParameter::MaxRight => self.steer_right(),
+            CalibrationParameter::Centre
+            | CalibrationParameter::Trim
+            | CalibrationParameter::Expo => self.steer_center(),
+        }
+        self.update_display();
+
+        Ok(())
+    }
+
+    /// Change a parameter of the steering calibration by the given amount. Only possible while the calibration is active.
+    pub fn adjust_steering_calibration(
+        &mut self,
+        parameter: CalibrationParameter,
+        delta: i16,
+    ) -> Result<SteeringCalibration, Error> {
+        if !self.is_steering_calibration_active() {
+            return Err(Error::SteeringCalibrationNotActive);
+        }
+
+        let calibration = self.steering.calibration().adjusted(parameter, delta);
+        self.steering
+            .set_calibration(calibration)
+            .map_err(Error::Steering)?;
+        self.update_display();
+
+        Ok(calibration)
     }
 
     pub fn handle_distance_sensor_interrupt(
         &mut self,
         now: fugit::TimerInstantU32<1_000_000>,
-    ) -> Result<(), DE> {
+    ) -> Result<(), Error> {
         if let Some(front_distance_sensor) = self.front_distance_sensor.as_mut() {
             let result = match front_distance_sensor.get_distance_in_mm() {
                 Ok(distance) => {
                     defmt::debug!("Received range: {}mm", distance);
-                    self.latest_front_distance_in_mm = Some(distance);
-                    self.last_front_distance_update = Some(now);
+                    self.consecutive_distance_sensor_errors = 0;
+                    // rejected measurements are treated as if there was no measurement (i.e. the lag check applies)
+                    let min_front_distance_in_mm = self.drive_limits().min_front_distance_in_mm;
+                    if let Some(distance) =
+                        self.front_distance_filter
+                            .filter(distance, now, min_front_distance_in_mm)
+                    {
+                        self.latest_front_distance_in_mm = Some(distance);
+                        self.last_front_distance_update = Some(now);
+                        self.closing_speed_estimator.update(distance, now);
+                    }
                     Ok(())
                 }
                 Err(e) => {
@@ -148,11 +1054,22 @@ where
                         "Failed to get distance from TOF: {}",
                         defmt::Debug2Format(&e)
                     );
+                    event_log::record(Event::DistanceSensorError);
                     self.latest_front_distance_in_mm = None;
-                    Err(e)
+                    self.consecutive_distance_sensor_errors =
+                        self.consecutive_distance_sensor_errors.saturating_add(1);
+                    Err(Error::DistanceSensor)
                 }
             };
 
+            if self.consecutive_distance_sensor_errors >= MAX_CONSECUTIVE_DISTANCE_SENSOR_ERRORS {
+                defmt::error!(
+                    "{} failed TOF measurements in a row => resetting the TOF",
+                    self.consecutive_distance_sensor_errors
+                );
+                self.reset_distance_sensor(now);
+            }
+
             self.validate_distance(now);
 
             self.update_display();
@@ -164,62 +1081,326 @@ where
     }
 
     pub fn validate_distance(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        self.reset_distance_sensor_if_unresponsive(now);
+
         if let Some(last_front_distance_update) = self.last_front_distance_update {
             if last_front_distance_update + MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS.millis() < now {
                 defmt::error!("took too long to get a new TOF update => enabling emergency brake!");
-                self.halt_if_driving_forward();
-                self.current_state = ForwardDistanceInvalid;
+                // the latest distance is outdated, thus it can't be used to creep forward
+                self.set_collision_override(false);
+                self.emergency_stop(EmergencyStopReason::DistanceSensorLag);
             } else {
                 // handle the case if we have data. note that if we don't have data we don't do anything
                 // and just keep the previous state until we either time out (see above) or have a distance available again.
                 if let Some(distance_in_mm) = self.latest_front_distance_in_mm {
-                    if distance_in_mm < MIN_FRONT_DISTANCE_IN_MM {
-                        self.halt_if_driving_forward();
-                        self.led_status_obstacle.set_high().ok();
-                        if self.current_state != ForwardDistanceInvalid {
-                            defmt::warn!("collision warning, the front distance of {}mm is less than the safe minimum of {}mm - stopping the car!", distance_in_mm, MIN_FRONT_DISTANCE_IN_MM);
-                            self.current_state = ForwardDistanceInvalid;
+                    let min_front_distance_in_mm = self.drive_limits().min_front_distance_in_mm;
+                    match assess_collision_risk(
+                        distance_in_mm,
+                        self.closing_speed_estimator.closing_speed_in_mm_per_s(),
+                        min_front_distance_in_mm,
+                    ) {
+                        CollisionRisk::TooClose => {
+                            self.led_status_obstacle.set_high().ok();
+                            if self.current_state != ForwardDistanceInvalid {
+                                defmt::warn!("collision warning, the front distance of {}mm is less than the safe minimum of {}mm - stopping the car!", distance_in_mm, min_front_distance_in_mm);
+                            }
+                            self.emergency_stop(EmergencyStopReason::Obstacle);
+                        }
+                        CollisionRisk::TimeToCollision {
+                            time_to_collision_in_ms,
+                        } => {
+                            self.led_status_obstacle.set_high().ok();
+                            if self.current_state != ForwardDistanceInvalid {
+                                defmt::warn!("collision warning, the obstacle {}mm in front will be hit in {}ms which is less than the safe minimum of {}ms - stopping the car!", distance_in_mm, time_to_collision_in_ms, MIN_TIME_TO_COLLISION_IN_MS);
+                            }
+                            self.emergency_stop(EmergencyStopReason::TimeToCollision);
+                        }
+                        CollisionRisk::Clear => {
+                            // enough distance => allow driving forward
+                            self.led_status_obstacle.set_low().ok();
+                            self.set_state(Normal);
                         }
-                    } else {
-                        // enough distance => allow driving forward
-                        self.led_status_obstacle.set_low().ok();
-                        self.current_state = Normal;
                     }
                 }
             }
         } else {
             defmt::error!("no distance data available => prevent driving forward");
-            self.halt_if_driving_forward();
-            self.current_state = ForwardDistanceInvalid;
+            self.emergency_stop(EmergencyStopReason::NoDistanceData);
+        }
+    }
+
+    /// Override the collision avoidance, e.g. to dock against an object: while it is active the car may creep forward
+    /// with at most [`COLLISION_OVERRIDE_MAX_SPEED`] even if the obstacle in front is too close, down to a hard minimum
+    /// distance (see [`DriveLimits::collision_override_min_front_distance_in_mm`]). This only works with a valid front
+    /// distance: if the distance sensor lags the override is ended. Drive profiles without a minimum distance (e.g. the
+    /// kid profile) don't allow overriding the collision avoidance, thus the request is ignored.
+    pub fn set_collision_override(&mut self, active: bool) {
+        if active == self.collision_override {
+            return;
+        }
+        if active
+            && self
+                .drive_limits()
+                .collision_override_min_front_distance_in_mm
+                .is_none()
+        {
+            defmt::warn!(
+                "the collision avoidance can't be overridden in the drive profile {}",
+                self.drive_profile
+            );
+            return;
         }
+        if active {
+            defmt::warn!("the collision avoidance is being overridden!");
+        } else {
+            defmt::info!("the collision avoidance is no longer overridden");
+        }
+        event_log::record(Event::CollisionOverride(active));
+        self.collision_override = active;
+    }
+
+    pub fn is_collision_override_active(&self) -> bool {
+        self.collision_override
     }
 
-    /// Halt in case the car is currently driving forward, otherwise do nothing.
-    /// This is used in the collision avoidance to ensure that it's still possible to drive backwards.
-    fn halt_if_driving_forward(&mut self) {
+    /// Whether the car may drive forward although the front distance isn't valid, see [`Car::set_collision_override`].
+    fn may_creep_forward(&self) -> bool {
+        self.collision_override
+            && self
+                .drive_limits()
+                .may_creep_forward(self.latest_front_distance_in_mm)
+    }
+
+    /// The latest filtered front distance, `None` if there is no (valid) measurement.
+    pub fn front_distance_in_mm(&self) -> Option<u16> {
+        self.latest_front_distance_in_mm
+    }
+
+    /// The estimated time (in milliseconds) until the car collides with the obstacle in front, based on the
+    /// distance and the speed at which the obstacle is getting closer. `None` if the obstacle isn't approaching
+    /// (or the data is not available).
+    pub fn time_to_collision_in_ms(&self) -> Option<u32> {
+        collision::time_to_collision_in_ms(
+            self.latest_front_distance_in_mm?,
+            self.closing_speed_estimator.closing_speed_in_mm_per_s()?,
+        )
+    }
+
+    /// Reset the distance sensor if it hasn't delivered any measurement for too long.
+    fn reset_distance_sensor_if_unresponsive(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        if self.front_distance_sensor.is_none() {
+            return;
+        }
+
+        match self
+            .last_front_distance_update
+            .max(self.last_distance_sensor_reset)
+        {
+            // start observing the sensor
+            None => self.last_distance_sensor_reset = Some(now),
+            Some(last_sign_of_life)
+                if last_sign_of_life + DISTANCE_SENSOR_RESET_TIMEOUT_IN_MS.millis() < now =>
+            {
+                defmt::error!(
+                    "no TOF measurement for more than {}ms => resetting the TOF",
+                    DISTANCE_SENSOR_RESET_TIMEOUT_IN_MS
+                );
+                self.reset_distance_sensor(now);
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Reset the distance sensor. The car can't drive forward until a new measurement is available.
+    fn reset_distance_sensor(&mut self, now: fugit::TimerInstantU32<1_000_000>) {
+        event_log::record(Event::DistanceSensorReset);
+        self.emergency_stop(EmergencyStopReason::NoDistanceData);
+        self.forget_front_distance();
+        self.consecutive_distance_sensor_errors = 0;
+        self.distance_sensor_resets += 1;
+        self.last_distance_sensor_reset = Some(now);
+
+        if let Some(front_distance_sensor) = self.front_distance_sensor.as_mut() {
+            match front_distance_sensor.reset(self.ranging_profile) {
+                Ok(()) => defmt::info!("TOF has been reset"),
+                // it'll be tried again after the timeout
+                Err(e) => defmt::error!("Failed to reset the TOF: {}", defmt::Debug2Format(&e)),
+            }
+        }
+
+        self.update_display();
+    }
+
+    /// Stop driving forward (driving backwards is still possible) until the distance in front is validated again.
+    /// While the collision avoidance is overridden the car keeps creeping forward unless it is too close.
+    fn emergency_stop(&mut self, reason: EmergencyStopReason) {
+        if self.current_state != ForwardDistanceInvalid {
+            event_log::record(Event::EmergencyStop(reason));
+        }
+        self.set_state(ForwardDistanceInvalid);
         if self.current_speed() > 0 {
-            self.halt();
+            // driving forward is only allowed anymore while creeping forward, see `Car::may_creep_forward`
+            self.enforce_limits();
+        }
+    }
+
+    fn set_state(&mut self, state: CarState) {
+        if state != self.current_state {
+            event_log::record(Event::StateChanged(state));
+            self.current_state = state;
+        }
+    }
+
+    /// Discard all knowledge about the front distance, e.g. because the sensor has been reset.
+    fn forget_front_distance(&mut self) {
+        self.latest_front_distance_in_mm = None;
+        self.last_front_distance_update = None;
+        self.front_distance_filter.reset();
+        self.closing_speed_estimator.reset();
+    }
+
+    /// React to an error according to the central policy, see [`Error::reaction`].
+    pub fn handle_error(&mut self, error: Error) {
+        match error.reaction() {
+            Reaction::Reject => defmt::warn!("request rejected: {}", error),
+            Reaction::Retry => defmt::warn!("{}, it will be retried", error),
+            Reaction::Degrade => {
+                defmt::error!("{}, continuing without the affected component", error);
+                if error == Error::Display && !self.display_faulted {
+                    event_log::record(Event::DisplayDisabled);
+                    self.display_faulted = true;
+                }
+                if error == Error::Imu && !self.imu_faulted {
+                    event_log::record(Event::ImuDisabled);
+                    self.imu_faulted = true;
+                }
+            }
+            Reaction::Halt => {
+                defmt::error!("{}, stopping the car!", error);
+                event_log::record(Event::HaltedOnError);
+                self.safety_stop(false);
+            }
+        }
+    }
+
+    /// Needs to be called periodically to refresh the display. If the display has been disabled due to an error
+    /// it is re-initialised first.
+    pub fn refresh_display(&mut self) {
+        if self.display_faulted {
+            if let Some(display) = self.display.as_mut() {
+                if display.init().is_err() {
+                    defmt::trace!("display still not available");
+                    return;
+                }
+                defmt::info!("display is available again");
+                self.display_faulted = false;
+            }
         }
+        self.update_display();
     }
 
+    /// Redraw the display with the current status, unless it has been disabled due to an error.
     fn update_display(&mut self) {
+        if self.display_faulted {
+            return;
+        }
+        if let Err(e) = self.draw_display() {
+            defmt::error!("Failed to update the display: {}", defmt::Debug2Format(&e));
+            self.handle_error(Error::Display);
+        }
+    }
+
+    fn draw_display(&mut self) -> Result<(), DisplayError> {
         if let Some(display) = self.display.as_mut() {
             display.clear();
-            if let Some(front_distance_in_mm) = self.latest_front_distance_in_mm {
-                let text_style = MonoTextStyleBuilder::new()
-                    .font(&FONT_6X12)
-                    .text_color(BinaryColor::On)
-                    .build();
-                let mut buffer = itoa::Buffer::new();
-                let front_distance_in_mm = buffer.format(front_distance_in_mm);
-                Text::new("Front distance: ", Point::new(15, 15), text_style)
-                    .draw(display)
-                    .unwrap();
-                Text::new(front_distance_in_mm, Point::new(15, 30), text_style)
-                    .draw(display)
-                    .unwrap();
-            }
-            display.flush().unwrap();
+            let text_style = text_style();
+            if let Some(parameter) = self.steering_calibration {
+                let calibration = self.steering.calibration();
+                Text::new("Steering calibration", Point::new(5, 12), text_style).draw(display)?;
+                draw_labelled_value(display, "C:", calibration.centre, Point::new(5, 26))?;
+                draw_labelled_value(display, "T:", calibration.trim, Point::new(65, 26))?;
+                draw_labelled_value(display, "L:", calibration.max_left, Point::new(5, 40))?;
+                draw_labelled_value(display, "R:", calibration.max_right, Point::new(50, 40))?;
+                draw_labelled_value(display, "E:", calibration.expo, Point::new(95, 40))?;
+                let parameter = match parameter {
+                    CalibrationParameter::Centre => "Adjust: centre",
+                    CalibrationParameter::Trim => "Adjust: trim",
+                    CalibrationParameter::MaxLeft => "Adjust: max left",
+                    CalibrationParameter::MaxRight => "Adjust: max right",
+                    CalibrationParameter::Expo => "Adjust: expo",
+                };
+                Text::new(parameter, Point::new(5, 54), text_style).draw(display)?;
+            } else {
+                if let Some(front_distance_in_mm) = self.latest_front_distance_in_mm {
+                    let mut buffer = itoa::Buffer::new();
+                    let front_distance_in_mm = buffer.format(front_distance_in_mm);
+                    Text::new("Front distance: ", Point::new(15, 15), text_style).draw(display)?;
+                    Text::new(front_distance_in_mm, Point::new(15, 30), text_style)
+                        .draw(display)?;
+                }
+                // show why the car has been reset, unless it was the user
+                let reset = if self.reset_info.requires_degraded_mode() {
+                    Some("DEGRADED")
+                } else {
+                    match self.reset_info.cause {
+                        ResetCause::PowerOn | ResetCause::ResetPin => None,
+                        ResetCause::BrownOut => Some("Brown-out"),
+                        ResetCause::Software => Some("SW reset"),
+                        ResetCause::Panic => Some("Panic"),
+                        ResetCause::Watchdog | ResetCause::WindowWatchdog => Some("Watchdog"),
+                        ResetCause::LowPower => Some("LP reset"),
+                        ResetCause::Unknown => Some("Reset ?"),
+                    }
+                };
+                if let Some(reset) = reset {
+                    Text::new(reset, Point::new(65, 30), text_style).draw(display)?;
+                }
+                if self.distance_sensor_resets > 0 {
+                    draw_labelled_value(
+                        display,
+                        "TOF resets:",
+                        self.distance_sensor_resets,
+                        Point::new(5, 42),
+                    )?;
+                }
+                if let Some(battery) = self.battery {
+                    let warning = match battery.state {
+                        BatteryState::Ok => None,
+                        BatteryState::Low => Some("Bat. low! mV:"),
+                        BatteryState::Critical => Some("Bat. empty! mV:"),
+                    };
+                    if let Some(warning) = warning {
+                        draw_labelled_value(
+                            display,
+                            warning,
+                            battery.voltage_in_mv,
+                            Point::new(5, 54),
+                        )?;
+                    }
+                }
+            }
+            display.flush()?;
         }
+        Ok(())
     }
 }
+
+fn text_style() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&FONT_6X12)
+        .text_color(BinaryColor::On)
+        .build()
+}
+
+/// Draw a label directly followed by a numeric value, e.g. "C:4930".
+fn draw_labelled_value(
+    display: &mut Display,
+    label: &str,
+    value: impl itoa::Integer,
+    position: Point,
+) -> Result<(), DisplayError> {
+    let mut buffer = itoa::Buffer::new();
+    let next = Text::new(label, position, text_style()).draw(display)?;
+    Text::new(buffer.format(value), next, text_style()).draw(display)?;
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! Persistent configuration of the robotcar, stored in the internal flash of the microcontroller.
+//!
+//! The last flash sector is reserved for the configuration (see `memory.x`). Erasing a sector of
+//! this size takes a few seconds, during which the CPU is stalled (it executes from the same flash),
+//! which would trigger the watchdog. To avoid this the configuration is written as an append-only
+//! log of fixed-size records: storing a new configuration just programs the next free slot, and
+//! the latest valid record wins when loading. The sector is only erased (and the latest record
+//! re-written) during boot, before the watchdog is started, once it is nearly full.
+//!
+//! The same log is also used to persist the latest [`CrashRecord`], it is distinguished from the configuration
+//! by the magic number at the start of the record.
+
+use crate::drive_profile::DriveProfile;
+use crate::event_log::{self, CrashRecord, Event};
+use crate::manoeuvre::ManoeuvreSequence;
+use crate::steering::{SteeringCalibration, DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND};
+use crate::tof_sensor::{RangingConfig, RangingPreset};
+use defmt::Format;
+use stm32f4xx_hal::flash::{FlashExt, LockedFlash};
+
+/// Offset (from the start of the flash) of the sector reserved for the configuration.
+const CONFIG_SECTOR_OFFSET: usize = 0x6_0000;
+/// Sector number of the sector reserved for the configuration (sector 7 on the STM32F401RE).
+const CONFIG_SECTOR_NUMBER: u8 = 7;
+/// Size of the sector reserved for the configuration.
+const CONFIG_SECTOR_SIZE: usize = 0x2_0000;
+
+/// Size of a single record. This is larger than currently needed to leave room for future settings.
+const SLOT_SIZE: usize = 256;
+const SLOT_COUNT: usize = CONFIG_SECTOR_SIZE / SLOT_SIZE;
+/// If fewer free slots than this are available during boot the sector will be compacted.
+const MIN_FREE_SLOTS_AT_BOOT: usize = 16;
+
+/// Marks the start of a valid configuration record.
+const RECORD_MAGIC: u32 = 0x5243_4346; // "RCCF"
+/// Needs to be increased every time the layout of the payload changes. Records with a different
+/// version are ignored (i.e. the defaults will be used instead).
+const RECORD_VERSION: u16 = 7;
+/// Marks the start of a valid crash record.
+const CRASH_RECORD_MAGIC: u32 = 0x5243_4352; // "RCCR"
+/// Same as [`RECORD_VERSION`], but for the crash records.
+const CRASH_RECORD_VERSION: u16 = 3;
+/// Size of the header: magic (4 bytes), version (2 bytes) and payload length (2 bytes).
+const HEADER_SIZE: usize = 8;
+/// Size of the trailing checksum.
+const CHECKSUM_SIZE: usize = 4;
+const MAX_PAYLOAD_SIZE: usize = SLOT_SIZE - HEADER_SIZE - CHECKSUM_SIZE;
+
+/// Errors which can happen while storing the configuration.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum Error {
+    /// All slots of the configuration sector are in use. It will be compacted during the next boot.
+    StorageFull,
+    /// Erasing or programming the flash failed. The details have been logged.
+    FlashError,
+}
+
+/// All settings of the robotcar which can be changed at runtime and survive a restart.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct Config {
+    /// The calibration of the steering servo.
+    pub steering: SteeringCalibration,
+    /// The maximum speed at which the steering turns, `None` to turn as fast as the servo can.
+    pub steering_slew_rate_in_degrees_per_second: Option<u16>,
+    /// The ranging profiles used by the TOF sensor.
+    pub tof_ranging: RangingConfig,
+    /// The manoeuvre sequence played on request (see `ManoeuvrePlayer`).
+    pub manoeuvre_sequence: ManoeuvreSequence,
+    /// The limits the car is driven with (see `drive_profile`).
+    pub drive_profile: DriveProfile,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            steering: SteeringCalibration::default(),
+            steering_slew_rate_in_degrees_per_second: Some(DEFAULT_SLEW_RATE_IN_DEGREES_PER_SECOND),
+            tof_ranging: RangingConfig::default(),
+            manoeuvre_sequence: ManoeuvreSequence::default(),
+            drive_profile: DriveProfile::default(),
+        }
+    }
+}
+
+impl Config {
+    const PAYLOAD_SIZE: usize = 15 + ManoeuvreSequence::SIZE;
+
+    fn to_bytes(self) -> [u8; Self::PAYLOAD_SIZE] {
+        let mut bytes = [0; Self::PAYLOAD_SIZE];
+        bytes[0..2].copy_from_slice(&self.steering.centre.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.steering.trim.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.steering.max_left.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.steering.max_right.to_le_bytes());
+        bytes[8] = self.steering.expo;
+        // a slew rate of 0 would mean that the steering never moves, thus it's used to represent `None`.
+        bytes[9..11].copy_from_slice(
+            &self
+                .steering_slew_rate_in_degrees_per_second
+                .unwrap_or(0)
+                .to_le_bytes(),
+        );
+        bytes[11] = ranging_preset_to_byte(self.tof_ranging.low_speed_preset);
+        bytes[12] = ranging_preset_to_byte(self.tof_ranging.high_speed_preset);
+        bytes[13] = self.tof_ranging.narrow_field_of_view as u8;
+        bytes[14..14 + ManoeuvreSequence::SIZE]
+            .copy_from_slice(&self.manoeuvre_sequence.to_bytes());
+        bytes[14 + ManoeuvreSequence::SIZE] = drive_profile_to_byte(self.drive_profile);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Config> {
+        if bytes.len() != Self::PAYLOAD_SIZE {
+            return None;
+        }
+        Some(Config {
+            steering: SteeringCalibration {
+                centre: u16::from_le_bytes([bytes[0], bytes[1]]),
+                trim: i16::from_le_bytes([bytes[2], bytes[3]]),
+                max_left: u16::from_le_bytes([bytes[4], bytes[5]]),
+                max_right: u16::from_le_bytes([bytes[6], bytes[7]]),
+                expo: bytes[8],
+            },
+            steering_slew_rate_in_degrees_per_second: Some(u16::from_le_bytes([
+                bytes[9], bytes[10],
+            ]))
+            .filter(|&slew_rate| slew_rate != 0),
+            tof_ranging: RangingConfig {
+                low_speed_preset: ranging_preset_from_byte(bytes[11])?,
+                high_speed_preset: ranging_preset_from_byte(bytes[12])?,
+                narrow_field_of_view: bytes[13] != 0,
+            },
+            manoeuvre_sequence: ManoeuvreSequence::from_bytes(
+                &bytes[14..14 + ManoeuvreSequence::SIZE],
+            )?,
+            drive_profile: drive_profile_from_byte(bytes[14 + ManoeuvreSequence::SIZE])?,
+        })
+    }
+}
+
+fn ranging_preset_to_byte(preset: RangingPreset) -> u8 {
+    match preset {
+        RangingPreset::ShortFast => 0,
+        RangingPreset::LongPrecise => 1,
+    }
+}
+
+fn ranging_preset_from_byte(byte: u8) -> Option<RangingPreset> {
+    match byte {
+        0 => Some(RangingPreset::ShortFast),
+        1 => Some(RangingPreset::LongPrecise),
+        _ => None,
+    }
+}
+
+fn drive_profile_to_byte(profile: DriveProfile) -> u8 {
+    match profile {
+        DriveProfile::Normal => 0,
+        DriveProfile::Kid => 1,
+    }
+}
+
+fn drive_profile_from_byte(byte: u8) -> Option<DriveProfile> {
+    match byte {
+        0 => Some(DriveProfile::Normal),
+        1 => Some(DriveProfile::Kid),
+        _ => None,
+    }
+}
+
+// ensure at compile time that the configuration & the crash record always fit into a slot.
+const _: () = assert!(Config::PAYLOAD_SIZE <= MAX_PAYLOAD_SIZE);
+const _: () = assert!(CrashRecord::SIZE <= MAX_PAYLOAD_SIZE);
+
+/// Loads and stores the [`Config`] (and the [`CrashRecord`]) from/to the flash.
+pub struct ConfigStore {
+    flash: LockedFlash,
+    /// The slot which will be written next, `None` if the sector is full.
+    next_free_slot: Option<usize>,
+}
+
+impl ConfigStore {
+    /// Create the store. If the configuration sector is nearly full it will be compacted, which stalls
+    /// the CPU for a few seconds. Thus, this must be called before the watchdog is started!
+    pub fn new(flash: LockedFlash) -> ConfigStore {
+        let mut store = ConfigStore {
+            flash,
+            next_free_slot: None,
+        };
+        store.next_free_slot = store.find_next_free_slot();
+
+        let free_slots = SLOT_COUNT - store.next_free_slot.unwrap_or(SLOT_COUNT);
+        if free_slots < MIN_FREE_SLOTS_AT_BOOT {
+            defmt::info!(
+                "config storage nearly full ({} slots left), compacting it",
+                free_slots
+            );
+            let config = store.load();
+            let crash_record = store.load_crash_record();
+            if let Err(e) = store.compact(config, crash_record) {
+                defmt::error!("failed to compact the config storage: {}", e);
+            }
+        }
+
+        store
+    }
+
+    /// Load the latest stored configuration. Returns the default configuration if none has been stored so far.
+    pub fn load(&self) -> Config {
+        let config = (0..SLOT_COUNT)
+            .rev()
+            .find_map(|slot| {
+                self.read_record(slot, RECORD_MAGIC, RECORD_VERSION)
+                    .and_then(Config::from_bytes)
+            })
+            .unwrap_or_else(|| {
+                defmt::info!("no valid config found in flash, using the defaults");
+                Config::default()
+            });
+        defmt::debug!("loaded config: {}", config);
+        config
+    }
+
+    /// Persist the configuration. It will be used from the next boot onwards.
+    pub fn store(&mut self, config: Config) -> Result<(), Error> {
+        let slot = self.write_record(RECORD_MAGIC, RECORD_VERSION, &config.to_bytes())?;
+        defmt::info!("stored config in slot {}: {}", slot, config);
+        Ok(())
+    }
+
+    /// Load the latest stored crash record (if any).
+    pub fn load_crash_record(&self) -> Option<CrashRecord> {
+        (0..SLOT_COUNT).rev().find_map(|slot| {
+            self.read_record(slot, CRASH_RECORD_MAGIC, CRASH_RECORD_VERSION)
+                .and_then(CrashRecord::from_bytes)
+        })
+    }
+
+    /// Persist a crash record. It replaces the previously stored one.
+    pub fn store_crash_record(&mut self, crash_record: CrashRecord) -> Result<(), Error> {
+        let slot = self.write_record(
+            CRASH_RECORD_MAGIC,
+            CRASH_RECORD_VERSION,
+            &crash_record.to_bytes(),
+        )?;
+        defmt::info!("stored crash record in slot {}", slot);
+        Ok(())
+    }
+
+    /// Erase the configuration sector and write the configuration (and the crash record) to the first slots.
+    fn compact(&mut self, config: Config, crash_record: Option<CrashRecord>) -> Result<(), Error> {
+        self.flash
+            .unlocked()
+            .erase(CONFIG_SECTOR_NUMBER)
+            .map_err(|e| {
+                defmt::error!("failed to erase flash: {}", defmt::Debug2Format(&e));
+                event_log::record(Event::StorageError);
+                Error::FlashError
+            })?;
+        self.next_free_slot = Some(0);
+        self.store(config)?;
+        if let Some(crash_record) = crash_record {
+            self.store_crash_record(crash_record)?;
+        }
+        Ok(())
+    }
+
+    fn slot(&self, slot: usize) -> &[u8] {
+        let offset = CONFIG_SECTOR_OFFSET + slot * SLOT_SIZE;
+        &self.flash.read()[offset..offset + SLOT_SIZE]
+    }
+
+    fn find_next_free_slot(&self) -> Option<usize> {
+        // slots are written in order, thus the first erased slot is followed only by erased slots.
+        (0..SLOT_COUNT).find(|&slot| self.slot(slot).iter().all(|&b| b == 0xFF))
+    }
+
+    /// Read the payload of the record in the given slot if it's a valid record of the expected type & version.
+    fn read_record(
+        &self,
+        slot: usize,
+        expected_magic: u32,
+        expected_version: u16,
+    ) -> Option<&[u8]> {
+        let bytes = self.slot(slot);
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let payload_len = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        if magic != expected_magic || payload_len > MAX_PAYLOAD_SIZE {
+            return None;
+        }
+
+        let checksum_offset = HEADER_SIZE + payload_len;
+        let checksum = u32::from_le_bytes([
+            bytes[checksum_offset],
+            bytes[checksum_offset + 1],
+            bytes[checksum_offset + 2],
+            bytes[checksum_offset + 3],
+        ]);
+        if checksum != crc32(&bytes[..checksum_offset]) {
+            defmt::warn!("record in slot {} is corrupt, ignoring it", slot);
+            return None;
+        }
+        if version != expected_version {
+            defmt::warn!(
+                "record in slot {} has version {} instead of {}, ignoring it",
+                slot,
+                version,
+                expected_version
+            );
+            return None;
+        }
+
+        Some(&bytes[HEADER_SIZE..checksum_offset])
+    }
+
+    /// Write a record to the next free slot and return the slot.
+    fn write_record(&mut self, magic: u32, version: u16, payload: &[u8]) -> Result<usize, Error> {
+        let slot = self.next_free_slot.ok_or(Error::StorageFull)?;
+        self.write_slot(slot, magic, version, payload)?;
+        self.next_free_slot = if slot + 1 < SLOT_COUNT {
+            Some(slot + 1)
+        } else {
+            None
+        };
+        Ok(slot)
+    }
+
+    fn write_slot(
+        &mut self,
+        slot: usize,
+        magic: u32,
+        version: u16,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut record = [0xFF; SLOT_SIZE];
+        let checksum_offset = HEADER_SIZE + payload.len();
+        record[0..4].copy_from_slice(&magic.to_le_bytes());
+        record[4..6].copy_from_slice(&version.to_le_bytes());
+        record[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        record[HEADER_SIZE..checksum_offset].copy_from_slice(payload);
+        let checksum = crc32(&record[..checksum_offset]);
+        record[checksum_offset..checksum_offset + CHECKSUM_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+
+        self.flash
+            .unlocked()
+            .program(
+                CONFIG_SECTOR_OFFSET + slot * SLOT_SIZE,
+                record[..checksum_offset + CHECKSUM_SIZE].iter(),
+            )
+            .map_err(|e| {
+                defmt::error!("failed to program flash: {}", defmt::Debug2Format(&e));
+                event_log::record(Event::StorageError);
+                Error::FlashError
+            })
+    }
+}
+
+/// Standard CRC-32 (as used by e.g. Ethernet and zlib), calculated bitwise to avoid the need for a lookup table.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,91 @@
+//! The errors which can happen while the robotcar is running and how it reacts to them.
+//!
+//! All runtime paths report their errors as [`Error`]. How the car reacts to an error is decided centrally by
+//! [`Error::reaction`] (and applied by `Car::handle_error`) instead of every caller deciding on its own.
+
+use crate::arbiter::CommandSource;
+use crate::config;
+use crate::steering;
+use defmt::Format;
+use tb6612fng::DriveError;
+
+/// Errors which can potentially happen while the car is running.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum Error {
+    /// An attempt was made to drive forward but this is currently prohibited (collision avoidance).
+    NotAllowedToDriveForward,
+    /// The car would get (further) outside of the geofence of the drive profile, see `DriveLimits`.
+    OutsideGeofence,
+    /// The command hasn't been executed as a source with a higher priority is in control of the car (see `arbiter`).
+    CommandOverridden(CommandSource),
+    /// The car can't drive while the steering is being calibrated.
+    SteeringCalibrationActive,
+    /// The steering calibration can only be changed while the calibration is active.
+    SteeringCalibrationNotActive,
+    /// A target speed (in mm/s) can only be set if the car has a wheel encoder.
+    SpeedControlNotAvailable,
+    /// The battery is (nearly) empty, the car can't drive anymore.
+    BatteryCritical,
+    /// Something went wrong in the underlying motor control library. See the attached error for further details.
+    Drive(DriveError),
+    /// Something went wrong while steering. See the attached error for further details.
+    Steering(steering::Error),
+    /// The communication with the display failed (e.g. because it has been unplugged).
+    Display,
+    /// The communication with the distance sensor failed.
+    DistanceSensor,
+    /// The line sensor couldn't be read.
+    LineSensor,
+    /// The communication with the IMU failed.
+    Imu,
+    /// The communication with the remote control failed. See the attached error for further details.
+    Transport(TransportError),
+    /// Something couldn't be persisted. See the attached error for further details.
+    Storage(config::Error),
+}
+
+/// Errors in the communication with the remote control (via the bluetooth module).
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum TransportError {
+    /// The USART connected to the bluetooth module couldn't be set up.
+    Setup,
+    /// A message couldn't be received from the bluetooth module.
+    Receive,
+    /// Data couldn't be sent to the bluetooth module.
+    Send,
+}
+
+/// How the car reacts to an error.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum Reaction {
+    /// The request has been rejected, nothing else needs to be done (the user can try again later).
+    Reject,
+    /// The error is transient, the operation is retried (e.g. by the next run of a periodic task).
+    Retry,
+    /// The affected component isn't needed to drive safely, it is disabled until it works again.
+    Degrade,
+    /// The car can't be controlled safely anymore and has to stop.
+    Halt,
+}
+
+impl Error {
+    /// The central policy on how the car reacts to the error.
+    pub fn reaction(&self) -> Reaction {
+        match self {
+            Error::NotAllowedToDriveForward
+            | Error::CommandOverridden(_)
+            | Error::OutsideGeofence
+            | Error::SteeringCalibrationActive
+            | Error::SteeringCalibrationNotActive
+            | Error::SpeedControlNotAvailable
+            | Error::BatteryCritical
+            | Error::Steering(_)
+            | Error::Storage(_) => Reaction::Reject,
+            Error::DistanceSensor
+            | Error::LineSensor
+            | Error::Transport(TransportError::Receive | TransportError::Send) => Reaction::Retry,
+            Error::Display | Error::Imu => Reaction::Degrade,
+            Error::Drive(_) | Error::Transport(TransportError::Setup) => Reaction::Halt,
+        }
+    }
+}
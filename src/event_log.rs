@@ -0,0 +1,548 @@
+//! A log of the important events (state changes, emergency stops, errors, etc.) of the robotcar.
+//!
+//! Without a debug probe attached the `defmt` output is lost, which makes it hard to find out why the car
+//! stopped or reset. Thus, the events are additionally recorded in a ring buffer which can be dumped over
+//! bluetooth (see `Telemetry::send_event`).
+//!
+//! The ring buffer is placed in RAM which isn't initialised during the boot, thus it survives a reset of the
+//! microcontroller (but not a power cycle). After a crash (a watchdog reset or a panic) the last events before the
+//! reset are persisted as a [`CrashRecord`] (see `ConfigStore::store_crash_record`).
+
+use crate::arbiter::CommandSource;
+use crate::battery::BatteryState;
+use crate::car::CarState;
+use crate::drive_profile::DriveProfile;
+use crate::reset_cause::ResetCause;
+use crate::supervisor::SupervisedTask;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use cortex_m::interrupt;
+use defmt::Format;
+
+/// The number of events kept in RAM. Older events are overwritten.
+const EVENT_LOG_SIZE: usize = 64;
+/// The number of (latest) events which are persisted in a [`CrashRecord`].
+pub const CRASH_RECORD_SIZE: usize = 16;
+/// The max. length (in bytes) of the file name kept in a [`PanicLocation`]. Longer paths are shortened at the start.
+const PANIC_FILE_SIZE: usize = 32;
+
+/// Marks the event log in RAM as initialised (i.e. it survived a reset).
+/// Needs to be changed every time the layout of the log or the encoding of the events changes.
+const EVENT_LOG_MAGIC: u32 = 0x4556_4C33; // "EVL3"
+
+/// Why the car did an emergency stop.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum EmergencyStopReason {
+    /// The obstacle in front is too close.
+    Obstacle,
+    /// The obstacle in front is approaching too fast.
+    TimeToCollision,
+    /// The distance sensor didn't deliver new data in time.
+    DistanceSensorLag,
+    /// No distance data is available (e.g. after the distance sensor has been reset).
+    NoDistanceData,
+    /// The battery is (nearly) empty.
+    BatteryCritical,
+}
+
+/// An event which is recorded in the log.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum Event {
+    /// The microcontroller has booted after a reset with the given cause.
+    Boot(ResetCause),
+    /// The state of the car has changed.
+    StateChanged(CarState),
+    EmergencyStop(EmergencyStopReason),
+    /// A measurement of the distance sensor failed.
+    DistanceSensorError,
+    /// The distance sensor has been reset.
+    DistanceSensorReset,
+    /// The I2C bus had to be recovered.
+    I2cBusRecovered,
+    BatteryStateChanged(BatteryState),
+    SteeringCalibrationStarted,
+    SteeringCalibrationFinished,
+    /// Storing something in the flash failed.
+    StorageError,
+    /// The car only runs in a degraded mode since it kept crashing, see `ResetInfo::requires_degraded_mode`.
+    DegradedMode,
+    /// A supervised task didn't check in within its deadline, the watchdog will reset the car.
+    TaskMissedDeadline(SupervisedTask),
+    /// The firmware panicked, see [`PanicLocation`] for where.
+    Panic,
+    /// The display has been disabled due to an error, see `Car::handle_error`.
+    DisplayDisabled,
+    /// The car has been stopped due to an error, see `Car::handle_error`.
+    HaltedOnError,
+    /// The IMU has been disabled due to an error, see `Car::handle_error`.
+    ImuDisabled,
+    /// A manoeuvre sequence has been started, see `ManoeuvrePlayer`.
+    ManoeuvreStarted,
+    /// The manoeuvre sequence has been played to the end.
+    ManoeuvreFinished,
+    /// The manoeuvre sequence has been aborted (by the user, the collision avoidance or an error).
+    ManoeuvreAborted,
+    /// The car started to follow the wall, see `WallFollower`.
+    WallFollowingStarted,
+    /// The car stopped following the wall (by the user, the collision avoidance, an error or because the wall is lost).
+    WallFollowingStopped,
+    /// The car started to follow the target in front, see `TargetFollower`.
+    FollowMeStarted,
+    /// The car stopped following the target (by the user, an error or because the target is lost).
+    FollowMeStopped,
+    /// The car started to follow the line, see `LineFollower`.
+    LineFollowingStarted,
+    /// The car stopped following the line (by the user, the collision avoidance, an error or because the line is lost).
+    LineFollowingStopped,
+    /// Another source is in control of the car (`None` if it is idle), see `arbiter`.
+    ControlChanged(Option<CommandSource>),
+    /// Another drive profile has been selected, see `Car::set_drive_profile`.
+    DriveProfileChanged(DriveProfile),
+    /// The car has left the geofence of the drive profile, see `DriveLimits::geofence_radius_in_mm`.
+    GeofenceReached,
+    /// The user started (`true`) or stopped (`false`) to override the collision avoidance, see
+    /// `Car::set_collision_override`.
+    CollisionOverride(bool),
+}
+
+impl Event {
+    /// Encode the event as `u32`: the variant in the upper byte and its data (if any) in the lower bytes.
+    fn to_raw(self) -> u32 {
+        let (tag, data) = match self {
+            Event::Boot(cause) => (
+                1,
+                match cause {
+                    ResetCause::PowerOn => 0,
+                    ResetCause::BrownOut => 1,
+                    ResetCause::ResetPin => 2,
+                    ResetCause::Software => 3,
+                    ResetCause::Watchdog => 4,
+                    ResetCause::WindowWatchdog => 5,
+                    ResetCause::LowPower => 6,
+                    ResetCause::Unknown => 7,
+                    ResetCause::Panic => 8,
+                },
+            ),
+            Event::StateChanged(state) => (
+                2,
+                match state {
+                    CarState::Normal => 0,
+                    CarState::ForwardDistanceInvalid => 1,
+                },
+            ),
+            Event::EmergencyStop(reason) => (
+                3,
+                match reason {
+                    EmergencyStopReason::Obstacle => 0,
+                    EmergencyStopReason::TimeToCollision => 1,
+                    EmergencyStopReason::DistanceSensorLag => 2,
+                    EmergencyStopReason::NoDistanceData => 3,
+                    EmergencyStopReason::BatteryCritical => 4,
+                },
+            ),
+            Event::DistanceSensorError => (4, 0),
+            Event::DistanceSensorReset => (5, 0),
+            Event::I2cBusRecovered => (6, 0),
+            Event::BatteryStateChanged(state) => (
+                7,
+                match state {
+                    BatteryState::Ok => 0,
+                    BatteryState::Low => 1,
+                    BatteryState::Critical => 2,
+                },
+            ),
+            Event::SteeringCalibrationStarted => (8, 0),
+            Event::SteeringCalibrationFinished => (9, 0),
+            Event::StorageError => (10, 0),
+            Event::DegradedMode => (11, 0),
+            Event::TaskMissedDeadline(task) => (
+                12,
+                match task {
+                    SupervisedTask::ValidateDistance => 0,
+                    SupervisedTask::Display => 1,
+                    SupervisedTask::Telemetry => 2,
+                },
+            ),
+            Event::Panic => (13, 0),
+            Event::DisplayDisabled => (14, 0),
+            Event::HaltedOnError => (15, 0),
+            Event::ImuDisabled => (16, 0),
+            Event::ManoeuvreStarted => (17, 0),
+            Event::ManoeuvreFinished => (18, 0),
+            Event::ManoeuvreAborted => (19, 0),
+            Event::WallFollowingStarted => (20, 0),
+            Event::WallFollowingStopped => (21, 0),
+            Event::FollowMeStarted => (22, 0),
+            Event::FollowMeStopped => (23, 0),
+            Event::LineFollowingStarted => (24, 0),
+            Event::LineFollowingStopped => (25, 0),
+            Event::ControlChanged(source) => (
+                26,
+                match source {
+                    None => 0,
+                    Some(CommandSource::Autonomous) => 1,
+                    Some(CommandSource::Manual) => 2,
+                    Some(CommandSource::Safety) => 3,
+                },
+            ),
+            Event::DriveProfileChanged(profile) => (
+                27,
+                match profile {
+                    DriveProfile::Normal => 0,
+                    DriveProfile::Kid => 1,
+                },
+            ),
+            Event::GeofenceReached => (28, 0),
+            Event::CollisionOverride(active) => (29, active as u32),
+        };
+        (tag << 24) | data
+    }
+
+    /// Decode an event encoded with [`Event::to_raw`]. Returns `None` if it isn't a valid event.
+    fn from_raw(raw: u32) -> Option<Event> {
+        let data = raw & 0x00FF_FFFF;
+        let event = match (raw >> 24, data) {
+            (1, 0) => Event::Boot(ResetCause::PowerOn),
+            (1, 1) => Event::Boot(ResetCause::BrownOut),
+            (1, 2) => Event::Boot(ResetCause::ResetPin),
+            (1, 3) => Event::Boot(ResetCause::Software),
+            (1, 4) => Event::Boot(ResetCause::Watchdog),
+            (1, 5) => Event::Boot(ResetCause::WindowWatchdog),
+            (1, 6) => Event::Boot(ResetCause::LowPower),
+            (1, 7) => Event::Boot(ResetCause::Unknown),
+            (1, 8) => Event::Boot(ResetCause::Panic),
+            (2, 0) => Event::StateChanged(CarState::Normal),
+            (2, 1) => Event::StateChanged(CarState::ForwardDistanceInvalid),
+            (3, 0) => Event::EmergencyStop(EmergencyStopReason::Obstacle),
+            (3, 1) => Event::EmergencyStop(EmergencyStopReason::TimeToCollision),
+            (3, 2) => Event::EmergencyStop(EmergencyStopReason::DistanceSensorLag),
+            (3, 3) => Event::EmergencyStop(EmergencyStopReason::NoDistanceData),
+            (3, 4) => Event::EmergencyStop(EmergencyStopReason::BatteryCritical),
+            (4, 0) => Event::DistanceSensorError,
+            (5, 0) => Event::DistanceSensorReset,
+            (6, 0) => Event::I2cBusRecovered,
+            (7, 0) => Event::BatteryStateChanged(BatteryState::Ok),
+            (7, 1) => Event::BatteryStateChanged(BatteryState::Low),
+            (7, 2) => Event::BatteryStateChanged(BatteryState::Critical),
+            (8, 0) => Event::SteeringCalibrationStarted,
+            (9, 0) => Event::SteeringCalibrationFinished,
+            (10, 0) => Event::StorageError,
+            (11, 0) => Event::DegradedMode,
+            (12, 0) => Event::TaskMissedDeadline(SupervisedTask::ValidateDistance),
+            (12, 1) => Event::TaskMissedDeadline(SupervisedTask::Display),
+            (12, 2) => Event::TaskMissedDeadline(SupervisedTask::Telemetry),
+            (13, 0) => Event::Panic,
+            (14, 0) => Event::DisplayDisabled,
+            (15, 0) => Event::HaltedOnError,
+            (16, 0) => Event::ImuDisabled,
+            (17, 0) => Event::ManoeuvreStarted,
+            (18, 0) => Event::ManoeuvreFinished,
+            (19, 0) => Event::ManoeuvreAborted,
+            (20, 0) => Event::WallFollowingStarted,
+            (21, 0) => Event::WallFollowingStopped,
+            (22, 0) => Event::FollowMeStarted,
+            (23, 0) => Event::FollowMeStopped,
+            (24, 0) => Event::LineFollowingStarted,
+            (25, 0) => Event::LineFollowingStopped,
+            (26, 0) => Event::ControlChanged(None),
+            (26, 1) => Event::ControlChanged(Some(CommandSource::Autonomous)),
+            (26, 2) => Event::ControlChanged(Some(CommandSource::Manual)),
+            (26, 3) => Event::ControlChanged(Some(CommandSource::Safety)),
+            (27, 0) => Event::DriveProfileChanged(DriveProfile::Normal),
+            (27, 1) => Event::DriveProfileChanged(DriveProfile::Kid),
+            (28, 0) => Event::GeofenceReached,
+            (29, 0) => Event::CollisionOverride(false),
+            (29, 1) => Event::CollisionOverride(true),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// An event together with the time at which it happened.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct Entry {
+    /// Milliseconds since the boot during which the event happened.
+    pub timestamp_in_ms: u32,
+    pub event: Event,
+}
+
+/// Where the firmware panicked.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct PanicLocation {
+    /// The end of the path of the source file, padded with zeros.
+    file: [u8; PANIC_FILE_SIZE],
+    /// The line in the source file, `0` is used to mark that there is no panic location.
+    pub line: u32,
+}
+
+impl PanicLocation {
+    pub fn new(file: &str, line: u32) -> PanicLocation {
+        // keep the end of the path as it contains the file name (without splitting a character)
+        let start = file
+            .char_indices()
+            .map(|(index, _)| index)
+            .find(|index| file.len() - index <= PANIC_FILE_SIZE)
+            .unwrap_or(file.len());
+        let file = &file.as_bytes()[start..];
+        let mut location = PanicLocation {
+            file: [0; PANIC_FILE_SIZE],
+            line,
+        };
+        location.file[..file.len()].copy_from_slice(file);
+        location
+    }
+
+    /// The (end of the) path of the source file.
+    pub fn file(&self) -> &str {
+        let len = self
+            .file
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(PANIC_FILE_SIZE);
+        core::str::from_utf8(&self.file[..len]).unwrap_or("?")
+    }
+}
+
+impl Format for PanicLocation {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}:{}", self.file(), self.line)
+    }
+}
+
+/// The latest events before a crash (e.g. a watchdog reset or a panic), to be persisted.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct CrashRecord {
+    len: usize,
+    entries: [Entry; CRASH_RECORD_SIZE],
+    panic_location: Option<PanicLocation>,
+}
+
+impl CrashRecord {
+    /// Size of a serialized record: the number of entries followed by the timestamp & event of each entry and
+    /// the panic location (line & file, the line is `0` if there is none).
+    pub const SIZE: usize = 1 + CRASH_RECORD_SIZE * 8 + 4 + PANIC_FILE_SIZE;
+    /// Offset of the panic location in a serialized record.
+    const PANIC_LOCATION_OFFSET: usize = 1 + CRASH_RECORD_SIZE * 8;
+
+    /// The events, oldest first.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..self.len]
+    }
+
+    /// Where the firmware panicked, `None` if the crash wasn't caused by a panic.
+    pub fn panic_location(&self) -> Option<&PanicLocation> {
+        self.panic_location.as_ref()
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.len as u8;
+        for (entry, bytes) in self.entries().iter().zip(bytes[1..].chunks_exact_mut(8)) {
+            bytes[0..4].copy_from_slice(&entry.timestamp_in_ms.to_le_bytes());
+            bytes[4..8].copy_from_slice(&entry.event.to_raw().to_le_bytes());
+        }
+        if let Some(panic_location) = self.panic_location {
+            let bytes = &mut bytes[Self::PANIC_LOCATION_OFFSET..];
+            bytes[0..4].copy_from_slice(&panic_location.line.to_le_bytes());
+            bytes[4..].copy_from_slice(&panic_location.file);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<CrashRecord> {
+        if bytes.len() != Self::SIZE || bytes[0] as usize > CRASH_RECORD_SIZE {
+            return None;
+        }
+        let panic_location = &bytes[Self::PANIC_LOCATION_OFFSET..];
+        let panic_line = u32::from_le_bytes([
+            panic_location[0],
+            panic_location[1],
+            panic_location[2],
+            panic_location[3],
+        ]);
+        let mut record = CrashRecord {
+            len: bytes[0] as usize,
+            entries: [EMPTY_ENTRY; CRASH_RECORD_SIZE],
+            panic_location: (panic_line != 0).then(|| {
+                let mut location = PanicLocation {
+                    file: [0; PANIC_FILE_SIZE],
+                    line: panic_line,
+                };
+                location.file.copy_from_slice(&panic_location[4..]);
+                location
+            }),
+        };
+        for (entry, bytes) in record.entries[..record.len]
+            .iter_mut()
+            .zip(bytes[1..].chunks_exact(8))
+        {
+            *entry = Entry {
+                timestamp_in_ms: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                event: Event::from_raw(u32::from_le_bytes([
+                    bytes[4], bytes[5], bytes[6], bytes[7],
+                ]))?,
+            };
+        }
+        Some(record)
+    }
+}
+
+/// Placeholder for the unused entries of a [`CrashRecord`].
+const EMPTY_ENTRY: Entry = Entry {
+    timestamp_in_ms: 0,
+    event: Event::Boot(ResetCause::Unknown),
+};
+
+/// The raw event log as it is stored in RAM. Only consists of `u32`s, thus any content is valid memory-wise,
+/// but it needs to be validated before being used as it's not initialised during the boot.
+#[repr(C)]
+struct RawEventLog {
+    magic: u32,
+    /// The index of the entry which will be written next.
+    next: u32,
+    /// The number of entries in use.
+    len: u32,
+    /// The timestamp & the encoded event of each entry.
+    entries: [[u32; 2]; EVENT_LOG_SIZE],
+    /// See [`PanicLocation`], the line is `0` if the firmware didn't panic.
+    panic_line: u32,
+    panic_file: [u8; PANIC_FILE_SIZE],
+}
+
+impl RawEventLog {
+    fn is_valid(&self) -> bool {
+        self.magic == EVENT_LOG_MAGIC
+            && (self.next as usize) < EVENT_LOG_SIZE
+            && (self.len as usize) <= EVENT_LOG_SIZE
+    }
+
+    fn clear(&mut self) {
+        self.magic = EVENT_LOG_MAGIC;
+        self.next = 0;
+        self.len = 0;
+        self.panic_line = 0;
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.entries[self.next as usize] = [entry.timestamp_in_ms, entry.event.to_raw()];
+        self.next = (self.next + 1) % EVENT_LOG_SIZE as u32;
+        self.len = (self.len + 1).min(EVENT_LOG_SIZE as u32);
+    }
+
+    /// Get the entry at the given index (0 = oldest entry). Entries which can't be decoded are skipped.
+    fn get(&self, index: usize) -> Option<Option<Entry>> {
+        if index >= self.len as usize {
+            return None;
+        }
+        let first = (self.next as usize + EVENT_LOG_SIZE - self.len as usize) % EVENT_LOG_SIZE;
+        let [timestamp_in_ms, raw_event] = self.entries[(first + index) % EVENT_LOG_SIZE];
+        Some(Event::from_raw(raw_event).map(|event| Entry {
+            timestamp_in_ms,
+            event,
+        }))
+    }
+}
+
+/// Wrapper to be able to place the event log in a `static`. All accesses happen within a critical section.
+struct EventLogCell(UnsafeCell<MaybeUninit<RawEventLog>>);
+
+// SAFETY: the content is only accessed within a critical section on a single-core system, see `with_event_log`.
+#[allow(unsafe_code)]
+unsafe impl Sync for EventLogCell {}
+
+/// Placed in the `.uninit` section so that it isn't initialised (= cleared) during the boot.
+#[allow(unsafe_code)]
+#[link_section = ".uninit.EVENT_LOG"]
+static EVENT_LOG: EventLogCell = EventLogCell(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Run `f` with exclusive access to the event log.
+#[allow(unsafe_code)]
+fn with_event_log<R>(f: impl FnOnce(&mut RawEventLog) -> R) -> R {
+    interrupt::free(|_| {
+        // SAFETY: we're in a critical section on a single-core system, so nothing else can access the log
+        // at the same time. The log only consists of `u32`s, thus any bit pattern is a valid value (the
+        // content is validated by `init` before it's used).
+        let event_log = unsafe { &mut *EVENT_LOG.0.get().cast::<RawEventLog>() };
+        f(event_log)
+    })
+}
+
+/// Initialise the event log. Needs to be called during the boot before any event is recorded.
+/// If the log survived the reset its entries are kept, otherwise it is cleared.
+pub fn init() {
+    with_event_log(|event_log| {
+        if event_log.is_valid() {
+            defmt::info!(
+                "event log survived the reset, it contains {} entries",
+                event_log.len
+            );
+        } else {
+            event_log.clear();
+        }
+    });
+}
+
+/// Record an event. The timestamp is taken from the monotonic timer.
+pub fn record(event: Event) {
+    let timestamp_in_ms = crate::app::monotonics::now()
+        .duration_since_epoch()
+        .to_millis();
+    defmt::debug!("event: {}", event);
+    with_event_log(|event_log| {
+        event_log.push(Entry {
+            timestamp_in_ms,
+            event,
+        })
+    });
+}
+
+/// Get the entry at the given index (0 = oldest entry). Returns `None` once the index is beyond the last entry
+/// and `Some(None)` for entries which couldn't be decoded (e.g. if the log got corrupted).
+pub fn get(index: usize) -> Option<Option<Entry>> {
+    with_event_log(|event_log| event_log.get(index))
+}
+
+/// Record that the firmware panicked and where. The location is kept until it's taken with
+/// [`take_panic_location`] (after the reset).
+pub fn record_panic(location: PanicLocation) {
+    with_event_log(|event_log| {
+        event_log.panic_line = location.line;
+        event_log.panic_file = location.file;
+    });
+    record(Event::Panic);
+}
+
+/// Take the location of the panic which caused the last reset (if any) out of the log.
+pub fn take_panic_location() -> Option<PanicLocation> {
+    with_event_log(|event_log| {
+        if event_log.panic_line == 0 {
+            return None;
+        }
+        let location = PanicLocation {
+            file: event_log.panic_file,
+            line: event_log.panic_line,
+        };
+        event_log.panic_line = 0;
+        Some(location)
+    })
+}
+
+/// The latest events in the log together with the location of the panic (if any), to be persisted after a crash.
+pub fn crash_record(panic_location: Option<PanicLocation>) -> CrashRecord {
+    with_event_log(|event_log| {
+        let mut record = CrashRecord {
+            len: 0,
+            entries: [EMPTY_ENTRY; CRASH_RECORD_SIZE],
+            panic_location,
+        };
+        let skip = (event_log.len as usize).saturating_sub(CRASH_RECORD_SIZE);
+        for index in skip..event_log.len as usize {
+            if let Some(Some(entry)) = event_log.get(index) {
+                record.entries[record.len] = entry;
+                record.len += 1;
+            }
+        }
+        record
+    })
+}
@@ -0,0 +1,137 @@
+//! Follows a target (e.g. a person or another car) in front of the car at a constant distance.
+//!
+//! The filtered front distance (see `Car::handle_distance_sensor_interrupt`) is kept at [`FOLLOW_DISTANCE_IN_MM`]
+//! by a PI controller which sets the speed: the car drives forward if the target is further away and reverses gently
+//! if it comes too close. The collision avoidance still applies: if the target is closer than the minimum front
+//! distance the car can only reverse. If the target is lost the car stops.
+
+use crate::arbiter::{Command, CommandSource, Motion, AUTONOMOUS_COMMAND_TIMEOUT_IN_MS};
+use crate::error::Error;
+use crate::event_log::{self, Event};
+use crate::pid::{PidController, PidGains};
+use crate::steering::Direction;
+use crate::CarT as Car;
+
+/// The distance to the target which is kept.
+const FOLLOW_DISTANCE_IN_MM: u16 = 800;
+/// If there is nothing closer than this the target is considered to be lost.
+const MAX_TARGET_DISTANCE_IN_MM: u16 = 1_500;
+/// The maximum speed (in percentage) while following. Above 50% the distance sensor would switch to its short range
+/// profile (see `HIGH_SPEED_THRESHOLD` in `car`) and the target would be lost.
+const MAX_FORWARD_SPEED: i32 = 50;
+/// The maximum speed (in percentage) when reversing because the target came too close.
+const MAX_BACKWARDS_SPEED: i32 = 25;
+
+/// The gains of the controller (output: speed in percentage; input: distance in mm).
+/// The I-term is needed to overcome the dead band of the motor.
+const FOLLOW_ME_GAINS: PidGains = PidGains {
+    kp: 200,
+    ki: 100,
+    kd: 0,
+};
+
+/// Follows the target in front of the car. [`TargetFollower::update`] needs to be called periodically while it is
+/// running.
+pub struct TargetFollower {
+    controller: PidController,
+    running: bool,
+    last_update: Option<fugit::TimerInstantU32<1_000_000>>,
+}
+
+impl Default for TargetFollower {
+    fn default() -> Self {
+        TargetFollower {
+            controller: PidController::new(FOLLOW_ME_GAINS, MAX_FORWARD_SPEED),
+            running: false,
+            last_update: None,
+        }
+    }
+}
+
+impl TargetFollower {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Start following the target, this is only possible if there is a target in range.
+    /// The car starts driving with the next update.
+    pub fn start(&mut self, car: &Car) {
+        if !is_target_in_range(car.front_distance_in_mm()) {
+            defmt::warn!("no target in range, can't follow it");
+            return;
+        }
+        defmt::info!("starting to follow the target");
+        event_log::record(Event::FollowMeStarted);
+        self.controller.reset();
+        self.last_update = None;
+        self.running = true;
+    }
+
+    /// Stop following the target and stop the car.
+    pub fn stop(&mut self, car: &mut Car) {
+        if self.running {
+            defmt::info!("stopped following the target");
+            event_log::record(Event::FollowMeStopped);
+            self.running = false;
+            car.release_command(CommandSource::Autonomous);
+        }
+    }
+
+    /// Adjust the speed based on the current distance to the target. Returns whether the car is still following it.
+    pub fn update(&mut self, car: &mut Car, now: fugit::TimerInstantU32<1_000_000>) -> bool {
+        if !self.running {
+            return false;
+        }
+        let distance_in_mm = match car.front_distance_in_mm() {
+            Some(distance_in_mm) if is_target_in_range(Some(distance_in_mm)) => distance_in_mm,
+            _ => {
+                defmt::warn!("lost the target");
+                self.stop(car);
+                return false;
+            }
+        };
+
+        let dt_in_ms = self
+            .last_update
+            .replace(now)
+            .and_then(|last_update| now.checked_duration_since(last_update))
+            .map_or(0, |dt| dt.to_millis());
+        // the controller reduces the measurement towards the setpoint, but the car has to drive towards the target
+        // (i.e. forward) to reduce the distance, thus the output is inverted.
+        let speed = -self
+            .controller
+            .update(
+                FOLLOW_DISTANCE_IN_MM as i32,
+                distance_in_mm as i32,
+                dt_in_ms,
+            )
+            .clamp(-MAX_FORWARD_SPEED, MAX_BACKWARDS_SPEED);
+        defmt::trace!("target distance: {}mm => speed {}%", distance_in_mm, speed);
+
+        let command = Command {
+            motion: Motion::Duty(speed as i8),
+            steering: Direction::Centre,
+        };
+        match car.submit_command(
+            CommandSource::Autonomous,
+            command,
+            Some(AUTONOMOUS_COMMAND_TIMEOUT_IN_MS),
+            now,
+        ) {
+            Ok(()) => true,
+            Err(Error::NotAllowedToDriveForward) => {
+                // the target is too close (the collision avoidance stopped the car), wait until it moves away again.
+                true
+            }
+            Err(e) => {
+                car.handle_error(e);
+                self.stop(car);
+                false
+            }
+        }
+    }
+}
+
+fn is_target_in_range(distance_in_mm: Option<u16>) -> bool {
+    matches!(distance_in_mm, Some(distance_in_mm) if distance_in_mm <= MAX_TARGET_DISTANCE_IN_MM)
+}
@@ -0,0 +1,356 @@
+//! An I2C bus which can recover from faults.
+//!
+//! A slave can get stuck in the middle of a transfer (e.g. due to a glitch on the bus or a reset of the
+//! microcontroller during a transfer) and keep SDA low, blocking the bus for everyone. Since the slave
+//! isn't reset together with the microcontroller, this used to require a power cycle.
+//! To recover from this the bus is cleared by clocking out SCL pulses until the slave releases SDA,
+//! followed by a STOP condition. This is done on every boot and whenever a transfer fails in a way
+//! which indicates a problem with the bus. Afterwards the I2C peripheral is re-initialised.
+//!
+//! The I2C driver of the HAL waits forever if the bus is stuck (e.g. for the START condition while SDA is held
+//! low), thus the transfers are implemented here with a timeout on every wait (based on the DWT cycle counter).
+//!
+//! Note that the devices on the bus might have lost their state and need to be re-initialised by
+//! their owners, see [`recovery_count`].
+
+use crate::event_log::{self, Event};
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use fugit::HertzU32;
+use stm32f4xx_hal::{
+    gpio::{Alternate, OpenDrain, PB8, PB9},
+    i2c::{Error, I2c, NoAcknowledgeSource},
+    pac::{i2c1, I2C1},
+    rcc::Clocks,
+};
+
+/// The frequency used for the I2C bus (fast mode).
+const I2C_FREQUENCY: HertzU32 = HertzU32::from_raw(400_000);
+/// The frequency used to clock out the SCL pulses while clearing the bus (standard mode, to be on the safe side).
+const BUS_CLEAR_FREQUENCY: u32 = 100_000;
+/// A slave can be at most in the middle of a byte plus the ACK bit, thus 9 clock pulses are enough to release it.
+const BUS_CLEAR_PULSES: u8 = 9;
+/// The maximum time to wait for a single step of a transfer (START, address, byte, STOP). A byte takes 22.5µs at
+/// 400kHz, thus this leaves plenty of room for clock stretching by the slaves.
+const I2C_TIMEOUT_IN_US: u32 = 1_000;
+
+/// The number of times the bus has been recovered since the boot (not counting the clearing during the boot).
+static RECOVERY_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The number of times the bus has been recovered since the boot. The devices on the bus should be
+/// re-initialised every time this changes.
+pub fn recovery_count() -> u32 {
+    RECOVERY_COUNT.load(Ordering::Relaxed)
+}
+
+/// Nothing can be read into an empty buffer. This is a bug of the caller and not a bus fault, thus it's rejected before
+/// anything is sent on the bus (and without a recovery of the bus).
+fn check_buffer(buffer: &[u8]) -> Result<(), Error> {
+    if buffer.is_empty() {
+        defmt::error!("I2C read with an empty buffer");
+        return Err(Error::NoAcknowledge(NoAcknowledgeSource::Unknown));
+    }
+    Ok(())
+}
+
+type Scl = PB8<Alternate<4, OpenDrain>>;
+type Sda = PB9<Alternate<4, OpenDrain>>;
+
+/// I2C1 on PB8 (SCL) & PB9 (SDA) with automatic recovery from bus faults.
+pub struct RecoverableI2c {
+    /// Only `None` while the bus is being recovered.
+    i2c: Option<BoundedI2c>,
+    clocks: Clocks,
+}
+
+impl RecoverableI2c {
+    /// Set up the I2C bus. The bus will be cleared first, in case a slave is still stuck from before the reset.
+    /// The DWT cycle counter must already be enabled, it's needed for the timeouts.
+    pub fn new(i2c: I2C1, pins: (PB8, PB9), clocks: &Clocks) -> RecoverableI2c {
+        assert!(
+            DWT::cycle_counter_enabled(),
+            "the I2C timeouts need the DWT cycle counter"
+        );
+        let pins = clear_bus(pins, clocks);
+        RecoverableI2c {
+            i2c: Some(BoundedI2c::new(i2c, pins, clocks)),
+            clocks: *clocks,
+        }
+    }
+
+    /// Clear the bus and re-initialise the I2C peripheral.
+    fn recover(&mut self) {
+        if let Some(i2c) = self.i2c.take() {
+            let (i2c, pins) = i2c.release();
+            let pins = clear_bus(pins, &self.clocks);
+            self.i2c = Some(BoundedI2c::new(i2c, pins, &self.clocks));
+
+            let recovery_count = RECOVERY_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            defmt::warn!("I2C bus recovered ({} recoveries so far)", recovery_count);
+            event_log::record(Event::I2cBusRecovered);
+        }
+    }
+
+    /// Run a transfer on the bus and recover the bus if the transfer failed due to a bus fault.
+    fn transfer<T>(
+        &mut self,
+        f: impl FnOnce(&mut BoundedI2c) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let i2c = self
+            .i2c
+            .as_mut()
+            .expect("I2C is only unavailable during the recovery");
+        let result = f(i2c);
+
+        match result {
+            Ok(_) => {}
+            // the addressed device didn't respond (e.g. because it isn't present or busy), this is up to its owner.
+            // a stuck bus results in a timeout instead.
+            Err(Error::NoAcknowledge(_)) => {}
+            Err(e) => {
+                defmt::error!(
+                    "I2C bus fault ({}), recovering the bus",
+                    defmt::Debug2Format(&e)
+                );
+                self.recover();
+            }
+        }
+
+        result
+    }
+}
+
+impl Write for RecoverableI2c {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.transfer(|i2c| i2c.write(address, bytes))
+    }
+}
+
+impl Read for RecoverableI2c {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        check_buffer(buffer)?;
+        self.transfer(|i2c| i2c.read(address, buffer))
+    }
+}
+
+impl WriteRead for RecoverableI2c {
+    type Error = Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_buffer(buffer)?;
+        self.transfer(|i2c| i2c.write_read(address, bytes, buffer))
+    }
+}
+
+/// An I2C master which gives up with [`Error::Timeout`] instead of waiting forever if the bus is stuck.
+///
+/// The transfers follow the ones of the HAL, which is only used to configure the peripheral.
+struct BoundedI2c {
+    i2c: I2C1,
+    pins: (Scl, Sda),
+    /// [`I2C_TIMEOUT_IN_US`] in cycles of the DWT cycle counter.
+    timeout_in_cycles: u32,
+}
+
+impl BoundedI2c {
+    fn new(i2c: I2C1, (scl, sda): (PB8, PB9), clocks: &Clocks) -> BoundedI2c {
+        let pins = (
+            scl.into_alternate_open_drain(),
+            sda.into_alternate_open_drain(),
+        );
+        // the pins are already in the alternate mode, thus the HAL keeps them like this when it is released.
+        let (i2c, pins) = I2c::new(i2c, pins, I2C_FREQUENCY, clocks).release();
+        BoundedI2c {
+            i2c,
+            pins,
+            timeout_in_cycles: clocks.sysclk().raw() / 1_000_000 * I2C_TIMEOUT_IN_US,
+        }
+    }
+
+    fn release(self) -> (I2C1, (PB8, PB9)) {
+        let (scl, sda) = self.pins;
+        (self.i2c, (scl.into_input(), sda.into_input()))
+    }
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.start_write(address)?;
+        self.write_bytes(bytes)?;
+        self.stop()
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let (last, buffer) = buffer
+            .split_last_mut()
+            .ok_or(Error::NoAcknowledge(NoAcknowledgeSource::Unknown))?;
+        self.start_read(address)?;
+        for byte in buffer {
+            *byte = self.read_byte()?;
+        }
+        // send a NACK and then a STOP after the last byte
+        self.i2c
+            .cr1
+            .modify(|_, w| w.ack().clear_bit().stop().set_bit());
+        *last = self.read_byte()?;
+        self.wait_for(|i2c| Ok(i2c.cr1.read().stop().bit_is_clear()))
+    }
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.start_write(address)?;
+        self.write_bytes(bytes)?;
+        // repeated START
+        self.read(address, buffer)
+    }
+
+    /// Send a START condition followed by the address (for writing).
+    fn start_write(&mut self, address: u8) -> Result<(), Error> {
+        self.i2c.cr1.modify(|_, w| w.start().set_bit());
+        self.send_address(address << 1)
+    }
+
+    /// Send a START condition followed by the address (for reading).
+    fn start_read(&mut self, address: u8) -> Result<(), Error> {
+        self.i2c
+            .cr1
+            .modify(|_, w| w.start().set_bit().ack().set_bit());
+        self.send_address((address << 1) | 1)
+    }
+
+    fn send_address(&mut self, address: u8) -> Result<(), Error> {
+        // wait until the START condition has been generated and we're the master
+        self.wait_for(|i2c| Ok(check_and_clear_error_flags(i2c)?.sb().bit_is_set()))?;
+        self.wait_for(|i2c| {
+            check_and_clear_error_flags(i2c)?;
+            let sr2 = i2c.sr2.read();
+            Ok(sr2.msl().bit_is_set() || sr2.busy().bit_is_set())
+        })?;
+
+        self.i2c.dr.write(|w| w.dr().bits(address));
+        self.wait_for(|i2c| Ok(check_and_clear_error_flags(i2c)?.addr().bit_is_set()))
+            .map_err(|e| self.abort(e, NoAcknowledgeSource::Address))?;
+        // clear the ADDR flag by reading SR2
+        self.i2c.sr2.read();
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for byte in bytes {
+            self.wait_for(|i2c| Ok(check_and_clear_error_flags(i2c)?.tx_e().bit_is_set()))
+                .map_err(|e| self.abort(e, NoAcknowledgeSource::Data))?;
+            self.i2c.dr.write(|w| w.dr().bits(*byte));
+            self.wait_for(|i2c| Ok(check_and_clear_error_flags(i2c)?.btf().bit_is_set()))
+                .map_err(|e| self.abort(e, NoAcknowledgeSource::Data))?;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        self.wait_for(|i2c| Ok(check_and_clear_error_flags(i2c)?.rx_ne().bit_is_set()))?;
+        Ok(self.i2c.dr.read().dr().bits())
+    }
+
+    /// Send a STOP condition and wait until it has been sent.
+    fn stop(&mut self) -> Result<(), Error> {
+        self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+        self.wait_for(|i2c| Ok(i2c.cr1.read().stop().bit_is_clear()))
+    }
+
+    /// Release the bus after the slave didn't acknowledge and tell the source of the NACK.
+    fn abort(&mut self, error: Error, source: NoAcknowledgeSource) -> Error {
+        match error {
+            Error::NoAcknowledge(_) => {
+                self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+                Error::NoAcknowledge(source)
+            }
+            e => e,
+        }
+    }
+
+    /// Wait until the `condition` is met, fails with [`Error::Timeout`] if this takes longer than [`I2C_TIMEOUT_IN_US`].
+    fn wait_for(
+        &self,
+        mut condition: impl FnMut(&I2C1) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let start = DWT::cycle_count();
+        while !condition(&self.i2c)? {
+            if DWT::cycle_count().wrapping_sub(start) > self.timeout_in_cycles {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the error flags of the I2C peripheral and clear the one which is reported (if any).
+fn check_and_clear_error_flags(i2c: &I2C1) -> Result<i2c1::sr1::R, Error> {
+    let sr1 = i2c.sr1.read();
+    if sr1.timeout().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.timeout().clear_bit());
+        return Err(Error::Timeout);
+    }
+    if sr1.pecerr().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.pecerr().clear_bit());
+        return Err(Error::Crc);
+    }
+    if sr1.ovr().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.ovr().clear_bit());
+        return Err(Error::Overrun);
+    }
+    if sr1.af().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.af().clear_bit());
+        return Err(Error::NoAcknowledge(NoAcknowledgeSource::Unknown));
+    }
+    if sr1.arlo().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.arlo().clear_bit());
+        return Err(Error::ArbitrationLoss);
+    }
+    // the errata says that BERR can be detected incorrectly, it should be ignored (as the HAL does it).
+    if sr1.berr().bit_is_set() {
+        i2c.sr1.modify(|_, w| w.berr().clear_bit());
+    }
+    Ok(sr1)
+}
+
+/// Clear the bus by bit-banging: clock SCL until the slave releases SDA and then generate a STOP condition.
+fn clear_bus((mut scl, mut sda): (PB8, PB9), clocks: &Clocks) -> (PB8, PB9) {
+    let half_period_in_cycles = clocks.sysclk().raw() / BUS_CLEAR_FREQUENCY / 2;
+    let wait = || cortex_m::asm::delay(half_period_in_cycles);
+
+    scl.with_open_drain_output_in_state(true.into(), |scl| {
+        sda.with_open_drain_output_in_state(true.into(), |sda| {
+            for _ in 0..BUS_CLEAR_PULSES {
+                if sda.is_high() {
+                    break;
+                }
+                scl.set_low();
+                wait();
+                scl.set_high();
+                wait();
+            }
+            if sda.is_low() {
+                defmt::error!("I2C: SDA is still held low after clearing the bus");
+            }
+
+            // STOP condition: SDA goes high while SCL is high
+            scl.set_low();
+            wait();
+            sda.set_low();
+            wait();
+            scl.set_high();
+            wait();
+            sda.set_high();
+            wait();
+        })
+    });
+
+    (scl, sda)
+}
@@ -0,0 +1,113 @@
+//! A minimal driver for the gyroscope of the IMU ([MPU6050](https://invensense.tdk.com/products/motion-tracking/6-axis/mpu-6050/)).
+//!
+//! Only the yaw rate (rotation around the z-axis) is needed to estimate the heading of the car (see `pose`), thus
+//! this only reads the z-axis of the gyroscope instead of pulling in a full driver.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+/// The I2C address of the MPU6050 (AD0 is pulled low on the Adafruit board).
+const MPU6050_ADDRESS: u8 = 0x68;
+const REGISTER_SMPLRT_DIV: u8 = 0x19;
+const REGISTER_CONFIG: u8 = 0x1A;
+const REGISTER_GYRO_CONFIG: u8 = 0x1B;
+const REGISTER_GYRO_ZOUT_H: u8 = 0x47;
+const REGISTER_PWR_MGMT_1: u8 = 0x6B;
+const REGISTER_WHO_AM_I: u8 = 0x75;
+/// The content of `WHO_AM_I`.
+const MPU6050_ID: u8 = 0x68;
+
+/// The gyroscope is configured for ±500°/s (the car turns with up to ca. 200°/s), i.e. 65.5 LSB per °/s.
+const GYRO_FULL_SCALE_RANGE_500: u8 = 1 << 3;
+const GYRO_LSB_PER_DEGREE_PER_SECOND: f32 = 65.5;
+/// The sign of the z-axis: the IMU is mounted with its z-axis pointing upwards, thus a positive yaw rate is a turn
+/// to the left (counter-clockwise).
+const GYRO_Z_SIGN: f32 = 1.0;
+
+/// The number of samples averaged to determine the offset of the gyroscope.
+const CALIBRATION_SAMPLES: i32 = 64;
+/// The time between two calibration samples (ca. 2ms at 84MHz, the gyroscope delivers a new sample every 1ms).
+const CALIBRATION_SAMPLE_DELAY_IN_CYCLES: u32 = 170_000;
+
+/// Errors which can occur while talking to the IMU.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The I2C communication failed.
+    I2c(E),
+    /// The device doesn't identify itself as an MPU6050.
+    UnknownDevice(u8),
+}
+
+/// The MPU6050, only its gyroscope is used.
+pub struct Mpu6050<I2C> {
+    i2c: I2C,
+    /// The raw value measured while standing still, it is subtracted from all measurements.
+    gyro_z_offset: i16,
+}
+
+impl<I2C, E> Mpu6050<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Mpu6050<I2C> {
+        Mpu6050 {
+            i2c,
+            gyro_z_offset: 0,
+        }
+    }
+
+    /// (Re-)initialise the IMU, e.g. after it lost its state due to a recovery of the I2C bus.
+    /// The offset of the gyroscope determined by [`Self::calibrate`] is kept.
+    pub fn initialise(&mut self) -> Result<(), Error<E>> {
+        let id = self.read_register(REGISTER_WHO_AM_I)?;
+        if id != MPU6050_ID {
+            return Err(Error::UnknownDevice(id));
+        }
+        // wake up (it starts in sleep mode), using the PLL of the x-axis gyroscope as clock (more stable)
+        self.write_register(REGISTER_PWR_MGMT_1, 0x01)?;
+        // low pass filter at 44Hz (the car doesn't turn faster), sample rate 1kHz
+        self.write_register(REGISTER_CONFIG, 0x03)?;
+        self.write_register(REGISTER_SMPLRT_DIV, 0x00)?;
+        self.write_register(REGISTER_GYRO_CONFIG, GYRO_FULL_SCALE_RANGE_500)
+    }
+
+    /// Determine the offset of the gyroscope. The car must not move while doing this (it takes ca. 130ms).
+    pub fn calibrate(&mut self) -> Result<(), Error<E>> {
+        let mut sum = 0;
+        for _ in 0..CALIBRATION_SAMPLES {
+            cortex_m::asm::delay(CALIBRATION_SAMPLE_DELAY_IN_CYCLES);
+            sum += self.read_raw_gyro_z()? as i32;
+        }
+        self.gyro_z_offset = (sum / CALIBRATION_SAMPLES) as i16;
+        defmt::info!("IMU calibrated, gyroscope offset: {}", self.gyro_z_offset);
+
+        Ok(())
+    }
+
+    /// The current rotation around the vertical axis, positive to the left (counter-clockwise).
+    pub fn get_yaw_rate_in_degrees_per_second(&mut self) -> Result<f32, Error<E>> {
+        let raw = self.read_raw_gyro_z()?.saturating_sub(self.gyro_z_offset);
+        Ok(GYRO_Z_SIGN * raw as f32 / GYRO_LSB_PER_DEGREE_PER_SECOND)
+    }
+
+    fn read_raw_gyro_z(&mut self) -> Result<i16, Error<E>> {
+        let mut buffer = [0; 2];
+        self.i2c
+            .write_read(MPU6050_ADDRESS, &[REGISTER_GYRO_ZOUT_H], &mut buffer)
+            .map_err(Error::I2c)?;
+        Ok(i16::from_be_bytes(buffer))
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut buffer = [0; 1];
+        self.i2c
+            .write_read(MPU6050_ADDRESS, &[register], &mut buffer)
+            .map_err(Error::I2c)?;
+        Ok(buffer[0])
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(MPU6050_ADDRESS, &[register, value])
+            .map_err(Error::I2c)
+    }
+}
@@ -0,0 +1,142 @@
+//! Follows a (dark) line on the floor using the reflectance sensors (see `line_sensor`).
+//!
+//! The car drives at a fixed speed and steers proportionally to the position of the line below it. If the line is
+//! lost (e.g. in a sharp curve) the car slows down and steers fully towards the side where it has last seen the line
+//! until it finds it again. If it doesn't find it within [`LINE_RECOVERY_TIMEOUT_IN_MS`] it stops. The collision
+//! avoidance still applies: if the car isn't allowed to drive forward anymore the line following is stopped.
+
+use crate::arbiter::{Command, CommandSource, Motion, AUTONOMOUS_COMMAND_TIMEOUT_IN_MS};
+use crate::car::CarState;
+use crate::event_log::{self, Event};
+use crate::speed_control::MOTOR_DEAD_BAND_IN_PERCENT;
+use crate::steering::Direction;
+use crate::CarT as Car;
+use core::cmp::Ordering;
+use fugit::ExtU32;
+
+/// The speed (in percentage) at which the car drives while following the line.
+const LINE_FOLLOWING_SPEED: i8 = 30;
+/// The speed (in percentage) while searching for the lost line, slow but still above the dead band of the motor.
+const LINE_RECOVERY_SPEED: i8 = 25;
+/// How long the car searches for the lost line before it stops.
+const LINE_RECOVERY_TIMEOUT_IN_MS: u32 = 1_500;
+/// The steering (in percentage) per percentage of the line position (see `Reflectance::line_position`).
+/// The outer sensors are at the edge of the steering range, thus the line below one of them results in full steering.
+const LINE_STEERING_GAIN: i32 = 1;
+
+const _: () = assert!(LINE_RECOVERY_SPEED as u8 > MOTOR_DEAD_BAND_IN_PERCENT);
+const _: () = assert!(LINE_RECOVERY_SPEED < LINE_FOLLOWING_SPEED);
+
+/// Follows the line below the car. [`LineFollower::update`] needs to be called periodically while it is running.
+#[derive(Default)]
+pub struct LineFollower {
+    running: bool,
+    /// The latest position of the line, used to find it again once it has been lost.
+    last_line_position: i8,
+    /// Since when the line has been lost, `None` while the line is seen.
+    line_lost_since: Option<fugit::TimerInstantU32<1_000_000>>,
+}
+
+impl LineFollower {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Start following the line, this is only possible if the car is on the line.
+    /// The car starts driving with the next update.
+    pub fn start(&mut self, car: &mut Car) {
+        match car.read_line_position() {
+            Ok(Some(line_position)) => {
+                defmt::info!("starting to follow the line");
+                event_log::record(Event::LineFollowingStarted);
+                self.last_line_position = line_position;
+                self.line_lost_since = None;
+                self.running = true;
+            }
+            Ok(None) => defmt::warn!("no line below the car (or there is no line sensor)"),
+            Err(e) => car.handle_error(e),
+        }
+    }
+
+    /// Stop following the line and stop the car.
+    pub fn stop(&mut self, car: &mut Car) {
+        if self.running {
+            defmt::info!("stopped following the line");
+            event_log::record(Event::LineFollowingStopped);
+            self.running = false;
+            car.release_command(CommandSource::Autonomous);
+        }
+    }
+
+    /// Steer based on the current position of the line. Returns whether the car is still following the line.
+    pub fn update(&mut self, car: &mut Car, now: fugit::TimerInstantU32<1_000_000>) -> bool {
+        if !self.running {
+            return false;
+        }
+        if car.current_state() != CarState::Normal {
+            defmt::warn!("obstacle in front, stopping to follow the line");
+            self.stop(car);
+            return false;
+        }
+
+        let line_position = match car.read_line_position() {
+            Ok(line_position) => line_position,
+            Err(e) => {
+                car.handle_error(e);
+                self.stop(car);
+                return false;
+            }
+        };
+        let (steering, speed) = match line_position {
+            Some(line_position) => {
+                self.last_line_position = line_position;
+                if self.line_lost_since.take().is_some() {
+                    defmt::info!("found the line again");
+                }
+                (
+                    line_position as i32 * LINE_STEERING_GAIN,
+                    LINE_FOLLOWING_SPEED,
+                )
+            }
+            None => {
+                let line_lost_since = *self.line_lost_since.get_or_insert_with(|| {
+                    defmt::warn!("lost the line, searching for it");
+                    now
+                });
+                if line_lost_since + LINE_RECOVERY_TIMEOUT_IN_MS.millis() < now {
+                    defmt::warn!("couldn't find the line again");
+                    self.stop(car);
+                    return false;
+                }
+                // the line has left the sensors on the side where it has last been seen
+                (
+                    self.last_line_position.signum() as i32 * 100,
+                    LINE_RECOVERY_SPEED,
+                )
+            }
+        };
+        defmt::trace!("line position: {} => steering {}%", line_position, steering);
+
+        let steering = steering.clamp(-100, 100);
+        let direction = match steering.cmp(&0) {
+            Ordering::Greater => Direction::Left(steering as u8),
+            Ordering::Less => Direction::Right(steering.unsigned_abs() as u8),
+            Ordering::Equal => Direction::Centre,
+        };
+        let command = Command {
+            motion: Motion::Duty(speed),
+            steering: direction,
+        };
+        if let Err(e) = car.submit_command(
+            CommandSource::Autonomous,
+            command,
+            Some(AUTONOMOUS_COMMAND_TIMEOUT_IN_MS),
+            now,
+        ) {
+            car.handle_error(e);
+            self.stop(car);
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,195 @@
+//! Abstraction layer for the reflectance sensors used to follow a line on the floor (see `line_following`).
+//!
+//! The car uses an array of three reflectance sensors (left, centre, right) pointing at the floor. They can either
+//! be read as digital signals (e.g. TCRT5000 modules with a comparator, connected to GPIO pins) or as analog
+//! signals through an I2C ADC board ([TI ADS1015](https://www.ti.com/product/ADS1015)), which allows a more precise
+//! line position.
+
+use core::fmt::Debug;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::digital::v2::InputPin;
+
+/// The value of a sensor which is fully over the (dark) line.
+pub const MAX_REFLECTANCE_VALUE: u16 = 1_000;
+
+/// A sensor has to measure at least this much (see [`Reflectance`]) to be considered to see the line.
+const LINE_THRESHOLD: u16 = 300;
+
+/// The darkness of the floor below each sensor, from 0 (bright floor) to [`MAX_REFLECTANCE_VALUE`] (dark line).
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct Reflectance {
+    pub left: u16,
+    pub centre: u16,
+    pub right: u16,
+}
+
+impl Reflectance {
+    /// The position of the line relative to the centre of the car from -100 (below the right sensor) to 100
+    /// (below the left sensor), i.e. positive to the left as with the steering. `None` if no sensor sees the line.
+    pub fn line_position(&self) -> Option<i8> {
+        let weight = |value: u16| value.saturating_sub(LINE_THRESHOLD) as i32;
+        let (left, centre, right) = (weight(self.left), weight(self.centre), weight(self.right));
+        let total = left + centre + right;
+        if total == 0 {
+            return None;
+        }
+        Some(((left - right) * 100 / total) as i8)
+    }
+}
+
+/// Represents an array of reflectance sensors.
+pub trait LineSensor {
+    type Error: Debug;
+
+    /// (Re-)initialise the sensor, e.g. after it lost its state due to a recovery of the I2C bus.
+    fn initialise(&mut self) -> Result<(), Self::Error>;
+
+    /// Read the current reflectance of the floor below the sensors.
+    fn read(&mut self) -> Result<Reflectance, Self::Error>;
+}
+
+/// Three digital reflectance sensors, each one is high while it is over the (dark) line.
+pub struct DigitalLineSensor<L, C, R> {
+    left: L,
+    centre: C,
+    right: R,
+}
+
+#[cfg_attr(not(feature = "use-line-sensor-gpio"), allow(dead_code))]
+impl<L, C, R> DigitalLineSensor<L, C, R> {
+    pub fn new(left: L, centre: C, right: R) -> DigitalLineSensor<L, C, R> {
+        DigitalLineSensor {
+            left,
+            centre,
+            right,
+        }
+    }
+}
+
+impl<L, C, R, E> LineSensor for DigitalLineSensor<L, C, R>
+where
+    L: InputPin<Error = E>,
+    C: InputPin<Error = E>,
+    R: InputPin<Error = E>,
+    E: Debug,
+{
+    type Error = E;
+
+    fn initialise(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Reflectance, E> {
+        let value = |on_line: bool| if on_line { MAX_REFLECTANCE_VALUE } else { 0 };
+        Ok(Reflectance {
+            left: value(self.left.is_high()?),
+            centre: value(self.centre.is_high()?),
+            right: value(self.right.is_high()?),
+        })
+    }
+}
+
+/// The I2C address of the ADS1015 (ADDR connected to GND).
+const ADS1015_ADDRESS: u8 = 0x48;
+const REGISTER_CONVERSION: u8 = 0x00;
+const REGISTER_CONFIG: u8 = 0x01;
+/// Start a single conversion (OS), ±4.096V (PGA = 001), single-shot mode. The input (MUX) is added per channel.
+const CONFIG_MSB_START_SINGLE_CONVERSION: u8 = (1 << 7) | (0b001 << 1) | 1;
+/// 1600 samples per second (i.e. a conversion takes ca. 0.6ms), comparator disabled.
+const CONFIG_LSB: u8 = (0b100 << 5) | 0b11;
+/// The single-ended inputs (AINx against GND) start at MUX = 100.
+const MUX_SINGLE_ENDED_AIN0: u8 = 0b100;
+/// The time to wait for a conversion while initialising (ca. 1.2ms at 84MHz).
+const CONVERSION_DELAY_IN_CYCLES: u32 = 100_000;
+/// The raw value (12 bit, ±4.096V) of a sensor over the line: the analog sensors (e.g. QRE1113) output up to
+/// ca. 3.0V while over a dark line.
+const ADS1015_MAX_RAW_VALUE: u16 = 1_500;
+/// The sensors are connected to AIN0 (left), AIN1 (centre) and AIN2 (right).
+const CHANNELS: usize = 3;
+
+/// Three analog reflectance sensors read with an ADS1015.
+///
+/// A conversion of the ADS1015 takes a while, thus only one channel is converted per read: the result of the
+/// previous conversion is read and the conversion of the next channel is started. Each sensor is thus updated
+/// with every third read.
+pub struct Ads1015LineSensor<I2C> {
+    i2c: I2C,
+    /// The latest value of each channel, see [`Reflectance`].
+    values: [u16; CHANNELS],
+    /// The channel which is currently being converted.
+    channel: usize,
+}
+
+#[cfg_attr(not(feature = "use-line-sensor"), allow(dead_code))]
+impl<I2C, E> Ads1015LineSensor<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C) -> Ads1015LineSensor<I2C> {
+        Ads1015LineSensor {
+            i2c,
+            values: [0; CHANNELS],
+            channel: 0,
+        }
+    }
+
+    fn start_conversion(&mut self, channel: usize) -> Result<(), E> {
+        let mux = MUX_SINGLE_ENDED_AIN0 + channel as u8;
+        self.i2c.write(
+            ADS1015_ADDRESS,
+            &[
+                REGISTER_CONFIG,
+                CONFIG_MSB_START_SINGLE_CONVERSION | (mux << 4),
+                CONFIG_LSB,
+            ],
+        )
+    }
+
+    /// Read the result of the latest conversion as a [`Reflectance`] value.
+    fn read_conversion(&mut self) -> Result<u16, E> {
+        let mut buffer = [0; 2];
+        self.i2c
+            .write_read(ADS1015_ADDRESS, &[REGISTER_CONVERSION], &mut buffer)?;
+        // the 12 bit result is left-aligned, negative values (noise around 0V) are treated as 0.
+        let raw = (i16::from_be_bytes(buffer) >> 4).max(0) as u32;
+        Ok(
+            (raw * MAX_REFLECTANCE_VALUE as u32 / ADS1015_MAX_RAW_VALUE as u32)
+                .min(MAX_REFLECTANCE_VALUE as u32) as u16,
+        )
+    }
+
+    fn reflectance(&self) -> Reflectance {
+        Reflectance {
+            left: self.values[0],
+            centre: self.values[1],
+            right: self.values[2],
+        }
+    }
+}
+
+impl<I2C, E> LineSensor for Ads1015LineSensor<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    type Error = E;
+
+    /// Convert all channels once (blocking), so that valid values are available right away.
+    fn initialise(&mut self) -> Result<(), E> {
+        for channel in 0..CHANNELS {
+            self.start_conversion(channel)?;
+            cortex_m::asm::delay(CONVERSION_DELAY_IN_CYCLES);
+            self.values[channel] = self.read_conversion()?;
+        }
+        self.channel = 0;
+        self.start_conversion(self.channel)
+    }
+
+    fn read(&mut self) -> Result<Reflectance, E> {
+        self.values[self.channel] = self.read_conversion()?;
+        self.channel = (self.channel + 1) % CHANNELS;
+        self.start_conversion(self.channel)?;
+        Ok(self.reflectance())
+    }
+}
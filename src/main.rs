@@ -8,57 +8,187 @@
 #![no_main]
 #![no_std]
 
+mod arbiter;
 mod bt_module;
 mod car;
+mod config;
+mod error;
+mod event_log;
+mod follow_me;
+mod i2c_bus;
+mod imu;
+mod line_following;
+mod line_sensor;
+mod manoeuvre;
+mod panic_handler;
+mod pose;
 mod remote_control;
-mod steering;
+mod reset_cause;
+mod supervisor;
+mod telemetry;
 mod tof_sensor;
-
-use panic_probe as _;
+mod wall_following;
+mod wheel_encoder;
 
 use defmt_rtt as _;
+// the hardware-independent logic, see `robotcar_core`
+use robotcar_core::{
+    battery, collision, distance_filter, drive_profile, odometry, pid, speed_control, steering,
+};
+
+// the digital line sensor uses the pins of the wheel encoder and the XSHUT of the side TOF
+#[cfg(all(
+    feature = "use-line-sensor-gpio",
+    any(
+        feature = "use-line-sensor",
+        feature = "use-encoder",
+        feature = "use-side-tof"
+    )
+))]
+compile_error!("`use-line-sensor-gpio` can't be combined with `use-line-sensor`, `use-encoder` or `use-side-tof`");
 
 pub use app::CarT;
 
-#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI1])]
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI1, EXTI2])]
 mod app {
 
+    #[cfg(feature = "use-side-tof")]
+    use crate::car::SIDE_DISTANCE_RANGING_PROFILE;
+    #[cfg(not(feature = "use-line-sensor-gpio"))]
+    use crate::line_sensor::Ads1015LineSensor;
+    #[cfg(feature = "use-line-sensor-gpio")]
+    use crate::line_sensor::DigitalLineSensor;
+    #[cfg(feature = "use-line-sensor")]
+    use crate::line_sensor::LineSensor;
+    #[cfg(any(feature = "use-tof", feature = "use-side-tof"))]
+    use crate::tof_sensor::DistanceSensor;
+    #[cfg(feature = "use-tof")]
+    use crate::tof_sensor::RangingProfile;
     use crate::{
-        bt_module::BluefruitLEUARTFriend,
+        battery::BatteryMonitor,
+        bt_module::{self, BluefruitLEUARTFriend},
         car::{Car, MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS},
+        config::ConfigStore,
+        error::{Error, TransportError},
+        event_log::{self, CrashRecord, Event},
+        i2c_bus::{self, RecoverableI2c},
+        imu::Mpu6050,
         remote_control::RemoteControl,
+        reset_cause::{ResetCause, ResetCounter},
         steering::Steering,
+        supervisor::{self, SupervisedTask},
+        telemetry::{self, Telemetry},
+        wheel_encoder::WheelEncoder,
     };
     #[cfg(feature = "use-display")]
     use display_interface::DisplayError;
     #[cfg(feature = "use-display")]
     use ssd1306::I2CDisplayInterface;
     use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306};
+    #[cfg(feature = "use-battery-monitor")]
+    use stm32f4xx_hal::adc::config::AdcConfig;
+    #[cfg(feature = "use-side-tof")]
+    use stm32f4xx_hal::gpio::{PinState, PC0};
+    #[cfg(feature = "use-line-sensor-gpio")]
+    use stm32f4xx_hal::gpio::{PA12, PC0, PC1};
+    use stm32f4xx_hal::{
+        adc::{config::SampleTime, Adc},
+        gpio::{Analog, PB0},
+        pac::ADC1,
+    };
     use stm32f4xx_hal::{
         dma::{traits::StreamISR, Stream2},
+        flash::LockedFlash,
         gpio::{Edge, Input, PA0, PA9},
-        i2c::I2c,
-        pac::{DMA2, IWDG, TIM5},
+        pac::{DMA2, IWDG, TIM5, USART1},
         prelude::*,
+        serial::Tx,
         timer::MonoTimerUs,
         watchdog::IndependentWatchdog,
     };
     use stm32f4xx_hal::{
-        gpio::{Output, PA8, PB4, PB5, PB8, PB9},
-        i2c::{self, I2c1},
+        gpio::{Output, PA8, PB4, PB5},
+        i2c,
         pac::{TIM2, TIM3},
         timer::PwmChannel,
     };
     use tb6612fng::Motor;
-    use vl53l1x_uld::{self, IOVoltage, Polarity, DEFAULT_ADDRESS, VL53L1X};
+    #[cfg(feature = "use-tof")]
+    use vl53l1x_uld::DEFAULT_ADDRESS;
+    use vl53l1x_uld::{self, VL53L1X};
+
+    /// The frequency of the system clock.
+    pub const SYSCLK_FREQUENCY: fugit::HertzU32 = fugit::HertzU32::from_raw(84_000_000);
+
+    /// The PWM frequency for the servos. Standard servos expect a pulse every 20ms.
+    const SERVO_PWM_FREQUENCY: fugit::HertzU32 = fugit::HertzU32::from_raw(50);
+
+    /// The I2C address to which the side TOF is moved, the front TOF keeps the default address.
+    #[cfg(feature = "use-side-tof")]
+    const SIDE_TOF_ADDRESS: u8 = 0x30;
+
+    /// Time to wait for the side TOF to boot after leaving the shutdown (max. 1.2ms according to the datasheet), in
+    /// CPU cycles. Ca. 2.4ms at 84MHz.
+    #[cfg(feature = "use-side-tof")]
+    const SIDE_TOF_BOOT_DELAY_IN_CYCLES: u32 = 200_000;
+
+    /// The interval in which the telemetry is sent. Sending it takes a while due to the low baud rate of the bluetooth module.
+    const TELEMETRY_INTERVAL_IN_MS: u32 = 1000;
+
+    /// The max. time it takes to send a status line. Sending blocks, thus all other tasks with the same priority as
+    /// `send_telemetry` can be delayed by this. The tasks which run more often (`run_autonomous_mode`: its commands
+    /// would time out, `update_odometry` & `feed_watchdog`) thus run with a higher priority.
+    const MAX_TELEMETRY_SEND_TIME_IN_MS: u32 =
+        telemetry::MAX_STATUS_LENGTH * 10 * 1_000 / bt_module::BAUD_RATE;
+    // the supervised tasks with the same priority still check in within their deadline
+    const _: () = assert!(
+        MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS + 1 + MAX_TELEMETRY_SEND_TIME_IN_MS
+            < SupervisedTask::ValidateDistance.deadline_in_ms()
+    );
+    const _: () = assert!(
+        DISPLAY_REFRESH_INTERVAL_IN_MS + MAX_TELEMETRY_SEND_TIME_IN_MS
+            < SupervisedTask::Display.deadline_in_ms()
+    );
+    const _: () = assert!(
+        TELEMETRY_INTERVAL_IN_MS + MAX_TELEMETRY_SEND_TIME_IN_MS
+            < SupervisedTask::Telemetry.deadline_in_ms()
+    );
+
+    /// The number of event log entries sent per run of `dump_event_log`. Sending takes a while due to the low baud
+    /// rate of the bluetooth module, thus the dump is split up to not block other tasks for too long.
+    const EVENT_LOG_ENTRIES_PER_DUMP: usize = 2;
+
+    /// The interval in which the display is refreshed (besides being updated whenever something changes).
+    const DISPLAY_REFRESH_INTERVAL_IN_MS: u32 = 500;
+
+    /// The interval in which the odometry & the pose are updated (and the speed controlled, if a target speed is set).
+    const ODOMETRY_INTERVAL_IN_MS: u32 = 20;
+
+    /// The interval in which the active autonomous mode (manoeuvre sequence, wall following, follow me) controls the car.
+    const AUTONOMOUS_MODE_INTERVAL_IN_MS: u32 = 20;
+
+    /// The interval in which the battery voltage is measured.
+    const BATTERY_MEASUREMENT_INTERVAL_IN_MS: u32 = 100;
+
+    /// Ratio of the voltage divider (10kΩ / 2.7kΩ) between VIN and the ADC pin: VIN = ADC voltage * 127 / 27.
+    /// The divider ensures that even fresh batteries (ca. 13V) stay below the maximum ADC voltage.
+    const BATTERY_VOLTAGE_DIVIDER_RATIO: (u32, u32) = (127, 27);
+
+    /// After running this long without a reset the car is considered to be stable again, i.e. previous watchdog
+    /// resets no longer count towards the degraded mode (see `ResetInfo::requires_degraded_mode`).
+    const STABLE_RUNTIME_IN_MS: u32 = 60_000;
 
     #[monotonic(binds = TIM5, default = true)]
     type MicrosecMono = MonoTimerUs<TIM5>;
 
-    type I2C1 = I2c1<(PB8, PB9)>;
-    type I2cProxy = shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<I2C1>>;
+    type I2cProxy = shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<RecoverableI2c>>;
     pub type Display =
         Ssd1306<I2CInterface<I2cProxy>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>;
+    pub type Imu = Mpu6050<I2cProxy>;
+    #[cfg(feature = "use-line-sensor-gpio")]
+    pub type LineSensorT = DigitalLineSensor<PC1<Input>, PC0<Input>, PA12<Input>>;
+    #[cfg(not(feature = "use-line-sensor-gpio"))]
+    pub type LineSensorT = Ads1015LineSensor<I2cProxy>;
     pub type CarT = Car<
         PwmChannel<TIM3, 0>,
         PB5<Output>,
@@ -73,6 +203,7 @@ mod app {
     struct Shared {
         remote_control: RemoteControl,
         car: crate::CarT,
+        telemetry: Telemetry<Tx<USART1>>,
     }
 
     #[local]
@@ -80,16 +211,37 @@ mod app {
         watchdog: IndependentWatchdog,
         button: PA9<Input>,
         tof_data_interrupt_pin: PA0<Input>,
+        /// The crash record persisted after the last watchdog reset (if any), to be included in the event log dump.
+        crash_record: Option<CrashRecord>,
+        reset_counter: ResetCounter,
+        /// The ADC and the pin connected to VIN, `None` if the battery isn't being monitored.
+        battery_adc: Option<(Adc<ADC1>, PB0<Analog>)>,
+        battery_monitor: BatteryMonitor,
+        /// `None` if the car has no wheel encoder.
+        wheel_encoder: Option<WheelEncoder>,
     }
 
     #[init]
     fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("booting system...");
 
+        event_log::init();
+        // find out why we've been reset. this needs to be done before the RCC is consumed.
+        // a panic resets the microcontroller by software, see `panic_handler`.
+        let panic_location = event_log::take_panic_location();
+        let reset_cause = match ResetCause::read_and_clear(&ctx.device.RCC) {
+            ResetCause::Software if panic_location.is_some() => ResetCause::Panic,
+            reset_cause => reset_cause,
+        };
+        let mut reset_counter = ResetCounter::new(ctx.device.RTC, &ctx.device.PWR, &ctx.device.RCC);
+        let reset_info = reset_counter.record_reset(reset_cause);
+        defmt::info!("reset: {}", reset_info);
+        mark_stable::spawn_after(STABLE_RUNTIME_IN_MS.millis()).ok();
+
         let mut syscfg = ctx.device.SYSCFG.constrain();
 
         let rcc = ctx.device.RCC.constrain();
-        let clocks = rcc.cfgr.sysclk(84.MHz()).freeze();
+        let clocks = rcc.cfgr.sysclk(SYSCLK_FREQUENCY).freeze();
         let mono = ctx.device.TIM5.monotonic_us(&clocks);
 
         let gpioa = ctx.device.GPIOA.split();
@@ -112,13 +264,51 @@ mod app {
 
         defmt::info!("LED & button setup done");
 
-        // set up I2C
-        let i2c = I2c::new(ctx.device.I2C1, (gpiob.pb8, gpiob.pb9), 400.kHz(), &clocks);
+        // load the persistent config. this must happen before the watchdog is started (see `ConfigStore::new`).
+        let mut config_store = ConfigStore::new(LockedFlash::new(ctx.device.FLASH));
+        let config = config_store.load();
+
+        defmt::info!("config loaded");
+
+        // persist the events which led to the crash, they'd be lost after a power cycle otherwise.
+        if reset_cause.is_crash() {
+            defmt::warn!(
+                "the car crashed ({}), persisting the latest events",
+                reset_cause
+            );
+            if let Some(panic_location) = panic_location {
+                defmt::warn!("panicked at {}", panic_location);
+            }
+            if let Err(e) = config_store.store_crash_record(event_log::crash_record(panic_location))
+            {
+                defmt::error!("failed to persist the crash record: {}", e);
+            }
+        }
+        let crash_record = config_store.load_crash_record();
+        event_log::record(Event::Boot(reset_cause));
+        if reset_info.requires_degraded_mode() {
+            defmt::error!(
+                "{} crashes in a row, only running in the degraded mode",
+                reset_info.consecutive_crashes
+            );
+            event_log::record(Event::DegradedMode);
+        }
+
+        // the cycle counter is needed for the timeouts of the I2C bus
+        ctx.core.DCB.enable_trace();
+        ctx.core.DWT.enable_cycle_counter();
+        // set up I2C (this also clears the bus in case a device is still stuck in a transfer from before the reset)
+        let i2c = RecoverableI2c::new(ctx.device.I2C1, (gpiob.pb8, gpiob.pb9), &clocks);
         #[cfg_attr(not(feature = "use-tof"), allow(unused))]
-        let i2c = shared_bus::new_atomic_check!(I2C1 = i2c).unwrap();
+        let i2c = shared_bus::new_atomic_check!(RecoverableI2c = i2c).unwrap();
 
         defmt::info!("I2C setup done");
 
+        // the side TOF has the same default address as the front TOF, thus it's kept in shutdown (its XSHUT is
+        // connected to PC0) until the front TOF has been set up and is then moved to another address.
+        #[cfg(feature = "use-side-tof")]
+        let side_tof_xshut = gpioc.pc0.into_push_pull_output_in_state(PinState::Low);
+
         // the pin is always needed (unless we want to change the code even more to make this optional as well)
         #[cfg_attr(not(feature = "use-tof"), allow(unused_mut))]
         let mut tof_data_interrupt_pin = gpioa.pa0.into_pull_down_input();
@@ -130,8 +320,12 @@ mod app {
             tof_data_interrupt_pin.enable_interrupt(&mut ctx.device.EXTI);
             tof_data_interrupt_pin.trigger_on_edge(&mut ctx.device.EXTI, Edge::Falling);
 
-            tof_sensor = Some(setup_tof(i2c.acquire_i2c()).expect("could initialise TOF sensor"));
+            tof_sensor = Some(
+                setup_tof(i2c.acquire_i2c(), config.tof_ranging.profile(false))
+                    .expect("could initialise TOF sensor"),
+            );
             validate_distance::spawn_after((MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS + 1).millis()).ok();
+            supervisor::register(SupervisedTask::ValidateDistance);
 
             defmt::info!("TOF setup done");
         }
@@ -142,10 +336,38 @@ mod app {
             defmt::warn!("TOF setup SKIPPED (TOF not enabled)");
         }
 
+        let side_tof;
+        #[cfg(feature = "use-side-tof")]
+        {
+            side_tof = match setup_side_tof(i2c.acquire_i2c(), side_tof_xshut) {
+                Ok(side_tof) => Some(side_tof),
+                Err(e) => {
+                    // the car can drive without it, it just can't follow walls.
+                    defmt::error!(
+                        "failed to initialise the side TOF: {}",
+                        defmt::Debug2Format(&e)
+                    );
+                    None
+                }
+            };
+
+            defmt::info!("side TOF setup done");
+        }
+        #[cfg(not(feature = "use-side-tof"))]
+        {
+            side_tof = None;
+
+            defmt::warn!("side TOF setup SKIPPED (side TOF not enabled)");
+        }
+
         let display;
         #[cfg(feature = "use-display")]
         {
             display = setup_display(i2c.acquire_i2c()).map(Some).unwrap_or(None);
+            if display.is_some() {
+                refresh_display::spawn_after(DISPLAY_REFRESH_INTERVAL_IN_MS.millis()).ok();
+                supervisor::register(SupervisedTask::Display);
+            }
 
             defmt::info!("display setup done");
         }
@@ -156,35 +378,133 @@ mod app {
             defmt::warn!("display setup SKIPPED (display not enabled)");
         }
 
+        let imu;
+        #[cfg(feature = "use-imu")]
+        {
+            // the car is standing still during the boot, thus the gyroscope can be calibrated
+            let mut mpu6050 = Mpu6050::new(i2c.acquire_i2c());
+            imu = match mpu6050.initialise().and_then(|_| mpu6050.calibrate()) {
+                Ok(()) => Some(mpu6050),
+                Err(e) => {
+                    defmt::error!("failed to initialise the IMU: {}", defmt::Debug2Format(&e));
+                    None
+                }
+            };
+
+            defmt::info!("IMU setup done");
+        }
+        #[cfg(not(feature = "use-imu"))]
+        {
+            imu = None;
+
+            defmt::warn!("IMU setup SKIPPED (IMU not enabled)");
+        }
+
+        let line_sensor;
+        #[cfg(feature = "use-line-sensor")]
+        {
+            let mut ads1015 = Ads1015LineSensor::new(i2c.acquire_i2c());
+            line_sensor = match ads1015.initialise() {
+                Ok(()) => Some(ads1015),
+                Err(e) => {
+                    // the car can drive without it, it just can't follow lines.
+                    defmt::error!(
+                        "failed to initialise the line sensor: {}",
+                        defmt::Debug2Format(&e)
+                    );
+                    None
+                }
+            };
+
+            defmt::info!("line sensor setup done");
+        }
+        #[cfg(feature = "use-line-sensor-gpio")]
+        {
+            // left, centre & right sensor on the spare pins (see `compile_error!` above for the conflicts)
+            line_sensor = Some(DigitalLineSensor::new(
+                gpioc.pc1.into_pull_down_input(),
+                gpioc.pc0.into_pull_down_input(),
+                gpioa.pa12.into_pull_down_input(),
+            ));
+
+            defmt::info!("line sensor setup done");
+        }
+        #[cfg(not(any(feature = "use-line-sensor", feature = "use-line-sensor-gpio")))]
+        {
+            line_sensor = None;
+
+            defmt::warn!("line sensor setup SKIPPED (line sensor not enabled)");
+        }
+
         // set up USART (for the bluetooth module)
-        let bt_module = BluefruitLEUARTFriend::new(
+        let mut bt_module = BluefruitLEUARTFriend::new(
             ctx.device.USART1,
             ctx.device.DMA2,
             gpiob.pb6,
             gpioa.pa10,
             &clocks,
-        );
-        let remote_control = RemoteControl::new(bt_module);
+        )
+        .expect("could set up the bluetooth module"); // the car can't be controlled without it
+        let telemetry = Telemetry::new(bt_module.tx.take().expect("bluetooth TX is available"));
+        send_telemetry::spawn_after(TELEMETRY_INTERVAL_IN_MS.millis()).ok();
+        supervisor::register(SupervisedTask::Telemetry);
+        let remote_control = RemoteControl::new(bt_module, config_store, config);
 
         defmt::info!("bluetooth setup done");
 
+        // set up the battery monitoring (VIN is connected to PB0 using a voltage divider)
+        let battery_adc;
+        #[cfg(feature = "use-battery-monitor")]
+        {
+            let adc = Adc::adc1(ctx.device.ADC1, true, AdcConfig::default());
+            battery_adc = Some((adc, gpiob.pb0.into_analog()));
+            measure_battery::spawn().ok();
+
+            defmt::info!("battery monitoring setup done");
+        }
+        #[cfg(not(feature = "use-battery-monitor"))]
+        {
+            battery_adc = None;
+
+            defmt::warn!("battery monitoring setup SKIPPED (battery monitoring not enabled)");
+        }
+
+        // set up the wheel encoder (connected to PA12, see `WheelEncoder`)
+        let wheel_encoder;
+        #[cfg(feature = "use-encoder")]
+        {
+            wheel_encoder = Some(WheelEncoder::new(ctx.device.TIM1, gpioa.pa12, &clocks));
+
+            defmt::info!("wheel encoder setup done");
+        }
+        #[cfg(not(feature = "use-encoder"))]
+        {
+            wheel_encoder = None;
+
+            defmt::warn!("wheel encoder setup SKIPPED (wheel encoder not enabled)");
+        }
+
         // set up servo 1 & 2
         let (servo1_pwm, _servo2_pwm) = ctx
             .device
             .TIM3
             .pwm_hz(
                 (gpioa.pa6.into_alternate(), gpioc.pc7.into_alternate()),
-                50.Hz(),
+                SERVO_PWM_FREQUENCY,
                 &clocks,
             )
             .split();
 
         defmt::info!("servo setup done");
 
-        // set up the steering. PWM empirically determined.
-        let steering_centre_pwm = 4930;
-        let max_steering_side = 800;
-        let steering = Steering::new(servo1_pwm, steering_centre_pwm, max_steering_side);
+        // set up the steering. the calibration can be changed at runtime, see `RemoteControl`.
+        let steering = Steering::new(
+            servo1_pwm,
+            SERVO_PWM_FREQUENCY,
+            config.steering,
+            config.steering_slew_rate_in_degrees_per_second,
+        );
+        update_steering::spawn().ok();
 
         defmt::info!("steering setup done");
 
@@ -206,7 +526,29 @@ mod app {
 
         defmt::info!("motor setup done");
 
-        let car = Car::new(steering, motor1, tof_sensor, display, led_status_obstacle);
+        let mut car = Car::new(
+            steering,
+            motor1,
+            tof_sensor,
+            config.tof_ranging,
+            display,
+            led_status_obstacle,
+            reset_info,
+        );
+        car.set_drive_profile(config.drive_profile);
+        if wheel_encoder.is_some() {
+            car.enable_speed_control();
+        }
+        if let Some(imu) = imu {
+            car.enable_imu(imu);
+        }
+        if let Some(line_sensor) = line_sensor {
+            car.enable_line_sensor(line_sensor);
+        }
+        if let Some(side_tof) = side_tof {
+            car.enable_side_distance_sensor(side_tof);
+        }
+        update_odometry::spawn().ok();
 
         let watchdog = setup_watchdog(ctx.device.IWDG);
 
@@ -219,16 +561,34 @@ mod app {
             Shared {
                 remote_control,
                 car,
+                telemetry,
             },
             Local {
                 watchdog,
                 button,
                 tof_data_interrupt_pin,
+                crash_record,
+                reset_counter,
+                battery_adc,
+                battery_monitor: BatteryMonitor::default(),
+                wheel_encoder,
             },
             init::Monotonics(mono),
         )
     }
 
+    /// Lock the car for a task which uses the devices on the I2C bus. If the bus has been recovered in the meantime
+    /// (e.g. due to a fault in another task) the devices are re-initialised first, see `Car::handle_i2c_bus_recoveries`.
+    fn lock_car_for_i2c<R>(
+        car: &mut impl rtic::Mutex<T = CarT>,
+        f: impl FnOnce(&mut CarT) -> R,
+    ) -> R {
+        car.lock(|car| {
+            car.handle_i2c_bus_recoveries(i2c_bus::recovery_count());
+            f(car)
+        })
+    }
+
     /// Set up the independent watchdog and start the periodic task to feed it
     fn setup_watchdog(iwdg: IWDG) -> IndependentWatchdog {
         let mut watchdog = IndependentWatchdog::new(iwdg);
@@ -252,30 +612,128 @@ mod app {
 
     /// Set up the TOF sensor.
     #[cfg(feature = "use-tof")]
-    fn setup_tof(i2c: I2cProxy) -> Result<VL53L1X<I2cProxy>, vl53l1x_uld::Error<i2c::Error>> {
+    fn setup_tof(
+        i2c: I2cProxy,
+        profile: RangingProfile,
+    ) -> Result<VL53L1X<I2cProxy>, vl53l1x_uld::Error<i2c::Error>> {
         let mut device = VL53L1X::new(i2c, DEFAULT_ADDRESS);
-        device.init(IOVoltage::Volt2_8)?;
-        device.set_interrupt_polarity(Polarity::ActiveHigh)?;
-        device.start_ranging()?;
+        device.initialise(profile)?;
+
+        Ok(device)
+    }
+
+    /// Wake up the side TOF (which must be the only TOF on the default address at this point), move it to
+    /// [`SIDE_TOF_ADDRESS`] and set it up. XSHUT stays high after the pin has been dropped.
+    #[cfg(feature = "use-side-tof")]
+    fn setup_side_tof(
+        i2c: I2cProxy,
+        mut xshut: PC0<Output>,
+    ) -> Result<VL53L1X<I2cProxy>, vl53l1x_uld::Error<i2c::Error>> {
+        xshut.set_high();
+        cortex_m::asm::delay(SIDE_TOF_BOOT_DELAY_IN_CYCLES);
+
+        let mut device = VL53L1X::new(i2c, vl53l1x_uld::DEFAULT_ADDRESS);
+        device.set_address(SIDE_TOF_ADDRESS)?;
+        device.initialise(SIDE_DISTANCE_RANGING_PROFILE)?;
 
         Ok(device)
     }
 
     /// Feed the watchdog periodically to avoid a hardware reset.
-    #[task(priority = 1, local = [watchdog])]
+    /// The watchdog is only fed as long as all supervised tasks are alive, see [`supervisor`]. This runs with a higher
+    /// priority so that it isn't delayed by e.g. sending the telemetry, the supervision covers the other tasks.
+    #[task(priority = 2, local = [watchdog])]
     fn feed_watchdog(cx: feed_watchdog::Context) {
-        defmt::trace!("feeding the watchdog!");
-        cx.local.watchdog.feed();
+        if supervisor::all_tasks_alive() {
+            defmt::trace!("feeding the watchdog!");
+            cx.local.watchdog.feed();
+        }
         feed_watchdog::spawn_after(100.millis()).ok();
     }
 
+    /// The car has been running long enough without a reset to be considered stable.
+    #[task(priority = 1, local = [reset_counter])]
+    fn mark_stable(ctx: mark_stable::Context) {
+        defmt::debug!("running stable, clearing the consecutive watchdog resets");
+        ctx.local.reset_counter.mark_stable();
+    }
+
     // see here for why this is EXTI9_5: https://github.com/stm32-rs/stm32f4xx-hal/blob/6d0c29233a4cd1f780b2fef3e47ef091ead6cf4a/src/gpio/exti.rs#L8-L23
-    /// Triggers every time the user button is pressed.
-    #[task(binds = EXTI9_5, local = [button])]
-    fn button_click(ctx: button_click::Context) {
+    /// Triggers every time the user button is pressed. This stops the autonomous mode if one is active,
+    /// otherwise it sends the event log to the connected app.
+    #[task(binds = EXTI9_5, local = [button], shared = [remote_control, car])]
+    fn button_click(mut ctx: button_click::Context) {
         ctx.local.button.clear_interrupt_pending_bit();
 
         defmt::info!("button pressed");
+        let aborted = ctx.shared.remote_control.lock(|remote_control| {
+            lock_car_for_i2c(&mut ctx.shared.car, |car| {
+                let active = remote_control.is_autonomous_mode_active();
+                remote_control.stop_autonomous_mode(car);
+                active
+            })
+        });
+        if !aborted {
+            dump_event_log::spawn(0).ok(); // fails if a dump is already in progress, which is fine
+        }
+    }
+
+    /// Periodically let the active autonomous mode control the car. Spawned when an autonomous mode is started.
+    /// This runs with a higher priority as sending the telemetry takes longer than the timeout of its commands.
+    #[task(priority = 2, shared = [remote_control, car])]
+    fn run_autonomous_mode(mut ctx: run_autonomous_mode::Context) {
+        let active = ctx.shared.remote_control.lock(|remote_control| {
+            lock_car_for_i2c(&mut ctx.shared.car, |car| {
+                remote_control.update_autonomous_mode(car, monotonics::now())
+            })
+        });
+        if active {
+            run_autonomous_mode::spawn_after(AUTONOMOUS_MODE_INTERVAL_IN_MS.millis()).ok();
+        }
+    }
+
+    /// Send the event log (preceded by the persisted crash record, if any) to the connected app, starting with the
+    /// entry at `index`. Only a few entries are sent per run, the task re-spawns itself for the remaining ones.
+    #[task(priority = 1, local = [crash_record], shared = [telemetry, car])]
+    fn dump_event_log(mut ctx: dump_event_log::Context, index: usize) {
+        let crash_record = ctx.local.crash_record.as_ref();
+        let crash_record_entries =
+            crash_record.map_or(&[][..], |crash_record| crash_record.entries());
+        let mut next_index = index;
+        let result = ctx.shared.telemetry.lock(|telemetry| {
+            if index == 0 {
+                if let Some(panic_location) =
+                    crash_record.and_then(|crash_record| crash_record.panic_location())
+                {
+                    telemetry.send_panic_location("C", panic_location)?;
+                }
+            }
+            while next_index < index + EVENT_LOG_ENTRIES_PER_DUMP {
+                if let Some(entry) = crash_record_entries.get(next_index) {
+                    telemetry.send_event("C", entry)?;
+                } else {
+                    match event_log::get(next_index - crash_record_entries.len()) {
+                        Some(Some(entry)) => telemetry.send_event("E", &entry)?,
+                        Some(None) => {} // skip entries which couldn't be decoded
+                        None => return Ok(true),
+                    }
+                }
+                next_index += 1;
+            }
+            Ok(false)
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                dump_event_log::spawn(next_index).ok();
+            }
+            Err(core::fmt::Error) => {
+                defmt::warn!("failed to send the event log");
+                ctx.shared
+                    .car
+                    .lock(|car| car.handle_error(Error::Transport(TransportError::Send)));
+            }
+        }
     }
 
     // see here for why this is EXTI0: https://github.com/stm32-rs/stm32f4xx-hal/blob/6d0c29233a4cd1f780b2fef3e47ef091ead6cf4a/src/gpio/exti.rs#L8-L23
@@ -285,17 +743,92 @@ mod app {
         ctx.local
             .tof_data_interrupt_pin
             .clear_interrupt_pending_bit();
+        lock_car_for_i2c(&mut ctx.shared.car, |car| {
+            if let Err(e) = car.handle_distance_sensor_interrupt(monotonics::now()) {
+                car.handle_error(e);
+            }
+        });
+    }
+
+    /// Periodically advance the steering towards the requested direction (it turns with a limited speed).
+    /// This is done once per servo PWM period as the servo can't react faster than that anyway.
+    #[task(priority = 1, shared = [car])]
+    fn update_steering(mut ctx: update_steering::Context) {
         ctx.shared.car.lock(|car| {
-            car.handle_distance_sensor_interrupt(monotonics::now()).ok(); // error already logged in the function
+            car.update_steering(monotonics::now());
+        });
+        update_steering::spawn_after((1_000 / SERVO_PWM_FREQUENCY.raw()).millis()).ok();
+    }
+
+    /// Periodically send the status of the car to the connected app.
+    #[task(priority = 1, shared = [car, telemetry])]
+    fn send_telemetry(mut ctx: send_telemetry::Context) {
+        let status = ctx.shared.car.lock(|car| car.status());
+        // sending takes a while, thus this is done without holding the lock on the car.
+        if ctx
+            .shared
+            .telemetry
+            .lock(|telemetry| telemetry.send_status(&status))
+            .is_err()
+        {
+            defmt::warn!("failed to send telemetry");
+            ctx.shared
+                .car
+                .lock(|car| car.handle_error(Error::Transport(TransportError::Send)));
+        } else {
+            supervisor::check_in(SupervisedTask::Telemetry);
+        }
+        send_telemetry::spawn_after(TELEMETRY_INTERVAL_IN_MS.millis()).ok();
+    }
+
+    /// Periodically refresh the display. Only spawned if a display is present.
+    #[task(priority = 1, shared = [car])]
+    fn refresh_display(mut ctx: refresh_display::Context) {
+        lock_car_for_i2c(&mut ctx.shared.car, |car| car.refresh_display());
+        supervisor::check_in(SupervisedTask::Display);
+        refresh_display::spawn_after(DISPLAY_REFRESH_INTERVAL_IN_MS.millis()).ok();
+    }
+
+    /// Periodically measure the battery voltage and let the car react to it.
+    #[task(priority = 1, local = [battery_adc, battery_monitor], shared = [car])]
+    fn measure_battery(mut ctx: measure_battery::Context) {
+        let (adc, battery_pin) = match ctx.local.battery_adc.as_mut() {
+            Some(battery_adc) => battery_adc,
+            None => panic!("measure_battery triggered but no battery monitoring enabled!"),
+        };
+        let sample = adc.convert(battery_pin, SampleTime::Cycles_480);
+        let (numerator, denominator) = BATTERY_VOLTAGE_DIVIDER_RATIO;
+        let voltage_in_mv =
+            (adc.sample_to_millivolts(sample) as u32 * numerator / denominator) as u16;
+        let battery = ctx.local.battery_monitor.update(voltage_in_mv);
+        lock_car_for_i2c(&mut ctx.shared.car, |car| {
+            car.handle_battery_status(battery);
         });
+        measure_battery::spawn_after(BATTERY_MEASUREMENT_INTERVAL_IN_MS.millis()).ok();
     }
 
-    /// Ensure that we also react in case we don't get a new sensor value from the TOF
+    /// Periodically update the odometry (with the pulses of the wheel encoder, if there is one) and the pose of the
+    /// car, control the speed and drop the commands which have timed out (see `arbiter`). This runs with a higher
+    /// priority so that the speed control and the pose aren't delayed by e.g. sending the telemetry.
+    #[task(priority = 2, local = [wheel_encoder], shared = [car])]
+    fn update_odometry(mut ctx: update_odometry::Context) {
+        let count = ctx.local.wheel_encoder.as_ref().map(WheelEncoder::count);
+        lock_car_for_i2c(&mut ctx.shared.car, |car| {
+            let now = monotonics::now();
+            car.update_odometry(count, now);
+            car.expire_commands(now);
+        });
+        update_odometry::spawn_after(ODOMETRY_INTERVAL_IN_MS.millis()).ok();
+    }
+
+    /// Ensure that we also react in case we don't get a new sensor value from the TOF.
+    /// This also retries the re-initialisation of the devices on the I2C bus if it failed after a recovery.
     #[task(priority = 1, shared = [car])]
     fn validate_distance(mut ctx: validate_distance::Context) {
-        ctx.shared.car.lock(|car| {
-            car.validate_distance(monotonics::now());
+        lock_car_for_i2c(&mut ctx.shared.car, |car| {
+            car.validate_distance(monotonics::now())
         });
+        supervisor::check_in(SupervisedTask::ValidateDistance);
         validate_distance::spawn_after((MAX_FRONT_DISTANCE_SENSOR_LAG_IN_MS + 1).millis()).ok();
     }
 
@@ -312,9 +845,15 @@ mod app {
         defmt::debug!("received DMA2_STREAM2 interrupt (transfer complete)");
         if Stream2::<DMA2>::get_transfer_complete_flag() {
             ctx.shared.remote_control.lock(|remote_control| {
-                ctx.shared.car.lock(|car| {
-                    remote_control.handle_bluetooth_message(car);
+                lock_car_for_i2c(&mut ctx.shared.car, |car| {
+                    if let Err(e) = remote_control.handle_bluetooth_message(car, monotonics::now())
+                    {
+                        car.handle_error(e);
+                    }
                 });
+                if remote_control.is_autonomous_mode_active() {
+                    run_autonomous_mode::spawn().ok(); // fails if it is already running, which is fine
+                }
             });
         }
     }
@@ -324,9 +863,14 @@ mod app {
     fn bluetooth_receive_interrupt(mut ctx: bluetooth_receive_interrupt::Context) {
         defmt::debug!("received USART1 interrupt (IDLE)");
         ctx.shared.remote_control.lock(|remote_control| {
-            ctx.shared.car.lock(|car| {
-                remote_control.handle_bluetooth_message(car);
+            lock_car_for_i2c(&mut ctx.shared.car, |car| {
+                if let Err(e) = remote_control.handle_bluetooth_message(car, monotonics::now()) {
+                    car.handle_error(e);
+                }
             });
+            if remote_control.is_autonomous_mode_active() {
+                run_autonomous_mode::spawn().ok(); // fails if it is already running, which is fine
+            }
         });
     }
 }
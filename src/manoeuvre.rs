@@ -0,0 +1,139 @@
+//! Plays predefined sequences of manoeuvres (e.g. "forward 50% for 2s, steer left 60%, reverse 1s, stop"),
+//! e.g. for demos and tests without someone having to hold the phone.
+//!
+//! The manoeuvres are submitted as autonomous commands to the car (see `arbiter`), thus the collision avoidance still
+//! applies: if the car isn't allowed to drive forward (or has been stopped by the collision avoidance) the sequence is
+//! aborted.
+
+use crate::arbiter::{Command, CommandSource, Motion, AUTONOMOUS_COMMAND_TIMEOUT_IN_MS};
+use crate::car::CarState;
+use crate::event_log::{self, Event};
+use crate::CarT as Car;
+use fugit::ExtU32;
+pub use robotcar_core::manoeuvre::{Manoeuvre, ManoeuvreSequence};
+
+/// The progress of the sequence currently being played.
+struct Progress {
+    /// The index of the manoeuvre currently being executed.
+    index: usize,
+    /// When the current manoeuvre is done, `None` if it hasn't been started yet.
+    end: Option<fugit::TimerInstantU32<1_000_000>>,
+}
+
+/// Plays a [`ManoeuvreSequence`] on the car. [`ManoeuvrePlayer::update`] needs to be called periodically while
+/// the sequence is running.
+pub struct ManoeuvrePlayer {
+    sequence: ManoeuvreSequence,
+    progress: Option<Progress>,
+    /// The command resulting from the manoeuvres executed so far.
+    command: Command,
+}
+
+impl ManoeuvrePlayer {
+    pub fn new(sequence: ManoeuvreSequence) -> ManoeuvrePlayer {
+        ManoeuvrePlayer {
+            sequence,
+            progress: None,
+            command: Command::STOP,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// Start the sequence from the beginning. The first manoeuvre is executed with the next update. An empty sequence
+    /// (which can only be created in code, see [`ManoeuvreSequence::from_bytes`]) isn't started.
+    pub fn start(&mut self) {
+        if self.sequence.is_empty() {
+            defmt::warn!("ignoring the empty manoeuvre sequence");
+            return;
+        }
+        defmt::info!("starting manoeuvre sequence {}", self.sequence);
+        event_log::record(Event::ManoeuvreStarted);
+        self.progress = Some(Progress {
+            index: 0,
+            end: None,
+        });
+        self.command = Command::STOP;
+    }
+
+    /// Stop the sequence and the car.
+    pub fn abort(&mut self, car: &mut Car) {
+        if self.progress.take().is_some() {
+            defmt::warn!("manoeuvre sequence aborted");
+            event_log::record(Event::ManoeuvreAborted);
+            car.release_command(CommandSource::Autonomous);
+        }
+    }
+
+    /// Execute the next manoeuvre(s) if the current one is done. Returns whether the sequence is still running.
+    pub fn update(&mut self, car: &mut Car, now: fugit::TimerInstantU32<1_000_000>) -> bool {
+        loop {
+            let progress = match self.progress.as_mut() {
+                Some(progress) => progress,
+                None => return false,
+            };
+            let manoeuvre = self.sequence.manoeuvres()[progress.index];
+            match progress.end {
+                Some(end) if end > now => {
+                    if Self::has_been_stopped(&manoeuvre, car) {
+                        defmt::warn!("the car has been stopped by the collision avoidance");
+                        self.abort(car);
+                        return false;
+                    }
+                    break;
+                }
+                Some(_) => {
+                    progress.index += 1;
+                    progress.end = None;
+                    if progress.index >= self.sequence.manoeuvres().len() {
+                        defmt::info!("manoeuvre sequence finished");
+                        event_log::record(Event::ManoeuvreFinished);
+                        self.progress = None;
+                        car.release_command(CommandSource::Autonomous);
+                        return false;
+                    }
+                }
+                None => {
+                    progress.end = Some(now + manoeuvre.duration_in_ms().millis());
+                    defmt::debug!("executing manoeuvre {}", manoeuvre);
+                    Self::apply(&manoeuvre, &mut self.command);
+                    if manoeuvre.duration_in_ms() > 0 {
+                        // the command is submitted below, the next manoeuvre follows once this one is done
+                        break;
+                    }
+                }
+            }
+        }
+
+        // this also keeps the command from timing out while a manoeuvre takes longer.
+        if let Err(e) = car.submit_command(
+            CommandSource::Autonomous,
+            self.command,
+            Some(AUTONOMOUS_COMMAND_TIMEOUT_IN_MS),
+            now,
+        ) {
+            defmt::warn!("command {} of the manoeuvre sequence failed", self.command);
+            car.handle_error(e);
+            self.abort(car);
+            return false;
+        }
+        true
+    }
+
+    /// Change the command according to the manoeuvre.
+    fn apply(manoeuvre: &Manoeuvre, command: &mut Command) {
+        match *manoeuvre {
+            Manoeuvre::Drive { speed, .. } => command.motion = Motion::Duty(speed),
+            Manoeuvre::Steer(direction) => command.steering = direction,
+            Manoeuvre::Stop { .. } => command.motion = Motion::Stop,
+        }
+    }
+
+    /// Whether the car has been stopped (by the collision avoidance) while it should be driving forward.
+    fn has_been_stopped(manoeuvre: &Manoeuvre, car: &mut Car) -> bool {
+        matches!(manoeuvre, Manoeuvre::Drive { speed, .. } if *speed > 0)
+            && (car.current_speed() <= 0 || car.current_state() != CarState::Normal)
+    }
+}
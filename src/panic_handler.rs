@@ -0,0 +1,85 @@
+//! Brings the car into a safe state if the firmware panics.
+//!
+//! Without this the motor and the steering would keep their last state until the watchdog resets the car.
+//! As the peripherals are owned by the (now defunct) tasks, the panic handler accesses the hardware directly:
+//! it brakes the motor, centres the steering, records where it panicked in the event log (which survives the
+//! reset, see [`event_log::record_panic`]), blinks the obstacle LED and then resets the microcontroller.
+//! After the reset the panic is persisted as a crash record (see `init`).
+//!
+//! Note that the pins used here must match the ones set up in `init`.
+
+use crate::event_log::{self, PanicLocation};
+use crate::steering;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::{DCB, SCB};
+use stm32f4xx_hal::pac;
+
+/// How often the obstacle LED blinks before the reset.
+const BLINKS: u32 = 10;
+/// Half of a blink period of the obstacle LED.
+const BLINK_HALF_PERIOD_IN_MS: u32 = 100;
+
+#[allow(unsafe_code)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
+    cortex_m::interrupt::disable();
+
+    // guard against a panic within the panic handler
+    if PANICKED.swap(true, Ordering::Relaxed) {
+        SCB::sys_reset();
+    }
+
+    // SAFETY: interrupts are disabled and this function never returns, thus the owners of the peripherals
+    // can't access them anymore.
+    let device = unsafe { pac::Peripherals::steal() };
+
+    // bring the car into a safe state first, everything else can wait
+    brake_motor(&device);
+    centre_steering(&device);
+
+    defmt::error!("{}", defmt::Display2Format(info));
+    if let Some(location) = info.location() {
+        event_log::record_panic(PanicLocation::new(location.file(), location.line()));
+    }
+
+    // let the debugger show the backtrace (like `panic-probe` does)
+    if DCB::is_debugger_attached() {
+        cortex_m::asm::udf();
+    }
+
+    blink_obstacle_led(&device);
+    SCB::sys_reset()
+}
+
+/// Short brake of motor A: both inputs (PB5 & PB4) high, independent of the PWM (see the TB6612FNG datasheet).
+fn brake_motor(device: &pac::Peripherals) {
+    device
+        .GPIOB
+        .bsrr
+        .write(|w| w.bs4().set_bit().bs5().set_bit());
+}
+
+/// Set the servo PWM (TIM3, channel 1) to the centre position, unless the steering hasn't been set up yet.
+fn centre_steering(device: &pac::Peripherals) {
+    if let Some(duty) = steering::centre_duty() {
+        device.TIM3.ccr1().write(|w| w.ccr().bits(duty));
+    }
+}
+
+/// Blink the obstacle LED (PA8) to show that something went wrong.
+fn blink_obstacle_led(device: &pac::Peripherals) {
+    let half_period_in_cycles =
+        crate::app::SYSCLK_FREQUENCY.raw() / 1_000 * BLINK_HALF_PERIOD_IN_MS;
+    for _ in 0..BLINKS * 2 {
+        device
+            .GPIOA
+            .odr
+            .modify(|r, w| w.odr8().bit(!r.odr8().bit()));
+        // the watchdog is still running, it must not reset the car before the blinking is done
+        device.IWDG.kr.write(|w| w.key().reset());
+        cortex_m::asm::delay(half_period_in_cycles);
+    }
+}
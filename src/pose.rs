@@ -0,0 +1,107 @@
+//! Estimates the pose (position & heading) of the car relative to where it started (dead reckoning).
+//!
+//! This module is hardware-agnostic: it gets the distance travelled (from the wheel encoder or, if there is none,
+//! estimated from the speed of the motor, see [`estimated_speed_in_mm_per_s`]), the steering angle and optionally
+//! the yaw rate measured by a gyroscope. The movement is calculated with a bicycle model: the car turns around a
+//! point on the extension of the rear axle, the radius depends on the steering angle and the wheelbase.
+//! If the yaw rate is available it is mostly trusted for the heading, as the bicycle model doesn't know about the
+//! wheels slipping and the steering angle is only known approximately.
+
+use crate::speed_control::MOTOR_DEAD_BAND_IN_PERCENT;
+use core::f32::consts::PI;
+use defmt::Format;
+
+/// The distance between the front and the rear axle.
+pub const WHEELBASE_IN_MM: f32 = 150.0;
+/// The steering angle of the front wheels per degree of the steering servo (the linkage translates it ca. 1:1).
+pub const STEERING_ANGLE_PER_SERVO_ANGLE: f32 = 1.0;
+/// How much the yaw rate measured by the gyroscope is trusted compared to the bicycle model (0.0 - 1.0).
+const GYRO_WEIGHT: f32 = 0.9;
+
+/// Without a wheel encoder the speed is estimated: the car starts moving above [`MOTOR_DEAD_BAND_IN_PERCENT`] and gets
+/// faster by this much per percentage above it (measured on a level floor with full batteries).
+const SPEED_MODEL_MM_PER_S_PER_PERCENT: i32 = 10;
+
+/// Estimate the speed of the car based on the speed (in percentage) set on the motor.
+/// This is only a rough estimate as the actual speed depends on the battery and the load.
+pub fn estimated_speed_in_mm_per_s(motor_speed: i8) -> i32 {
+    let effective_speed =
+        (motor_speed.unsigned_abs() as i32 - MOTOR_DEAD_BAND_IN_PERCENT as i32).max(0);
+    effective_speed * SPEED_MODEL_MM_PER_S_PER_PERCENT * motor_speed.signum() as i32
+}
+
+/// The pose of the car relative to where it started: it started at (0, 0) looking along the x-axis, the y-axis
+/// points to the left.
+#[derive(PartialEq, Debug, Copy, Clone, Default, Format)]
+pub struct Pose {
+    pub x_in_mm: f32,
+    pub y_in_mm: f32,
+    /// The heading in radians, counter-clockwise (i.e. to the left) is positive. Kept within `-PI..=PI`.
+    pub heading: f32,
+}
+
+impl Pose {
+    /// The heading in degrees (`-180..=180`), counter-clockwise (i.e. to the left) is positive.
+    pub fn heading_in_degrees(&self) -> f32 {
+        self.heading.to_degrees()
+    }
+}
+
+/// Keeps track of the [`Pose`] of the car.
+#[derive(Default)]
+pub struct PoseEstimator {
+    pose: Pose,
+}
+
+impl PoseEstimator {
+    /// Advance the pose by the movement since the last update.
+    ///
+    /// * `distance_in_mm`: the distance travelled since the last update (negative while driving backwards)
+    /// * `servo_angle_in_degrees`: the current angle of the steering servo, positive to the left
+    /// * `yaw_rate_in_degrees_per_second`: the yaw rate measured by the gyroscope (if available), positive to the left
+    /// * `dt_in_ms`: the time since the last update
+    pub fn update(
+        &mut self,
+        distance_in_mm: f32,
+        servo_angle_in_degrees: f32,
+        yaw_rate_in_degrees_per_second: Option<f32>,
+        dt_in_ms: u32,
+    ) {
+        if distance_in_mm == 0.0 {
+            // the car can't turn on the spot, thus everything the gyroscope measures now is drift.
+            return;
+        }
+
+        let steering_angle = (servo_angle_in_degrees * STEERING_ANGLE_PER_SERVO_ANGLE).to_radians();
+        let model_heading_change = distance_in_mm * libm::tanf(steering_angle) / WHEELBASE_IN_MM;
+        let heading_change = match yaw_rate_in_degrees_per_second {
+            Some(yaw_rate) => {
+                let gyro_heading_change = yaw_rate.to_radians() * dt_in_ms as f32 / 1_000.0;
+                GYRO_WEIGHT * gyro_heading_change + (1.0 - GYRO_WEIGHT) * model_heading_change
+            }
+            None => model_heading_change,
+        };
+
+        // move along the average heading during this update
+        let heading = self.pose.heading + heading_change / 2.0;
+        self.pose.x_in_mm += distance_in_mm * libm::cosf(heading);
+        self.pose.y_in_mm += distance_in_mm * libm::sinf(heading);
+        self.pose.heading = normalise_angle(self.pose.heading + heading_change);
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+}
+
+/// Wrap the angle (in radians) to `-PI..=PI`.
+fn normalise_angle(angle: f32) -> f32 {
+    let mut angle = angle;
+    while angle > PI {
+        angle -= 2.0 * PI;
+    }
+    while angle < -PI {
+        angle += 2.0 * PI;
+    }
+    angle
+}
@@ -1,40 +1,132 @@
 //! Contains the logic for the remote control. This deals with the events sent by the remote control
 //! app (e.g. on a smartphone) and triggers the corresponding actions on the robotcar.
 
+use crate::arbiter::{Command, CommandSource, Motion, MANUAL_COMMAND_TIMEOUT_IN_MS};
 use crate::bt_module::BluefruitLEUARTFriend;
+use crate::car::COLLISION_OVERRIDE_MAX_SPEED;
+use crate::config::{Config, ConfigStore};
+use crate::drive_profile::DriveProfile;
+use crate::error::{Error, TransportError};
+use crate::follow_me::TargetFollower;
+use crate::line_following::LineFollower;
+use crate::manoeuvre::ManoeuvrePlayer;
+use crate::steering::{CalibrationParameter, Direction};
+use crate::wall_following::WallFollower;
 use crate::CarT as Car;
 use adafruit_bluefruit_protocol::{
     self,
     button_event::{Button, ButtonEvent, ButtonState},
     ControllerEvent,
 };
-use core::cmp::{max, min, Ordering};
+use core::cmp::{max, min};
+use stm32f4xx_hal::dma::DMAError;
+
+/// The pulse width (in µs) by which the steering calibration is changed with every button press.
+const STEERING_CALIBRATION_STEP: i16 = 3;
+/// The percentage by which the expo of the steering curve is changed with every button press.
+const STEERING_EXPO_CALIBRATION_STEP: i16 = 5;
+/// The speed (in mm/s) by which the target speed is changed with every button press if the car has a wheel encoder.
+const TARGET_SPEED_STEP_IN_MM_PER_S: i16 = 250;
+/// The maximum target speed (in mm/s) in either direction.
+const MAX_TARGET_SPEED_IN_MM_PER_S: i16 = 1_000;
+
+/// The keys which have to be pressed in this order while holding the "2" key to switch the drive profile. This makes
+/// sure that the profile isn't switched by accident, e.g. by a child playing with the car in the kid mode.
+const DRIVE_PROFILE_SWITCH_SEQUENCE: [Button; 3] =
+    [Button::Button1, Button::Button3, Button::Button4];
+
+/// The state of the "2" key which is used to start the autonomous modes: on its own it starts the manoeuvre
+/// sequence (once released), in combination with another key it starts the corresponding autonomous mode.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum ModeKey {
+    Released,
+    Held,
+    /// The key has been used in a combination, releasing it doesn't start anything.
+    UsedInCombination,
+}
 
 /// The remote control which handles the events sent by an app.
 pub struct RemoteControl {
     bt_module: BluefruitLEUARTFriend,
+    config_store: ConfigStore,
+    config: Config,
+    /// The steering requested by the user, it is part of every manual command.
+    manual_steering: Direction,
+    mode_key: ModeKey,
+    /// How many keys of [`DRIVE_PROFILE_SWITCH_SEQUENCE`] have been pressed so far.
+    drive_profile_switch_progress: usize,
+    manoeuvre_player: ManoeuvrePlayer,
+    wall_follower: WallFollower,
+    target_follower: TargetFollower,
+    line_follower: LineFollower,
 }
 
 impl RemoteControl {
     /// Instantiate a new remote control to handle events.
-    pub fn new(bt_module: BluefruitLEUARTFriend) -> RemoteControl {
-        RemoteControl { bt_module }
+    /// `config` is the configuration currently in use, changes to it will be persisted in the `config_store`.
+    pub fn new(
+        bt_module: BluefruitLEUARTFriend,
+        config_store: ConfigStore,
+        config: Config,
+    ) -> RemoteControl {
+        RemoteControl {
+            bt_module,
+            config_store,
+            manual_steering: Direction::Centre,
+            mode_key: ModeKey::Released,
+            drive_profile_switch_progress: 0,
+            manoeuvre_player: ManoeuvrePlayer::new(config.manoeuvre_sequence),
+            wall_follower: WallFollower::default(),
+            target_follower: TargetFollower::default(),
+            line_follower: LineFollower::default(),
+            config,
+        }
     }
 
     /// This needs to be triggered every time a bluetooth message has been received, which is either
     /// the case if either a line idle interrupt or a DMA full interrupt occurs.
     ///
-    /// It handles the DMA buffer and acts on the message received.
-    pub fn handle_bluetooth_message(&mut self, car: &mut Car) {
-        let (filled_buffer, _) = self
+    /// It handles the DMA buffer and acts on the message received. Errors while acting on the message are
+    /// handled directly by the car, only errors while receiving the message are returned.
+    pub fn handle_bluetooth_message(
+        &mut self,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) -> Result<(), Error> {
+        let result = self.receive_bluetooth_message(car, now);
+        // the interrupt must be cleared in any case, otherwise it would trigger again immediately.
+        self.bt_module.rx_transfer.clear_idle_interrupt();
+        result
+    }
+
+    fn receive_bluetooth_message(
+        &mut self,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) -> Result<(), Error> {
+        let buffer = self
             .bt_module
-            .rx_transfer
-            .next_transfer(self.bt_module.rx_buffer.take().unwrap())
-            .unwrap();
+            .rx_buffer
+            .take()
+            .ok_or(Error::Transport(TransportError::Receive))?;
+        let filled_buffer = match self.bt_module.rx_transfer.next_transfer(buffer) {
+            Ok((filled_buffer, _)) => filled_buffer,
+            Err(
+                DMAError::NotReady(buffer)
+                | DMAError::SmallBuffer(buffer)
+                | DMAError::Overrun(buffer),
+            ) => {
+                // keep the buffer for the next transfer
+                self.bt_module.rx_buffer = Some(buffer);
+                return Err(Error::Transport(TransportError::Receive));
+            }
+        };
         defmt::trace!(
             "bluetooth: DMA transfer complete, received {:a}",
             filled_buffer
         );
+        // the app is still connected (even if the message doesn't change anything)
+        car.refresh_command(CommandSource::Manual, MANUAL_COMMAND_TIMEOUT_IN_MS, now);
 
         let events = adafruit_bluefruit_protocol::parse::<4>(filled_buffer);
         for event in events {
@@ -42,7 +134,7 @@ impl RemoteControl {
 
             match event {
                 Ok(event) => {
-                    self.handle_event(event, car);
+                    self.handle_event(event, car, now);
                 }
                 Err(err) => {
                     defmt::error!("error in event parsing: {}", err);
@@ -53,70 +145,414 @@ impl RemoteControl {
         // switch out the buffers
         filled_buffer.fill(0);
         self.bt_module.rx_buffer = Some(filled_buffer);
+        Ok(())
+    }
 
-        self.bt_module.rx_transfer.clear_idle_interrupt();
+    /// Whether an autonomous mode (manoeuvre sequence, wall following, follow me, line following) is currently active, see
+    /// [`Self::update_autonomous_mode`].
+    pub fn is_autonomous_mode_active(&self) -> bool {
+        self.manoeuvre_player.is_running()
+            || self.wall_follower.is_running()
+            || self.target_follower.is_running()
+            || self.line_follower.is_running()
+    }
+
+    /// Stop the autonomous mode which is currently active (if any) and stop the car.
+    pub fn stop_autonomous_mode(&mut self, car: &mut Car) {
+        self.manoeuvre_player.abort(car);
+        self.wall_follower.stop(car);
+        self.target_follower.stop(car);
+        self.line_follower.stop(car);
+        self.manual_steering = Direction::Centre;
+    }
+
+    /// Let the active autonomous mode control the car. Returns whether it is still active.
+    pub fn update_autonomous_mode(
+        &mut self,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) -> bool {
+        let manoeuvre_running = self.manoeuvre_player.update(car, now);
+        let wall_following = self.wall_follower.update(car, now);
+        let following_target = self.target_follower.update(car, now);
+        let following_line = self.line_follower.update(car, now);
+        manoeuvre_running || wall_following || following_target || following_line
     }
 
-    fn handle_event(&mut self, event: ControllerEvent, car: &mut Car) {
+    fn handle_event(
+        &mut self,
+        event: ControllerEvent,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
         match event {
             ControllerEvent::ButtonEvent(button_event) => {
-                self.handle_button_event(button_event, car)
+                self.handle_button_event(button_event, car, now)
             }
         }
     }
 
     /// Button events are used to remotely control the car (steering, speed change, etc.).
-    fn handle_button_event(&mut self, event: ButtonEvent, car: &mut Car) {
+    fn handle_button_event(
+        &mut self,
+        event: ButtonEvent,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
         defmt::debug!("handling {}", event);
+        if car.is_steering_calibration_active() {
+            self.handle_steering_calibration_button_event(event, car);
+            return;
+        }
+        if self.is_autonomous_mode_active() {
+            // any button takes back the control from the autonomous mode
+            if *event.state() == ButtonState::Pressed {
+                self.stop_autonomous_mode(car);
+            }
+            return;
+        }
+        if self.mode_key != ModeKey::Released {
+            self.handle_mode_key_combination(event, car, now);
+            return;
+        }
         match (event.button(), event.state()) {
             (Button::Left, ButtonState::Pressed) => {
-                car.steer_left();
+                self.handle_steering_change(car, Direction::Left(100), now);
             }
             (Button::Right, ButtonState::Pressed) => {
-                car.steer_right();
+                self.handle_steering_change(car, Direction::Right(100), now);
             }
             (Button::Left | Button::Right, ButtonState::Released) => {
-                car.steer_center();
+                self.handle_steering_change(car, Direction::Centre, now);
+            }
+            (Button::Up, ButtonState::Pressed) if car.has_speed_control() => {
+                let new_speed = min(
+                    car.target_speed_in_mm_per_s().unwrap_or(0) + TARGET_SPEED_STEP_IN_MM_PER_S,
+                    MAX_TARGET_SPEED_IN_MM_PER_S,
+                );
+                self.handle_target_speed_change(car, new_speed, now);
+            }
+            (Button::Down, ButtonState::Pressed) if car.has_speed_control() => {
+                let new_speed = max(
+                    car.target_speed_in_mm_per_s().unwrap_or(0) - TARGET_SPEED_STEP_IN_MM_PER_S,
+                    -MAX_TARGET_SPEED_IN_MM_PER_S,
+                );
+                self.handle_target_speed_change(car, new_speed, now);
             }
             (Button::Up, ButtonState::Pressed) => {
-                let new_speed = min(car.current_speed() + 25, 100);
-                self.handle_speed_change(car, new_speed);
+                let limits = car.drive_limits();
+                let new_step = min(
+                    limits.step_of_speed(car.current_speed()) + 1,
+                    limits.max_step(),
+                );
+                self.handle_speed_change(car, limits.speed_of_step(new_step), now);
             }
             (Button::Down, ButtonState::Pressed) => {
-                let new_speed = max(car.current_speed() - 25, -100);
-                self.handle_speed_change(car, new_speed);
+                let limits = car.drive_limits();
+                let new_step = max(
+                    limits.step_of_speed(car.current_speed()) - 1,
+                    -limits.max_step(),
+                );
+                self.handle_speed_change(car, limits.speed_of_step(new_step), now);
             }
             (Button::Button1, ButtonState::Pressed) => {
-                self.handle_speed_change(car, 0);
+                self.handle_speed_change(car, 0, now);
             }
-            (Button::Up | Button::Down | Button::Button1, ButtonState::Released) => {
-                defmt::trace!("button released which doesn't need any action");
+            (Button::Button2, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::Held;
+                self.drive_profile_switch_progress = 0;
             }
-            evt => {
-                defmt::warn!("unimplemented event {}", evt);
+            (Button::Button3 | Button::Button4, ButtonState::Pressed)
+                if car.drive_profile() != DriveProfile::Normal =>
+            {
+                // the configuration can only be changed after switching back to the normal profile (with "2" + "1",
+                // "3" & "4", see `DRIVE_PROFILE_SWITCH_SEQUENCE`)
+                defmt::warn!(
+                    "{} is locked in the drive profile {}",
+                    event.button(),
+                    car.drive_profile()
+                );
+            }
+            (Button::Button3, ButtonState::Pressed) => {
+                self.toggle_narrow_field_of_view(car);
+            }
+            (Button::Button4, ButtonState::Pressed) => {
+                car.start_steering_calibration();
+            }
+            (
+                Button::Up
+                | Button::Down
+                | Button::Button1
+                | Button::Button2
+                | Button::Button3
+                | Button::Button4,
+                ButtonState::Released,
+            ) => {
+                defmt::trace!("button released which doesn't need any action");
             }
         }
     }
 
-    fn handle_speed_change(&mut self, car: &mut Car, new_speed: i8) {
-        defmt::debug!("new speed set by remote: {}", new_speed);
-        // ignore failures as we can't report back to the actual remote control. the user will see
-        // whether his actions had an effect or not and can try again if he thinks that the action
-        // should work in a next step.
-        match new_speed.cmp(&0) {
-            Ordering::Greater => {
-                if let Err(err) = car.drive_forward(new_speed as u8) {
-                    defmt::error!("couldn't drive forward! {}", err);
+    /// While the "2" key is held the other keys start the autonomous modes: "up" starts the wall following, "down"
+    /// starts following the target in front, "right" starts following the line. "1", "3" & "4" in this order switch
+    /// the drive profile. As long as "left" is held as well the car creeps forward even if the obstacle in front is
+    /// too close (e.g. to dock against it). Releasing "2" without having pressed another key starts the manoeuvre
+    /// sequence.
+    fn handle_mode_key_combination(
+        &mut self,
+        event: ButtonEvent,
+        car: &mut Car,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        match (event.button(), event.state()) {
+            (Button::Button2, ButtonState::Released) => {
+                if self.mode_key == ModeKey::Held {
+                    self.manoeuvre_player.start();
                 }
+                self.mode_key = ModeKey::Released;
+                if car.is_collision_override_active() {
+                    self.handle_collision_override(car, false, now);
+                }
+            }
+            (Button::Left, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::UsedInCombination;
+                self.handle_collision_override(car, true, now);
+            }
+            (Button::Left, ButtonState::Released) => {
+                // only stop if creeping forward, otherwise this would take over from e.g. an autonomous mode
+                if car.is_collision_override_active() {
+                    self.handle_collision_override(car, false, now);
+                }
+            }
+            (Button::Up, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::UsedInCombination;
+                if !car.has_side_distance_sensor() {
+                    defmt::warn!("can't follow the wall without a side distance sensor");
+                    return;
+                }
+                self.wall_follower.start();
+            }
+            (Button::Down, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::UsedInCombination;
+                self.target_follower.start(car);
+            }
+            (Button::Right, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::UsedInCombination;
+                self.line_follower.start(car);
             }
-            Ordering::Less => {
-                if let Err(err) = car.drive_backwards((-new_speed) as u8) {
-                    defmt::error!("couldn't drive backwards! {}", err);
+            (Button::Button1 | Button::Button3 | Button::Button4, ButtonState::Pressed) => {
+                self.mode_key = ModeKey::UsedInCombination;
+                self.handle_drive_profile_switch_key(event.button(), car);
+            }
+            (_, ButtonState::Pressed) => {
+                defmt::debug!("no autonomous mode assigned to {}", event);
+                self.mode_key = ModeKey::UsedInCombination;
+                self.drive_profile_switch_progress = 0;
+            }
+            (_, ButtonState::Released) => {
+                defmt::trace!("button released which doesn't need any action");
+            }
+        }
+        if self.is_autonomous_mode_active() {
+            // the autonomous mode can only take over once the user has released the control (see `arbiter`).
+            car.set_collision_override(false);
+            car.release_command(CommandSource::Manual);
+            self.manual_steering = Direction::Centre;
+        }
+    }
+
+    /// While the steering is being calibrated the buttons are used to change the calibration:
+    /// left/right move the centre, "2" selects the next parameter, up/down change the selected
+    /// parameter, "1" persists the calibration (only in the normal drive profile) and "4" ends the calibration.
+    fn handle_steering_calibration_button_event(&mut self, event: ButtonEvent, car: &mut Car) {
+        let result = match (event.button(), event.state()) {
+            (Button::Left, ButtonState::Pressed) => car
+                .adjust_steering_calibration(
+                    CalibrationParameter::Centre,
+                    -STEERING_CALIBRATION_STEP,
+                )
+                .map(|_| ()),
+            (Button::Right, ButtonState::Pressed) => car
+                .adjust_steering_calibration(
+                    CalibrationParameter::Centre,
+                    STEERING_CALIBRATION_STEP,
+                )
+                .map(|_| ()),
+            (Button::Up | Button::Down, ButtonState::Pressed) => {
+                let parameter = car
+                    .selected_steering_calibration_parameter()
+                    .unwrap_or(CalibrationParameter::Centre);
+                let step = match parameter {
+                    CalibrationParameter::Expo => STEERING_EXPO_CALIBRATION_STEP,
+                    _ => STEERING_CALIBRATION_STEP,
+                };
+                let step = if *event.button() == Button::Up {
+                    step
+                } else {
+                    -step
+                };
+                car.adjust_steering_calibration(parameter, step).map(|_| ())
+            }
+            (Button::Button2, ButtonState::Pressed) => {
+                let next_parameter = match car.selected_steering_calibration_parameter() {
+                    Some(CalibrationParameter::MaxLeft) => CalibrationParameter::MaxRight,
+                    Some(CalibrationParameter::MaxRight) => CalibrationParameter::Trim,
+                    Some(CalibrationParameter::Trim) => CalibrationParameter::Expo,
+                    _ => CalibrationParameter::MaxLeft,
+                };
+                car.select_steering_calibration_parameter(next_parameter)
+            }
+            (Button::Button1, ButtonState::Pressed)
+                if car.drive_profile() != DriveProfile::Normal =>
+            {
+                defmt::warn!(
+                    "the steering calibration can't be stored in the drive profile {}",
+                    car.drive_profile()
+                );
+                return;
+            }
+            (Button::Button1, ButtonState::Pressed) => {
+                self.config.steering = car.steering_calibration();
+                if let Err(err) = self.config_store.store(self.config) {
+                    defmt::error!("couldn't store the steering calibration!");
+                    car.handle_error(Error::Storage(err));
                 }
+                return;
+            }
+            (Button::Button4, ButtonState::Pressed) => {
+                car.finish_steering_calibration();
+                return;
             }
-            Ordering::Equal => {
-                car.halt();
+            evt => {
+                defmt::trace!("event {} ignored during steering calibration", evt);
+                return;
             }
         };
+
+        if let Err(err) = result {
+            defmt::warn!("couldn't adjust the steering calibration!");
+            car.handle_error(err);
+        }
+    }
+
+    /// Advance the sequence to switch the drive profile (see [`DRIVE_PROFILE_SWITCH_SEQUENCE`]), a wrong key starts
+    /// it from the beginning. Once the sequence is complete the next profile is selected and persisted.
+    fn handle_drive_profile_switch_key(&mut self, button: &Button, car: &mut Car) {
+        if &DRIVE_PROFILE_SWITCH_SEQUENCE[self.drive_profile_switch_progress] != button {
+            defmt::debug!(
+                "{} is the wrong key to switch the drive profile, starting over",
+                button
+            );
+            self.drive_profile_switch_progress = 0;
+            return;
+        }
+        self.drive_profile_switch_progress += 1;
+        if self.drive_profile_switch_progress < DRIVE_PROFILE_SWITCH_SEQUENCE.len() {
+            return;
+        }
+
+        self.drive_profile_switch_progress = 0;
+        let drive_profile = car.drive_profile().next();
+        car.set_drive_profile(drive_profile);
+        self.config.drive_profile = drive_profile;
+        if let Err(err) = self.config_store.store(self.config) {
+            defmt::error!("couldn't store the drive profile!");
+            car.handle_error(Error::Storage(err));
+        }
+    }
+
+    /// Creep forward with the collision avoidance being overridden (see `Car::set_collision_override`) or stop again.
+    fn handle_collision_override(
+        &mut self,
+        car: &mut Car,
+        active: bool,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        car.set_collision_override(active);
+        if active && !car.is_collision_override_active() {
+            // not allowed in the current drive profile
+            return;
+        }
+        let speed = if active {
+            COLLISION_OVERRIDE_MAX_SPEED as i8
+        } else {
+            0
+        };
+        self.handle_speed_change(car, speed, now);
+    }
+
+    /// Switch the TOF sensor between the full and a narrow field of view and persist the change.
+    fn toggle_narrow_field_of_view(&mut self, car: &mut Car) {
+        let mut ranging_config = car.ranging_config();
+        ranging_config.narrow_field_of_view = !ranging_config.narrow_field_of_view;
+        car.set_ranging_config(ranging_config);
+        self.config.tof_ranging = ranging_config;
+        if let Err(err) = self.config_store.store(self.config) {
+            defmt::error!("couldn't store the TOF ranging config!");
+            car.handle_error(Error::Storage(err));
+        }
+    }
+
+    fn handle_steering_change(
+        &mut self,
+        car: &mut Car,
+        direction: Direction,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        self.manual_steering = direction;
+        // keep the speed the car is currently driving at, it might have been reduced by the car itself (e.g. due
+        // to a low battery or an obstacle in front).
+        let motion = match car.target_speed_in_mm_per_s() {
+            Some(speed_in_mm_per_s) => Motion::Speed(speed_in_mm_per_s),
+            None => Motion::Duty(car.current_speed()),
+        };
+        self.submit_manual_command(car, motion, now);
+    }
+
+    fn handle_speed_change(
+        &mut self,
+        car: &mut Car,
+        new_speed: i8,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        defmt::debug!("new speed set by remote: {}", new_speed);
+        self.submit_manual_command(car, Motion::Duty(new_speed), now);
+    }
+
+    fn handle_target_speed_change(
+        &mut self,
+        car: &mut Car,
+        new_speed_in_mm_per_s: i16,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        defmt::debug!(
+            "new target speed set by remote: {}mm/s",
+            new_speed_in_mm_per_s
+        );
+        self.submit_manual_command(car, Motion::Speed(new_speed_in_mm_per_s), now);
+    }
+
+    /// Let the car execute the motion with the steering requested by the user. The remote control only sends
+    /// changes, thus the command is refreshed by every message, see [`MANUAL_COMMAND_TIMEOUT_IN_MS`].
+    fn submit_manual_command(
+        &mut self,
+        car: &mut Car,
+        motion: Motion,
+        now: fugit::TimerInstantU32<1_000_000>,
+    ) {
+        let command = Command {
+            motion,
+            steering: self.manual_steering,
+        };
+        // we can't report failures back to the actual remote control. the user will see whether his
+        // actions had an effect or not and can try again if he thinks that the action should work in a next step.
+        if let Err(err) = car.submit_command(
+            CommandSource::Manual,
+            command,
+            Some(MANUAL_COMMAND_TIMEOUT_IN_MS),
+            now,
+        ) {
+            car.handle_error(err);
+        }
     }
 }
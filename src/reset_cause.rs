@@ -0,0 +1,150 @@
+//! Determines why the microcontroller has been reset and keeps track of the resets across reboots.
+//!
+//! The counters are kept in the backup registers of the RTC: they survive a reset of the microcontroller,
+//! but not a power cycle (on the Nucleo board VBAT is connected to VDD).
+
+use defmt::Format;
+use stm32f4xx_hal::pac::{PWR, RCC, RTC};
+
+/// Marks the backup registers as initialised by us (they're cleared on a power cycle).
+const BACKUP_REGISTER_MAGIC: u32 = 0x5253_5431; // "RST1"
+const MAGIC_REGISTER: usize = 0;
+const RESETS_REGISTER: usize = 1;
+const CONSECUTIVE_CRASHES_REGISTER: usize = 2;
+
+/// After this many crashes in a row (without the car running stable in between) the car only runs in a
+/// degraded mode, see [`ResetInfo::requires_degraded_mode`].
+const MAX_CONSECUTIVE_CRASHES: u32 = 3;
+
+/// Why the microcontroller has been reset.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum ResetCause {
+    /// The power has been switched on.
+    PowerOn,
+    /// The supply voltage dropped too low (e.g. due to empty batteries).
+    BrownOut,
+    /// The reset button has been pressed (or the debugger triggered a reset).
+    ResetPin,
+    /// The software requested a reset.
+    Software,
+    /// The software reset the microcontroller after a panic (see `panic_handler`).
+    Panic,
+    /// The independent watchdog wasn't fed in time.
+    Watchdog,
+    /// The window watchdog wasn't fed in time.
+    WindowWatchdog,
+    /// Entering a low-power mode was prohibited.
+    LowPower,
+    /// None of the reset flags was set.
+    Unknown,
+}
+
+impl ResetCause {
+    /// Read the cause of the last reset from the RCC and clear the flags (they'd accumulate over several resets otherwise).
+    pub fn read_and_clear(rcc: &RCC) -> ResetCause {
+        let csr = rcc.csr.read();
+        // several flags can be set at the same time (e.g. a power-on also triggers a brown-out and a pin reset),
+        // thus the order of the checks is important.
+        let cause = if csr.lpwrrstf().bit_is_set() {
+            ResetCause::LowPower
+        } else if csr.wwdgrstf().bit_is_set() {
+            ResetCause::WindowWatchdog
+        } else if csr.wdgrstf().bit_is_set() {
+            ResetCause::Watchdog
+        } else if csr.sftrstf().bit_is_set() {
+            ResetCause::Software
+        } else if csr.porrstf().bit_is_set() {
+            ResetCause::PowerOn
+        } else if csr.borrstf().bit_is_set() {
+            ResetCause::BrownOut
+        } else if csr.padrstf().bit_is_set() {
+            ResetCause::ResetPin
+        } else {
+            ResetCause::Unknown
+        };
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        cause
+    }
+
+    /// Whether the reset was caused by a crash of the firmware.
+    pub fn is_crash(self) -> bool {
+        matches!(
+            self,
+            ResetCause::Watchdog | ResetCause::WindowWatchdog | ResetCause::Panic
+        )
+    }
+}
+
+/// Information about the last reset and the resets before it.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct ResetInfo {
+    pub cause: ResetCause,
+    /// The number of resets since the last power cycle.
+    pub resets: u32,
+    /// The number of crashes (see [`ResetCause::is_crash`]) in a row, without the car having run stable in between.
+    pub consecutive_crashes: u32,
+}
+
+impl ResetInfo {
+    /// Whether the car keeps crashing and should thus only run in a (safer) degraded mode.
+    pub fn requires_degraded_mode(&self) -> bool {
+        self.consecutive_crashes >= MAX_CONSECUTIVE_CRASHES
+    }
+}
+
+/// Counts the resets in the backup registers.
+pub struct ResetCounter {
+    rtc: RTC,
+}
+
+impl ResetCounter {
+    /// Enable write access to the backup registers. Must be called before the RCC is consumed.
+    pub fn new(rtc: RTC, pwr: &PWR, rcc: &RCC) -> ResetCounter {
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        let mut reset_counter = ResetCounter { rtc };
+        if reset_counter.read(MAGIC_REGISTER) != BACKUP_REGISTER_MAGIC {
+            reset_counter.write(RESETS_REGISTER, 0);
+            reset_counter.write(CONSECUTIVE_CRASHES_REGISTER, 0);
+            reset_counter.write(MAGIC_REGISTER, BACKUP_REGISTER_MAGIC);
+        }
+        reset_counter
+    }
+
+    /// Count the reset which just happened.
+    pub fn record_reset(&mut self, cause: ResetCause) -> ResetInfo {
+        let resets = match cause {
+            // the registers have been cleared anyway
+            ResetCause::PowerOn => 0,
+            _ => self.read(RESETS_REGISTER).saturating_add(1),
+        };
+        let consecutive_crashes = if cause.is_crash() {
+            self.read(CONSECUTIVE_CRASHES_REGISTER).saturating_add(1)
+        } else {
+            0
+        };
+        self.write(RESETS_REGISTER, resets);
+        self.write(CONSECUTIVE_CRASHES_REGISTER, consecutive_crashes);
+
+        ResetInfo {
+            cause,
+            resets,
+            consecutive_crashes,
+        }
+    }
+
+    /// The car has been running stable for a while, thus previous crashes are no longer considered
+    /// to be consecutive.
+    pub fn mark_stable(&mut self) {
+        self.write(CONSECUTIVE_CRASHES_REGISTER, 0);
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        self.rtc.bkpr[register].read().bkp().bits()
+    }
+
+    fn write(&mut self, register: usize, value: u32) {
+        self.rtc.bkpr[register].write(|w| w.bkp().bits(value));
+    }
+}
@@ -0,0 +1,116 @@
+//! Supervises the critical tasks of the robotcar.
+//!
+//! Feeding the watchdog from a periodic task only detects a completely stuck system. If e.g. the TOF sensor hangs
+//! or the bluetooth module stops accepting data, the periodic task would still run and the watchdog would never
+//! trigger. Thus, the critical tasks regularly [`check_in`] and the watchdog is only fed as long as all registered
+//! tasks have checked in within their deadline, see [`all_tasks_alive`].
+
+use crate::event_log::{self, Event};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use defmt::Format;
+
+/// A task which is supervised.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum SupervisedTask {
+    /// Periodically validates the front distance (and re-initialises the devices on the I2C bus if needed).
+    ValidateDistance,
+    /// Periodically refreshes the display.
+    Display,
+    /// Periodically sends the telemetry over bluetooth. This only covers sending: the remote control (receiving)
+    /// is event-driven and thus can't check in regularly. Instead, its commands time out if nothing is received (see
+    /// [`crate::arbiter::MANUAL_COMMAND_TIMEOUT_IN_MS`]).
+    Telemetry,
+}
+
+impl SupervisedTask {
+    const ALL: [SupervisedTask; 3] = [
+        SupervisedTask::ValidateDistance,
+        SupervisedTask::Display,
+        SupervisedTask::Telemetry,
+    ];
+
+    /// The max. time between two check-ins of the task. This includes some margin as the tasks can be delayed by
+    /// higher priority tasks or by blocking operations (e.g. a reset of the TOF sensor).
+    pub const fn deadline_in_ms(self) -> u32 {
+        match self {
+            SupervisedTask::ValidateDistance => 1_000,
+            SupervisedTask::Display => 2_000,
+            SupervisedTask::Telemetry => 3_000,
+        }
+    }
+
+    fn state(self) -> &'static TaskState {
+        &TASKS[self as usize]
+    }
+}
+
+/// The supervision state of a single task.
+struct TaskState {
+    registered: AtomicBool,
+    last_check_in_in_ms: AtomicU32,
+    /// Whether the missed deadline has already been reported (to only report it once).
+    reported: AtomicBool,
+}
+
+impl TaskState {
+    const fn new() -> TaskState {
+        TaskState {
+            registered: AtomicBool::new(false),
+            last_check_in_in_ms: AtomicU32::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)] // only used to initialise `TASKS`
+const UNREGISTERED_TASK: TaskState = TaskState::new();
+static TASKS: [TaskState; SupervisedTask::ALL.len()] =
+    [UNREGISTERED_TASK; SupervisedTask::ALL.len()];
+
+fn now_in_ms() -> u32 {
+    crate::app::monotonics::now()
+        .duration_since_epoch()
+        .to_millis()
+}
+
+/// Start supervising the task. Tasks which aren't used (e.g. because the peripheral isn't enabled) must not be
+/// registered, otherwise the watchdog would never be fed.
+pub fn register(task: SupervisedTask) {
+    check_in(task);
+    task.state().registered.store(true, Ordering::Relaxed);
+}
+
+/// Report that the task is alive.
+pub fn check_in(task: SupervisedTask) {
+    let state = task.state();
+    state
+        .last_check_in_in_ms
+        .store(now_in_ms(), Ordering::Relaxed);
+    state.reported.store(false, Ordering::Relaxed);
+}
+
+/// Whether all registered tasks have checked in within their deadline. Tasks which missed their deadline are
+/// logged (once per missed deadline).
+pub fn all_tasks_alive() -> bool {
+    let now = now_in_ms();
+    let mut all_tasks_alive = true;
+    for task in SupervisedTask::ALL {
+        let state = task.state();
+        if !state.registered.load(Ordering::Relaxed) {
+            continue;
+        }
+        let elapsed_in_ms = now.wrapping_sub(state.last_check_in_in_ms.load(Ordering::Relaxed));
+        if elapsed_in_ms > task.deadline_in_ms() {
+            all_tasks_alive = false;
+            if !state.reported.swap(true, Ordering::Relaxed) {
+                defmt::error!(
+                    "task {} didn't check in for {}ms, the watchdog will reset the car!",
+                    task,
+                    elapsed_in_ms
+                );
+                event_log::record(Event::TaskMissedDeadline(task));
+            }
+        }
+    }
+    all_tasks_alive
+}
@@ -0,0 +1,121 @@
+//! Sends the status of the car to the remote control app (e.g. to be shown in the UART view of the app).
+//!
+//! The telemetry is sent as human-readable lines of `key=value` pairs separated by `;` which can
+//! also easily be parsed by a program (e.g. for plotting), e.g.:
+//! `S;state=Normal;speed=50;ctrl=Manual;profile=Normal;dist=1234;closing=-20;ttc=-;tof_err=0;tof_rst=0;bat=11800;i2c_rec=0;reset=PowerOn;resets=0;degraded=0;odo=5120;v=480;v_target=500;x=4870;y=-312;heading=-12`.
+//! Values which are not available are sent as `-`.
+//!
+//! Additionally, the entries of the event log can be sent, one per line, e.g.: `E;t=1234;StateChanged(Normal)`
+//! (`C` instead of `E` for the entries of the persisted crash record). If the crash was caused by a panic, its
+//! location is sent before the entries of the crash record, e.g.: `C;panic=src/car.rs:123`.
+
+use crate::car::CarStatus;
+use crate::event_log::{Entry, PanicLocation};
+use core::fmt::{self, Write};
+
+/// The max. length (in characters) of a status line, i.e. with all values at their longest (e.g. `i32::MIN`).
+pub const MAX_STATUS_LENGTH: u32 = 340;
+
+/// Sends the telemetry over the given writer (e.g. the UART connected to the bluetooth module).
+pub struct Telemetry<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Telemetry<W> {
+    pub fn new(writer: W) -> Telemetry<W> {
+        Telemetry { writer }
+    }
+
+    /// Send the current status of the car.
+    pub fn send_status(&mut self, status: &CarStatus) -> fmt::Result {
+        write!(
+            self.writer,
+            "S;state={:?};speed={}",
+            status.state, status.speed
+        )?;
+        match status.control {
+            Some(source) => write!(self.writer, ";ctrl={:?}", source)?,
+            None => self.writer.write_str(";ctrl=-")?,
+        }
+        write!(self.writer, ";profile={:?}", status.drive_profile)?;
+        write_optional(&mut self.writer, "dist", status.front_distance_in_mm)?;
+        write_optional(
+            &mut self.writer,
+            "closing",
+            status.closing_speed_in_mm_per_s,
+        )?;
+        write_optional(&mut self.writer, "ttc", status.time_to_collision_in_ms)?;
+        write_optional(
+            &mut self.writer,
+            "tof_err",
+            status
+                .front_distance_sensor
+                .map(|sensor| sensor.consecutive_errors),
+        )?;
+        write_optional(
+            &mut self.writer,
+            "tof_rst",
+            status.front_distance_sensor.map(|sensor| sensor.resets),
+        )?;
+        write_optional(
+            &mut self.writer,
+            "bat",
+            status.battery.map(|battery| battery.voltage_in_mv),
+        )?;
+        if let Some(battery) = status.battery {
+            write!(self.writer, ";bat_state={:?}", battery.state)?;
+        }
+        write!(self.writer, ";i2c_rec={}", status.i2c_bus_recoveries)?;
+        write!(
+            self.writer,
+            ";reset={:?};resets={};degraded={}",
+            status.reset.cause, status.reset.resets, status.degraded_mode as u8
+        )?;
+        write_optional(&mut self.writer, "odo", status.distance_travelled_in_mm)?;
+        write_optional(&mut self.writer, "v", status.measured_speed_in_mm_per_s)?;
+        write_optional(
+            &mut self.writer,
+            "v_target",
+            status.target_speed_in_mm_per_s,
+        )?;
+        write!(
+            self.writer,
+            ";x={};y={};heading={}",
+            status.pose.x_in_mm as i32,
+            status.pose.y_in_mm as i32,
+            status.pose.heading_in_degrees() as i32
+        )?;
+        self.writer.write_str("\n")
+    }
+
+    /// Send an entry of the event log. `prefix` identifies where the entry comes from.
+    pub fn send_event(&mut self, prefix: &str, entry: &Entry) -> fmt::Result {
+        writeln!(
+            self.writer,
+            "{};t={};{:?}",
+            prefix, entry.timestamp_in_ms, entry.event
+        )
+    }
+
+    /// Send the location of a panic. `prefix` identifies where the location comes from.
+    pub fn send_panic_location(&mut self, prefix: &str, location: &PanicLocation) -> fmt::Result {
+        writeln!(
+            self.writer,
+            "{};panic={}:{}",
+            prefix,
+            location.file(),
+            location.line
+        )
+    }
+}
+
+fn write_optional<W: Write>(
+    writer: &mut W,
+    key: &str,
+    value: Option<impl fmt::Display>,
+) -> fmt::Result {
+    match value {
+        Some(value) => write!(writer, ";{}={}", key, value),
+        None => write!(writer, ";{}=-", key),
+    }
+}
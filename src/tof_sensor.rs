@@ -6,15 +6,91 @@
 /// by the different drivers, so that consumers can directly interact with these traits.
 /// It is however unclear how much benefit this would bring (not investigated so far).
 use core::fmt::Debug;
+use defmt::Format;
 use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
-use vl53l1x_uld::{Error, VL53L1X};
+use vl53l1x_uld::{roi::ROI, DistanceMode, Error, IOVoltage, Polarity, Register, VL53L1X};
+
+/// Time to wait between entering & leaving the reset (min. 100us according to the ULD) and afterwards for the
+/// sensor to boot (max. 1.2ms according to the datasheet), in CPU cycles. Ca. 2.4ms at 84MHz.
+const VL53L1X_SOFT_RESET_DELAY_IN_CYCLES: u32 = 200_000;
+
+/// Width & height (in SPADs) of the narrow field of view of the VL53L1X. The full field of view is 16x16 SPADs (ca. 27°),
+/// this reduces it to ca. 13°, which avoids measuring the floor in front of the car.
+const VL53L1X_NARROW_ROI_SIZE: u16 = 8;
+const VL53L1X_FULL_ROI_SIZE: u16 = 16;
+
+/// Predefined combinations of the ranging settings of the distance sensor.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub enum RangingPreset {
+    /// Short maximum distance (ca. 1.3m) but fast measurements (every 20ms). Suited for high speeds.
+    ShortFast,
+    /// Long maximum distance (up to 4m) with precise but slow measurements (every 100ms).
+    LongPrecise,
+}
+
+/// The settings used by the distance sensor for its measurements.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct RangingProfile {
+    pub preset: RangingPreset,
+    /// Only measure in a narrow cone in front of the sensor, e.g. to avoid reflections from the floor.
+    pub narrow_field_of_view: bool,
+}
+
+/// Which ranging profile to use depending on the speed of the car.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Format)]
+pub struct RangingConfig {
+    pub low_speed_preset: RangingPreset,
+    /// At high speeds the distance needs to be measured more often to still be able to brake in time.
+    pub high_speed_preset: RangingPreset,
+    pub narrow_field_of_view: bool,
+}
+
+impl Default for RangingConfig {
+    fn default() -> Self {
+        RangingConfig {
+            low_speed_preset: RangingPreset::LongPrecise,
+            high_speed_preset: RangingPreset::ShortFast,
+            narrow_field_of_view: false,
+        }
+    }
+}
+
+impl RangingConfig {
+    /// The profile to be used at the given speed.
+    pub fn profile(&self, high_speed: bool) -> RangingProfile {
+        RangingProfile {
+            preset: if high_speed {
+                self.high_speed_preset
+            } else {
+                self.low_speed_preset
+            },
+            narrow_field_of_view: self.narrow_field_of_view,
+        }
+    }
+}
 
 /// Represents a simple distance sensor.
 ///
 /// For simplicity the error is currently not modelled here but instead the error of the actual
 /// implementation will be used (it's not referenced directly anywhere in `Car`, so this is fine).
 pub trait DistanceSensor<E> {
-    /// Get the distance measured in millimeters (if available).
+    /// (Re-)initialise the sensor and start the measurements. This is also used to bring the sensor
+    /// back into a known state after it lost its state (e.g. after a recovery of the I2C bus).
+    fn initialise(&mut self, profile: RangingProfile) -> Result<(), E>;
+
+    /// Reset the sensor (e.g. because it doesn't deliver correct data anymore) and re-initialise it,
+    /// see [`DistanceSensor::initialise`].
+    fn reset(&mut self, profile: RangingProfile) -> Result<(), E>;
+
+    /// Change the settings used for the measurements. The measurements continue with the new settings.
+    fn set_ranging_profile(&mut self, profile: RangingProfile) -> Result<(), E>;
+
+    /// Whether a new measurement is available since the last call of [`DistanceSensor::get_distance_in_mm`].
+    /// Only needed if the interrupt of the sensor isn't used.
+    fn is_data_ready(&mut self) -> Result<bool, E>;
+
+    /// Get the distance measured in millimeters (if available) and clear the interrupt, i.e. mark the measurement
+    /// as read.
     fn get_distance_in_mm(&mut self) -> Result<u16, E>;
 }
 
@@ -23,6 +99,56 @@ where
     E: Debug,
     I2C: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
 {
+    fn initialise(&mut self, profile: RangingProfile) -> Result<(), Error<E>> {
+        self.init(IOVoltage::Volt2_8)?;
+        self.set_interrupt_polarity(Polarity::ActiveHigh)?;
+        self.set_ranging_profile(profile)
+    }
+
+    fn reset(&mut self, profile: RangingProfile) -> Result<(), Error<E>> {
+        // the sensor might not react to this anymore, but the reset is done in any case.
+        self.stop_ranging().ok();
+
+        // note: the XSHUT pin of the sensor isn't wired on the PCB, thus only a soft reset is possible.
+        self.write_bytes(Register::SOFT_RESET, &[0x00])?;
+        cortex_m::asm::delay(VL53L1X_SOFT_RESET_DELAY_IN_CYCLES);
+        self.write_bytes(Register::SOFT_RESET, &[0x01])?;
+        cortex_m::asm::delay(VL53L1X_SOFT_RESET_DELAY_IN_CYCLES);
+        if !self.is_booted()? {
+            defmt::warn!("TOF hasn't booted yet after the reset, trying to initialise it anyway");
+        }
+
+        self.initialise(profile)
+    }
+
+    fn set_ranging_profile(&mut self, profile: RangingProfile) -> Result<(), Error<E>> {
+        let (distance_mode, timing_budget_in_ms) = match profile.preset {
+            RangingPreset::ShortFast => (DistanceMode::Short, 20),
+            RangingPreset::LongPrecise => (DistanceMode::Long, 100),
+        };
+        let roi_size = if profile.narrow_field_of_view {
+            VL53L1X_NARROW_ROI_SIZE
+        } else {
+            VL53L1X_FULL_ROI_SIZE
+        };
+
+        self.stop_ranging()?;
+        self.set_distance_mode(distance_mode)?;
+        self.set_timing_budget_ms(timing_budget_in_ms)?;
+        // measure continuously: the next measurement starts as soon as the previous one is done.
+        self.set_inter_measurement_period_ms(timing_budget_in_ms)?;
+        self.set_roi(ROI::new(roi_size, roi_size))?;
+        self.clear_interrupt()?;
+        self.start_ranging()?;
+
+        defmt::info!("TOF ranging profile set to {}", profile);
+        Ok(())
+    }
+
+    fn is_data_ready(&mut self) -> Result<bool, Error<E>> {
+        VL53L1X::is_data_ready(self)
+    }
+
     fn get_distance_in_mm(&mut self) -> Result<u16, Error<E>> {
         self.clear_interrupt()?;
         self.get_distance()
@@ -0,0 +1,158 @@
+//! Follows a wall (e.g. along a corridor) on the right side of the car at a fixed distance.
+//!
+//! The lateral distance to the wall is measured by the side distance sensor (see `Car::measure_side_distance`),
+//! a PD controller steers the car to keep the distance while it drives at a fixed speed. The collision avoidance
+//! still applies: if the car isn't allowed to drive forward anymore the wall following is stopped.
+
+use crate::arbiter::{Command, CommandSource, Motion, AUTONOMOUS_COMMAND_TIMEOUT_IN_MS};
+use crate::car::CarState;
+use crate::event_log::{self, Event};
+use crate::pid::{PidController, PidGains};
+use crate::steering::Direction;
+use crate::CarT as Car;
+use core::cmp::Ordering;
+
+/// The distance to the wall which is kept.
+const WALL_DISTANCE_IN_MM: u16 = 300;
+/// If the wall is further away than this it is considered to be lost (e.g. at the end of the corridor).
+const MAX_WALL_DISTANCE_IN_MM: u16 = 1_000;
+/// The speed (in percentage) at which the car drives while following the wall (ca. 200mm/s).
+const WALL_FOLLOWING_SPEED: i8 = 35;
+
+/// If the side distance sensor doesn't deliver a new measurement for this long the wall following is stopped
+/// (it measures every 20ms). The command based on the last measurement has timed out by then anyway.
+const MAX_SIDE_DISTANCE_SENSOR_LAG_IN_MS: u32 = AUTONOMOUS_COMMAND_TIMEOUT_IN_MS;
+
+/// The gains of the controller (output: steering in percentage, positive to the left; input: distance in mm).
+/// The D-term damps the turn towards the wall, the I-term isn't needed as there is no steady-state error.
+const WALL_FOLLOWING_GAINS: PidGains = PidGains {
+    kp: 300,
+    ki: 0,
+    kd: 800,
+};
+/// The D-term amplifies the noise of the measurement (a jitter of 5mm between two measurements 20ms apart would
+/// alone steer by 200%), thus it is smoothed over ca. 8 measurements. This is still much faster than the car
+/// turns.
+const WALL_FOLLOWING_DERIVATIVE_FILTER_SHIFT: u32 = 3;
+
+/// Follows the wall on the right side of the car. [`WallFollower::update`] needs to be called periodically while
+/// it is running.
+pub struct WallFollower {
+    controller: PidController,
+    running: bool,
+    last_update: Option<fugit::TimerInstantU32<1_000_000>>,
+}
+
+impl Default for WallFollower {
+    fn default() -> Self {
+        WallFollower {
+            controller: PidController::new(WALL_FOLLOWING_GAINS, 100)
+                .with_derivative_filter(WALL_FOLLOWING_DERIVATIVE_FILTER_SHIFT),
+            running: false,
+            last_update: None,
+        }
+    }
+}
+
+impl WallFollower {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Start following the wall. The car starts driving with the next update.
+    pub fn start(&mut self) {
+        defmt::info!("starting to follow the wall");
+        event_log::record(Event::WallFollowingStarted);
+        self.controller.reset();
+        self.last_update = None;
+        self.running = true;
+    }
+
+    /// Stop following the wall and stop the car.
+    pub fn stop(&mut self, car: &mut Car) {
+        if self.running {
+            defmt::info!("stopped following the wall");
+            event_log::record(Event::WallFollowingStopped);
+            self.running = false;
+            car.release_command(CommandSource::Autonomous);
+        }
+    }
+
+    /// Steer based on the current distance to the wall. Returns whether the car is still following the wall.
+    pub fn update(&mut self, car: &mut Car, now: fugit::TimerInstantU32<1_000_000>) -> bool {
+        if !self.running {
+            return false;
+        }
+        if car.current_state() != CarState::Normal {
+            defmt::warn!("obstacle in front, stopping to follow the wall");
+            self.stop(car);
+            return false;
+        }
+
+        let distance_in_mm = match car.measure_side_distance() {
+            Ok(Some(distance_in_mm)) if distance_in_mm <= MAX_WALL_DISTANCE_IN_MM => distance_in_mm,
+            Ok(Some(_)) => {
+                defmt::warn!("lost the wall");
+                self.stop(car);
+                return false;
+            }
+            Ok(None) if !car.has_side_distance_sensor() => {
+                defmt::warn!("there is no side distance sensor");
+                self.stop(car);
+                return false;
+            }
+            Ok(None) => {
+                // no new measurement yet: keep the current command (it's refreshed with the next measurement)
+                let lagging = self.last_update.is_some_and(|last_update| {
+                    now.checked_duration_since(last_update)
+                        .is_some_and(|lag| lag.to_millis() > MAX_SIDE_DISTANCE_SENSOR_LAG_IN_MS)
+                });
+                if lagging {
+                    defmt::warn!("no new side distance for too long");
+                    self.stop(car);
+                    return false;
+                }
+                return true;
+            }
+            Err(e) => {
+                car.handle_error(e);
+                self.stop(car);
+                return false;
+            }
+        };
+        let dt_in_ms = self
+            .last_update
+            .replace(now)
+            .and_then(|last_update| now.checked_duration_since(last_update))
+            .map_or(0, |dt| dt.to_millis());
+        let steering =
+            self.controller
+                .update(WALL_DISTANCE_IN_MM as i32, distance_in_mm as i32, dt_in_ms);
+        defmt::trace!(
+            "wall distance: {}mm => steering {}%",
+            distance_in_mm,
+            steering
+        );
+
+        let direction = match steering.cmp(&0) {
+            Ordering::Greater => Direction::Left(steering as u8),
+            Ordering::Less => Direction::Right(steering.unsigned_abs() as u8),
+            Ordering::Equal => Direction::Centre,
+        };
+        let command = Command {
+            motion: Motion::Duty(WALL_FOLLOWING_SPEED),
+            steering: direction,
+        };
+        if let Err(e) = car.submit_command(
+            CommandSource::Autonomous,
+            command,
+            Some(AUTONOMOUS_COMMAND_TIMEOUT_IN_MS),
+            now,
+        ) {
+            car.handle_error(e);
+            self.stop(car);
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,51 @@
+//! Counts the pulses of the wheel encoder (mounted on the shaft of the motor) using a hardware timer.
+//!
+//! The PCB doesn't have a free pair of pins which can be used for the encoder mode of a timer (the candidates are
+//! in use for the bluetooth module, the LED & the user button or the PWM of the motor & servo), thus a single-channel
+//! encoder is used: it is connected to the external trigger input of TIM1 (PA12 on the morpho header) which clocks
+//! the counter, i.e. the counter counts the rising edges of the encoder signal.
+
+use stm32f4xx_hal::{
+    gpio::{Alternate, PA12},
+    pac::TIM1,
+    rcc::Clocks,
+    timer::Timer,
+};
+
+/// The wheel encoder connected to PA12.
+pub struct WheelEncoder {
+    tim: TIM1,
+    _pin: PA12<Alternate<1>>,
+}
+
+impl WheelEncoder {
+    /// Set up TIM1 to count the rising edges on its external trigger input (PA12).
+    #[cfg_attr(not(feature = "use-encoder"), allow(dead_code))]
+    pub fn new(tim: TIM1, pin: PA12, clocks: &Clocks) -> WheelEncoder {
+        // enables & resets the timer
+        let tim = Timer::new(tim, clocks).release();
+        let pin = pin.into_alternate().internal_pull_up(true); // the encoder has an open-collector output
+
+        tim.arr.write(|w| w.arr().bits(u16::MAX));
+        // external clock mode 2: count the rising edges on ETR. the filter (8 samples at 84MHz / 32) suppresses
+        // glitches shorter than ca. 3µs, which is still way shorter than the pulses at full speed.
+        tim.smcr.write(|w| {
+            w.ece()
+                .enabled()
+                .etp()
+                .not_inverted()
+                .etps()
+                .div1()
+                .etf()
+                .fdts_div32_n8()
+        });
+        tim.cr1.write(|w| w.cen().enabled());
+
+        WheelEncoder { tim, _pin: pin }
+    }
+
+    /// The current value of the pulse counter. It overflows after `u16::MAX` pulses.
+    pub fn count(&self) -> u16 {
+        self.tim.cnt.read().cnt().bits()
+    }
+}